@@ -1,11 +1,17 @@
+use crate::object::{InlineCache, Obj};
 use crate::value::Value;
+use std::cell::RefCell;
 
-#[derive(Debug, Clone, PartialEq)]
-#[repr(u8)]
+/// A decoded instruction. `Chunk::code` stores each one as an opcode byte followed by
+/// its operands: constant indexes take one byte, or three in the `_LONG` form once
+/// they pass 255; slots and argument counts take one byte; jumps take two.
+/// `GetProperty`, `SetProperty` and `Invoke` end with two more bytes that number
+/// their inline cache. The superinstructions at the end are only made by the
+/// optimizer.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     Constant(isize),
     DefineGlobal(isize),
-    DefineLocal(isize),
     SetGlobal(isize),
     SetLocal(isize),
     GetGlobal(isize),
@@ -17,9 +23,9 @@ pub enum OpCode {
     Call(isize),
     Invoke(isize, isize),
     SuperInvoke(isize, isize),
-    Jump(i16),
-    JumpIfFalse(i16),
-    Loop(i16),
+    Jump(u16),
+    JumpIfFalse(u16),
+    Loop(u16),
     Closure(isize),
     Method(isize),
     Nil,
@@ -41,15 +47,126 @@ pub enum OpCode {
     Inherit,
     GetSuper(isize),
     CloseUpvalue,
+    /// A raw operand byte, such as the upvalue pairs that follow a `Closure`.
     Data(u8),
     Nop,
+    /// `GetLocal`, `GetLocal`, `Add`.
+    AddLocals(isize, isize),
+    /// `Constant`, `Add`, for a constant index that fits in a byte.
+    AddConstant(isize),
+    /// `Equal`, `Not`.
+    NotEqual,
+    /// `SetLocal`, `Pop`.
+    SetLocalPop(isize),
+    /// Replaces the given number of values on the stack with a list of them.
+    BuildList(isize),
+    GetIndex,
+    SetIndex,
+    /// Pushes copies of the top two values, for compound assignment to an index.
+    Dup2,
+    /// Replaces the top value with its printed form, for string interpolation.
+    Stringify,
+    Modulo,
+    Power,
+    /// The bitwise operators work on numbers that hold whole values.
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
 }
+
+/// The largest constant index or global slot a `_LONG` instruction can address.
+pub const MAX_LONG_OPERAND: usize = (1 << 24) - 1;
+/// The most inline caches one chunk can number.
+pub const MAX_INLINE_CACHES: usize = 1 << 16;
+
+/// Opcode bytes. Every instruction with a constant index has a narrow form with a
+/// one-byte operand and a `_LONG` form with a three-byte one.
+mod op {
+    pub const CONSTANT: u8 = 0;
+    pub const CONSTANT_LONG: u8 = 1;
+    pub const DEFINE_GLOBAL: u8 = 2;
+    pub const DEFINE_GLOBAL_LONG: u8 = 3;
+    pub const GET_GLOBAL: u8 = 4;
+    pub const GET_GLOBAL_LONG: u8 = 5;
+    pub const SET_GLOBAL: u8 = 6;
+    pub const SET_GLOBAL_LONG: u8 = 7;
+    pub const GET_PROPERTY: u8 = 8;
+    pub const GET_PROPERTY_LONG: u8 = 9;
+    pub const SET_PROPERTY: u8 = 10;
+    pub const SET_PROPERTY_LONG: u8 = 11;
+    pub const GET_SUPER: u8 = 12;
+    pub const GET_SUPER_LONG: u8 = 13;
+    pub const CLASS: u8 = 14;
+    pub const CLASS_LONG: u8 = 15;
+    pub const METHOD: u8 = 16;
+    pub const METHOD_LONG: u8 = 17;
+    pub const CLOSURE: u8 = 18;
+    pub const CLOSURE_LONG: u8 = 19;
+    pub const INVOKE: u8 = 20;
+    pub const INVOKE_LONG: u8 = 21;
+    pub const SUPER_INVOKE: u8 = 22;
+    pub const SUPER_INVOKE_LONG: u8 = 23;
+    pub const GET_LOCAL: u8 = 24;
+    pub const SET_LOCAL: u8 = 25;
+    pub const GET_UPVALUE: u8 = 26;
+    pub const SET_UPVALUE: u8 = 27;
+    pub const CALL: u8 = 28;
+    pub const JUMP: u8 = 29;
+    pub const JUMP_IF_FALSE: u8 = 30;
+    pub const LOOP: u8 = 31;
+    pub const NIL: u8 = 32;
+    pub const TRUE: u8 = 33;
+    pub const FALSE: u8 = 34;
+    pub const ADD: u8 = 35;
+    pub const SUBTRACT: u8 = 36;
+    pub const MULTIPLY: u8 = 37;
+    pub const DIVIDE: u8 = 38;
+    pub const NOT: u8 = 39;
+    pub const EQUAL: u8 = 40;
+    pub const GREATER: u8 = 41;
+    pub const LESS: u8 = 42;
+    pub const NEGATE: u8 = 43;
+    pub const RETURN: u8 = 44;
+    pub const PRINT: u8 = 45;
+    pub const POP: u8 = 46;
+    pub const INHERIT: u8 = 47;
+    pub const CLOSE_UPVALUE: u8 = 48;
+    pub const NOP: u8 = 49;
+    pub const ADD_LOCALS: u8 = 50;
+    pub const ADD_CONSTANT: u8 = 51;
+    pub const NOT_EQUAL: u8 = 52;
+    pub const SET_LOCAL_POP: u8 = 53;
+    pub const BUILD_LIST: u8 = 54;
+    pub const GET_INDEX: u8 = 55;
+    pub const SET_INDEX: u8 = 56;
+    pub const DUP2: u8 = 57;
+    pub const STRINGIFY: u8 = 58;
+    pub const MODULO: u8 = 59;
+    pub const POWER: u8 = 60;
+    pub const BIT_AND: u8 = 61;
+    pub const BIT_OR: u8 = 62;
+    pub const BIT_XOR: u8 = 63;
+    pub const BIT_NOT: u8 = 64;
+    pub const SHIFT_LEFT: u8 = 65;
+    pub const SHIFT_RIGHT: u8 = 66;
+
+    /// Opcodes up to this one carry a constant index: narrow on even bytes, long on odd.
+    pub const LAST_INDEXED: u8 = SUPER_INVOKE_LONG;
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Chunk {
+    /// Number of bytes in `code`.
     pub count: isize,
-    pub code: Vec<OpCode>,
+    pub code: Vec<u8>,
+    /// The source line of each byte in `code`.
     pub lines: Vec<usize>,
     pub constants: Vec<Value>,
+    /// One inline cache per `GetProperty`, `SetProperty` and `Invoke`, in code order.
+    pub caches: Vec<RefCell<InlineCache>>,
 }
 
 impl Chunk {
@@ -59,19 +176,283 @@ impl Chunk {
             code: Vec::new(),
             lines: Vec::new(),
             constants: Vec::new(),
+            caches: Vec::new(),
+        }
+    }
+    /// Encodes `instruction`. Slot and count operands are truncated to a byte; the
+    /// compiler reports a limit error before that can happen.
+    pub fn write_chunk(&mut self, instruction: OpCode, line: usize) {
+        match instruction {
+            OpCode::Constant(index) => self.write_indexed(op::CONSTANT, index, line),
+            OpCode::DefineGlobal(index) => self.write_indexed(op::DEFINE_GLOBAL, index, line),
+            OpCode::GetGlobal(index) => self.write_indexed(op::GET_GLOBAL, index, line),
+            OpCode::SetGlobal(index) => self.write_indexed(op::SET_GLOBAL, index, line),
+            OpCode::GetProperty(index) => {
+                self.write_indexed(op::GET_PROPERTY, index, line);
+                self.write_cache(line);
+            }
+            OpCode::SetProperty(index) => {
+                self.write_indexed(op::SET_PROPERTY, index, line);
+                self.write_cache(line);
+            }
+            OpCode::GetSuper(index) => self.write_indexed(op::GET_SUPER, index, line),
+            OpCode::Class(index) => self.write_indexed(op::CLASS, index, line),
+            OpCode::Method(index) => self.write_indexed(op::METHOD, index, line),
+            OpCode::Closure(index) => self.write_indexed(op::CLOSURE, index, line),
+            OpCode::Invoke(index, arg_count) => {
+                self.write_indexed(op::INVOKE, index, line);
+                self.write_byte(arg_count as u8, line);
+                self.write_cache(line);
+            }
+            OpCode::SuperInvoke(index, arg_count) => {
+                self.write_indexed(op::SUPER_INVOKE, index, line);
+                self.write_byte(arg_count as u8, line);
+            }
+            OpCode::GetLocal(slot) => self.write_bytes(op::GET_LOCAL, slot as u8, line),
+            OpCode::SetLocal(slot) => self.write_bytes(op::SET_LOCAL, slot as u8, line),
+            OpCode::GetUpvalue(slot) => self.write_bytes(op::GET_UPVALUE, slot as u8, line),
+            OpCode::SetUpvalue(slot) => self.write_bytes(op::SET_UPVALUE, slot as u8, line),
+            OpCode::Call(arg_count) => self.write_bytes(op::CALL, arg_count as u8, line),
+            OpCode::Jump(offset) => self.write_short(op::JUMP, offset, line),
+            OpCode::JumpIfFalse(offset) => self.write_short(op::JUMP_IF_FALSE, offset, line),
+            OpCode::Loop(offset) => self.write_short(op::LOOP, offset, line),
+            OpCode::Nil => self.write_byte(op::NIL, line),
+            OpCode::True => self.write_byte(op::TRUE, line),
+            OpCode::False => self.write_byte(op::FALSE, line),
+            OpCode::Add => self.write_byte(op::ADD, line),
+            OpCode::Subtract => self.write_byte(op::SUBTRACT, line),
+            OpCode::Multiply => self.write_byte(op::MULTIPLY, line),
+            OpCode::Divide => self.write_byte(op::DIVIDE, line),
+            OpCode::Not => self.write_byte(op::NOT, line),
+            OpCode::Equal => self.write_byte(op::EQUAL, line),
+            OpCode::Greater => self.write_byte(op::GREATER, line),
+            OpCode::Less => self.write_byte(op::LESS, line),
+            OpCode::Negate => self.write_byte(op::NEGATE, line),
+            OpCode::Return => self.write_byte(op::RETURN, line),
+            OpCode::Print => self.write_byte(op::PRINT, line),
+            OpCode::Pop => self.write_byte(op::POP, line),
+            OpCode::Inherit => self.write_byte(op::INHERIT, line),
+            OpCode::CloseUpvalue => self.write_byte(op::CLOSE_UPVALUE, line),
+            OpCode::Nop => self.write_byte(op::NOP, line),
+            OpCode::Data(byte) => self.write_byte(byte, line),
+            OpCode::AddLocals(a, b) => {
+                self.write_byte(op::ADD_LOCALS, line);
+                self.write_bytes(a as u8, b as u8, line);
+            }
+            OpCode::AddConstant(index) => self.write_bytes(op::ADD_CONSTANT, index as u8, line),
+            OpCode::NotEqual => self.write_byte(op::NOT_EQUAL, line),
+            OpCode::SetLocalPop(slot) => self.write_bytes(op::SET_LOCAL_POP, slot as u8, line),
+            OpCode::BuildList(count) => self.write_short(op::BUILD_LIST, count as u16, line),
+            OpCode::GetIndex => self.write_byte(op::GET_INDEX, line),
+            OpCode::SetIndex => self.write_byte(op::SET_INDEX, line),
+            OpCode::Dup2 => self.write_byte(op::DUP2, line),
+            OpCode::Stringify => self.write_byte(op::STRINGIFY, line),
+            OpCode::Modulo => self.write_byte(op::MODULO, line),
+            OpCode::Power => self.write_byte(op::POWER, line),
+            OpCode::BitAnd => self.write_byte(op::BIT_AND, line),
+            OpCode::BitOr => self.write_byte(op::BIT_OR, line),
+            OpCode::BitXor => self.write_byte(op::BIT_XOR, line),
+            OpCode::BitNot => self.write_byte(op::BIT_NOT, line),
+            OpCode::ShiftLeft => self.write_byte(op::SHIFT_LEFT, line),
+            OpCode::ShiftRight => self.write_byte(op::SHIFT_RIGHT, line),
         }
     }
-    pub fn write_chunk(&mut self, byte: OpCode, line: usize) {
+    fn write_byte(&mut self, byte: u8, line: usize) {
         self.code.push(byte);
         self.lines.push(line);
         self.count += 1;
     }
+    fn write_bytes(&mut self, byte1: u8, byte2: u8, line: usize) {
+        self.write_byte(byte1, line);
+        self.write_byte(byte2, line);
+    }
+    fn write_indexed(&mut self, narrow: u8, index: isize, line: usize) {
+        if let Ok(index) = u8::try_from(index) {
+            self.write_bytes(narrow, index, line);
+        } else {
+            let [_, high, middle, low] = (index as u32).to_be_bytes();
+            self.write_byte(narrow + 1, line);
+            self.write_bytes(high, middle, line);
+            self.write_byte(low, line);
+        }
+    }
+    /// Numbers a new inline cache. Past `MAX_INLINE_CACHES` the number wraps; the
+    /// compiler reports that as an error.
+    fn write_cache(&mut self, line: usize) {
+        let [high, low] = (self.caches.len() as u16).to_be_bytes();
+        self.caches.push(RefCell::default());
+        self.write_bytes(high, low, line);
+    }
+    /// Writes `opcode` with a two-byte operand.
+    fn write_short(&mut self, opcode: u8, operand: u16, line: usize) {
+        let [high, low] = operand.to_be_bytes();
+        self.write_byte(opcode, line);
+        self.write_bytes(high, low, line);
+    }
+    /// Drops every byte from `len` on.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+        self.count = self.code.len() as isize;
+    }
+    /// Overwrites the operand of the jump instruction at `offset`.
+    pub fn patch_jump(&mut self, offset: usize, jump: u16) {
+        let [high, low] = jump.to_be_bytes();
+        self.code[offset + 1] = high;
+        self.code[offset + 2] = low;
+    }
+    /// Decodes the instruction at `offset` and returns it with the offset just past
+    /// its operands. A `Closure`'s upvalue bytes are left for the caller to read.
+    pub fn read(&self, offset: usize) -> (OpCode, usize) {
+        let opcode = self.code[offset];
+        let width = operand_width(opcode);
+        let operand = self.code[offset + 1..offset + 1 + width]
+            .iter()
+            .fold(0isize, |operand, &byte| operand << 8 | byte as isize);
+        let mut next = offset + 1 + width;
+        let instruction = match opcode {
+            op::CONSTANT | op::CONSTANT_LONG => OpCode::Constant(operand),
+            op::DEFINE_GLOBAL | op::DEFINE_GLOBAL_LONG => OpCode::DefineGlobal(operand),
+            op::GET_GLOBAL | op::GET_GLOBAL_LONG => OpCode::GetGlobal(operand),
+            op::SET_GLOBAL | op::SET_GLOBAL_LONG => OpCode::SetGlobal(operand),
+            op::GET_PROPERTY | op::GET_PROPERTY_LONG => {
+                next += 2;
+                OpCode::GetProperty(operand)
+            }
+            op::SET_PROPERTY | op::SET_PROPERTY_LONG => {
+                next += 2;
+                OpCode::SetProperty(operand)
+            }
+            op::GET_SUPER | op::GET_SUPER_LONG => OpCode::GetSuper(operand),
+            op::CLASS | op::CLASS_LONG => OpCode::Class(operand),
+            op::METHOD | op::METHOD_LONG => OpCode::Method(operand),
+            op::CLOSURE | op::CLOSURE_LONG => OpCode::Closure(operand),
+            op::INVOKE | op::INVOKE_LONG | op::SUPER_INVOKE | op::SUPER_INVOKE_LONG => {
+                let arg_count = self.code[next] as isize;
+                next += 1;
+                if opcode <= op::INVOKE_LONG {
+                    next += 2;
+                    OpCode::Invoke(operand, arg_count)
+                } else {
+                    OpCode::SuperInvoke(operand, arg_count)
+                }
+            }
+            op::GET_LOCAL => OpCode::GetLocal(operand),
+            op::SET_LOCAL => OpCode::SetLocal(operand),
+            op::GET_UPVALUE => OpCode::GetUpvalue(operand),
+            op::SET_UPVALUE => OpCode::SetUpvalue(operand),
+            op::CALL => OpCode::Call(operand),
+            op::JUMP => OpCode::Jump(operand as u16),
+            op::JUMP_IF_FALSE => OpCode::JumpIfFalse(operand as u16),
+            op::LOOP => OpCode::Loop(operand as u16),
+            op::NIL => OpCode::Nil,
+            op::TRUE => OpCode::True,
+            op::FALSE => OpCode::False,
+            op::ADD => OpCode::Add,
+            op::SUBTRACT => OpCode::Subtract,
+            op::MULTIPLY => OpCode::Multiply,
+            op::DIVIDE => OpCode::Divide,
+            op::NOT => OpCode::Not,
+            op::EQUAL => OpCode::Equal,
+            op::GREATER => OpCode::Greater,
+            op::LESS => OpCode::Less,
+            op::NEGATE => OpCode::Negate,
+            op::RETURN => OpCode::Return,
+            op::PRINT => OpCode::Print,
+            op::POP => OpCode::Pop,
+            op::INHERIT => OpCode::Inherit,
+            op::CLOSE_UPVALUE => OpCode::CloseUpvalue,
+            op::NOP => OpCode::Nop,
+            op::ADD_LOCALS => OpCode::AddLocals(operand >> 8, operand & 0xff),
+            op::ADD_CONSTANT => OpCode::AddConstant(operand),
+            op::NOT_EQUAL => OpCode::NotEqual,
+            op::SET_LOCAL_POP => OpCode::SetLocalPop(operand),
+            op::BUILD_LIST => OpCode::BuildList(operand),
+            op::GET_INDEX => OpCode::GetIndex,
+            op::SET_INDEX => OpCode::SetIndex,
+            op::DUP2 => OpCode::Dup2,
+            op::STRINGIFY => OpCode::Stringify,
+            op::MODULO => OpCode::Modulo,
+            op::POWER => OpCode::Power,
+            op::BIT_AND => OpCode::BitAnd,
+            op::BIT_OR => OpCode::BitOr,
+            op::BIT_XOR => OpCode::BitXor,
+            op::BIT_NOT => OpCode::BitNot,
+            op::SHIFT_LEFT => OpCode::ShiftLeft,
+            op::SHIFT_RIGHT => OpCode::ShiftRight,
+            _ => panic!("Unknown opcode byte {opcode} at offset {offset}"),
+        };
+        (instruction, next)
+    }
+    /// The inline cache of the instruction that ends just before `next`, the offset
+    /// [`Chunk::read`] returned for it.
+    pub fn cache(&self, next: usize) -> &RefCell<InlineCache> {
+        let index = u16::from_be_bytes([self.code[next - 2], self.code[next - 1]]);
+        &self.caches[index as usize]
+    }
+    /// Whether the instruction at `offset` uses the three-byte `_LONG` operand form.
+    pub fn is_long(&self, offset: usize) -> bool {
+        let opcode = self.code[offset];
+        opcode <= op::LAST_INDEXED && opcode % 2 == 1
+    }
+    /// The offset of the instruction after the one at `offset`, past any upvalue bytes.
+    pub fn next_offset(&self, offset: usize) -> usize {
+        match self.read(offset) {
+            (OpCode::Closure(index), next) => next + 2 * self.upvalue_count(index),
+            (_, next) => next,
+        }
+    }
+    /// The number of upvalue pairs following a `Closure` of the function constant `index`.
+    pub fn upvalue_count(&self, index: isize) -> usize {
+        match self.constants.get(index as usize).and_then(Value::obj) {
+            Some(obj) => match &*obj.borrow() {
+                Obj::Function(function) => function.upvalue_count,
+                _ => 0,
+            },
+            None => 0,
+        }
+    }
+    /// The offset of the instruction whose bytes include `offset`.
+    pub fn instruction_start(&self, offset: usize) -> usize {
+        let mut start = 0;
+        loop {
+            let next = self.next_offset(start);
+            if next > offset || next >= self.code.len() {
+                return start;
+            }
+            start = next;
+        }
+    }
+    /// Decodes the whole chunk, listing a closure's upvalue bytes as `Data`.
+    pub fn instructions(&self) -> Vec<OpCode> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let (instruction, next) = self.read(offset);
+            instructions.push(instruction);
+            let end = self.next_offset(offset);
+            instructions.extend(self.code[next..end].iter().map(|&byte| OpCode::Data(byte)));
+            offset = end;
+        }
+        instructions
+    }
     pub fn add_constant(&mut self, value: Value) -> isize {
         self.constants.push(value);
         self.constants.len() as isize - 1
     }
 }
 
+fn operand_width(opcode: u8) -> usize {
+    match opcode {
+        op::CONSTANT..=op::LAST_INDEXED if opcode % 2 == 1 => 3,
+        op::CONSTANT..=op::LAST_INDEXED => 1,
+        op::GET_LOCAL | op::SET_LOCAL | op::GET_UPVALUE | op::SET_UPVALUE | op::CALL => 1,
+        op::ADD_CONSTANT | op::SET_LOCAL_POP => 1,
+        op::JUMP | op::JUMP_IF_FALSE | op::LOOP | op::ADD_LOCALS | op::BUILD_LIST => 2,
+        _ => 0,
+    }
+}
+
 impl Default for Chunk {
     fn default() -> Self {
         Self::new()
@@ -81,7 +462,8 @@ impl Default for Chunk {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::object::{Obj, ObjString};
+    use crate::object::{Obj, ObjFunction, ObjString};
+    use rstest::rstest;
     use std::cell::RefCell;
     use std::rc::Rc;
 
@@ -108,12 +490,9 @@ mod tests {
         chunk.write_chunk(OpCode::Pop, 11);
         chunk.write_chunk(OpCode::Constant(123), 12);
 
-        assert_eq!(chunk.count, 3);
-        assert_eq!(
-            chunk.code,
-            vec![OpCode::Add, OpCode::Pop, OpCode::Constant(123)]
-        );
-        assert_eq!(chunk.lines, vec![10, 11, 12]);
+        assert_eq!(chunk.count, 4);
+        assert_eq!(chunk.code, vec![op::ADD, op::POP, op::CONSTANT, 123]);
+        assert_eq!(chunk.lines, vec![10, 11, 12, 12]);
 
         // Ensure code/lines stay in sync.
         assert_eq!(chunk.code.len(), chunk.lines.len());
@@ -124,21 +503,103 @@ mod tests {
     fn add_constant_returns_index_and_stores_value() {
         let mut chunk = Chunk::new();
         let str_obj1 = ObjString::from_string("hello".to_owned());
-        let str_value1 = Value::Obj(Rc::new(RefCell::new(Obj::String(str_obj1))));
+        let str_value1 = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(str_obj1)))));
         let idx0 = chunk.add_constant(Value::Number(1.25));
-        let idx1 = chunk.add_constant(str_value1);
+        let idx1 = chunk.add_constant(str_value1.clone());
         let idx2 = chunk.add_constant(Value::Nil);
 
         assert_eq!(idx0, 0);
         assert_eq!(idx1, 1);
         assert_eq!(idx2, 2);
 
-        let str_obj2 = ObjString::from_string("hello".to_owned());
-        let str_value2 = Value::Obj(Rc::new(RefCell::new(Obj::String(str_obj2))));
-
         assert_eq!(
             chunk.constants,
-            vec![Value::Number(1.25), str_value2, Value::Nil]
+            vec![Value::Number(1.25), str_value1, Value::Nil]
+        );
+    }
+
+    #[rstest]
+    #[case(OpCode::Nil, 1)]
+    #[case(OpCode::Constant(0), 2)]
+    #[case(OpCode::Constant(255), 2)]
+    #[case(OpCode::Constant(256), 4)]
+    #[case(OpCode::GetGlobal(70_000), 4)]
+    #[case(OpCode::Closure(MAX_LONG_OPERAND as isize), 4)]
+    #[case(OpCode::Invoke(3, 2), 5)]
+    #[case(OpCode::SuperInvoke(300, 255), 5)]
+    #[case(OpCode::GetProperty(7), 4)]
+    #[case(OpCode::SetProperty(300), 6)]
+    #[case(OpCode::GetLocal(255), 2)]
+    #[case(OpCode::Call(8), 2)]
+    #[case(OpCode::Jump(0x1234), 3)]
+    #[case(OpCode::Loop(u16::MAX), 3)]
+    #[case(OpCode::AddLocals(1, 255), 3)]
+    #[case(OpCode::AddConstant(200), 2)]
+    #[case(OpCode::NotEqual, 1)]
+    #[case(OpCode::SetLocalPop(3), 2)]
+    #[case(OpCode::BuildList(0), 3)]
+    #[case(OpCode::BuildList(u16::MAX as isize), 3)]
+    #[case(OpCode::GetIndex, 1)]
+    #[case(OpCode::SetIndex, 1)]
+    #[case(OpCode::Dup2, 1)]
+    #[case(OpCode::Stringify, 1)]
+    #[case(OpCode::Modulo, 1)]
+    #[case(OpCode::Power, 1)]
+    #[case(OpCode::BitNot, 1)]
+    #[case(OpCode::ShiftRight, 1)]
+    fn instructions_round_trip(#[case] instruction: OpCode, #[case] size: usize) {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(instruction, 1);
+        assert_eq!(chunk.code.len(), size);
+        assert_eq!(chunk.read(0), (instruction, size));
+    }
+
+    #[test]
+    fn wide_indexes_use_the_long_form() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Constant(255), 1);
+        chunk.write_chunk(OpCode::Constant(0x012345), 1);
+        assert_eq!(
+            chunk.code,
+            vec![op::CONSTANT, 255, op::CONSTANT_LONG, 0x01, 0x23, 0x45]
+        );
+        assert!(!chunk.is_long(0));
+        assert!(chunk.is_long(2));
+    }
+
+    #[test]
+    fn patch_jump_rewrites_the_operand() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Nil, 1);
+        chunk.write_chunk(OpCode::JumpIfFalse(0), 1);
+        chunk.patch_jump(1, 0x0102);
+        assert_eq!(chunk.read(1), (OpCode::JumpIfFalse(0x0102), 4));
+    }
+
+    #[test]
+    fn instructions_list_upvalue_bytes_as_data() {
+        let mut function = ObjFunction::new();
+        function.upvalue_count = 1;
+        let mut chunk = Chunk::new();
+        let index = chunk.add_constant(Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            function,
+        ))))));
+        chunk.write_chunk(OpCode::Closure(index), 1);
+        chunk.write_chunk(OpCode::Data(1), 1);
+        chunk.write_chunk(OpCode::Data(3), 1);
+        chunk.write_chunk(OpCode::Return, 2);
+
+        assert_eq!(
+            chunk.instructions(),
+            vec![
+                OpCode::Closure(0),
+                OpCode::Data(1),
+                OpCode::Data(3),
+                OpCode::Return
+            ]
         );
+        assert_eq!(chunk.next_offset(0), 4);
+        assert_eq!(chunk.instruction_start(3), 0);
+        assert_eq!(chunk.instruction_start(4), 4);
     }
 }
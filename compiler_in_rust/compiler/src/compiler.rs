@@ -1,19 +1,21 @@
-use crate::object::{Obj, ObjString};
-use crate::value::Value;
+use crate::object::Obj;
+use crate::value::{Value, shift_left, shift_right, to_integer};
 use crate::{
-    chunks::{Chunk, OpCode},
+    chunks::{Chunk, MAX_INLINE_CACHES, MAX_LONG_OPERAND, OpCode},
+    diagnostics::{Diagnostic, Severity},
+    globals::GlobalNames,
+    interner::Interner,
     object::ObjFunction,
-    scanner::{Scanner, Token, TokenType},
+    optimizer::{self, OptLevel},
+    scanner::{Scanner, Token, TokenType, string_contents, unescape},
 };
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
-fn print_constants(constants: &Vec<Value>) {
-    for constant in constants.iter() {
-        constant.print()
-    }
-}
+/// Locals and upvalues are addressed by a one-byte operand.
+const MAX_SLOTS: usize = 256;
+
 pub fn assert_constants_eq(actual: &[Value], expected: &[Value]) {
     assert_eq!(
         actual.len(),
@@ -24,10 +26,8 @@ pub fn assert_constants_eq(actual: &[Value], expected: &[Value]) {
     );
 
     for (i, (a, e)) in actual.iter().zip(expected.iter()).enumerate() {
-        print!("actual: ");
-        a.print();
-        print!("expected: ");
-        e.print();
+        println!("actual: {a}");
+        println!("expected: {e}");
         assert!(
             values_equal(a, e),
             "constant {} differs:\nactual:   {:?}\nexpected: {:?}",
@@ -39,12 +39,11 @@ pub fn assert_constants_eq(actual: &[Value], expected: &[Value]) {
 }
 
 fn values_equal(a: &Value, b: &Value) -> bool {
-    match (a, b) {
-        (Value::Bool(x), Value::Bool(y)) => x == y,
-        (Value::Nil, Value::Nil) => true,
-        (Value::Number(x), Value::Number(y)) => (x - y).abs() < f64::EPSILON, // safer for floats
-
-        (Value::Obj(x), Value::Obj(y)) => {
+    if a.is_number() && b.is_number() {
+        return (a.as_number() - b.as_number()).abs() < f64::EPSILON; // safer for floats
+    }
+    match (a.obj(), b.obj()) {
+        (Some(x), Some(y)) => {
             let x_obj = x.borrow();
             let y_obj = y.borrow();
 
@@ -89,11 +88,112 @@ fn values_equal(a: &Value, b: &Value) -> bool {
             }
         }
 
-        _ => false,
+        _ => a == b,
+    }
+}
+
+/// Evaluates `left op right` for two literals the way the VM would, or returns `None`
+/// where the VM raises an error, so that the error stays at run time.
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+fn fold_binary(
+    op: &TokenType,
+    left: &Value,
+    right: &Value,
+    strings: &mut Interner,
+) -> Option<Value> {
+    match op {
+        TokenType::TokenEqualEqual => return Some(Value::Bool(left == right)),
+        TokenType::TokenBangEqual => return Some(Value::Bool(left != right)),
+        TokenType::TokenPlus if !left.is_number() || !right.is_number() => {
+            let (left, right) = (left.obj()?.borrow(), right.obj()?.borrow());
+            return match (&*left, &*right) {
+                (Obj::String(a), Obj::String(b)) => {
+                    Some(strings.intern_owned(format!("{}{}", a.data, b.data)))
+                }
+                _ => None,
+            };
+        }
+        _ => {}
+    }
+    if !left.is_number() || !right.is_number() {
+        return None;
+    }
+    let (a, b) = (left.as_number(), right.as_number());
+    let value = match op {
+        TokenType::TokenPlus => Value::Number(a + b),
+        TokenType::TokenMinus => Value::Number(a - b),
+        TokenType::TokenStar => Value::Number(a * b),
+        TokenType::TokenSlash => Value::Number(a / b),
+        TokenType::TokenPercent => Value::Number(a % b),
+        TokenType::TokenStarStar => Value::Number(a.powf(b)),
+        TokenType::TokenGreater => Value::Bool(a > b),
+        // The VM runs these as `!(a < b)` and `!(a > b)`, which differ for NaN.
+        TokenType::TokenGreaterEqual => Value::Bool(!(a < b)),
+        TokenType::TokenLess => Value::Bool(a < b),
+        TokenType::TokenLessEqual => Value::Bool(!(a > b)),
+        _ => return fold_bitwise(op, to_integer(a)?, to_integer(b)?),
+    };
+    Some(value)
+}
+
+fn fold_bitwise(op: &TokenType, a: i64, b: i64) -> Option<Value> {
+    let value = match op {
+        TokenType::TokenAmpersand => a & b,
+        TokenType::TokenPipe => a | b,
+        TokenType::TokenCaret => a ^ b,
+        TokenType::TokenLessLess => shift_left(a, b)?,
+        TokenType::TokenGreaterGreater => shift_right(a, b)?,
+        _ => return None,
+    };
+    Some(Value::Number(value as f64))
+}
+
+/// Evaluates `op operand` for a literal, or returns `None` where the VM raises an error.
+fn fold_unary(op: &TokenType, operand: &Value) -> Option<Value> {
+    match op {
+        TokenType::TokenBang => Some(Value::Bool(
+            operand.is_nil() || (operand.is_bool() && !operand.as_bool()),
+        )),
+        TokenType::TokenMinus if operand.is_number() => Some(Value::Number(-operand.as_number())),
+        TokenType::TokenTilde if operand.is_number() => {
+            Some(Value::Number(!to_integer(operand.as_number())? as f64))
+        }
+        _ => None,
+    }
+}
+
+/// Compiles a whole script into its top-level function, or returns the compile errors.
+pub fn compile(source: String) -> Result<ObjFunction, Vec<Diagnostic>> {
+    compile_with_globals(source, Rc::new(RefCell::new(GlobalNames::new())))
+}
+
+/// Compiles `source`, numbering its global variables in `globals`.
+pub fn compile_with_globals(
+    source: String,
+    globals: Rc<RefCell<GlobalNames>>,
+) -> Result<ObjFunction, Vec<Diagnostic>> {
+    compile_with_options(source, globals, OptLevel::O0)
+}
+
+/// Like [`compile_with_globals`], optimizing every function at `opt_level`.
+pub fn compile_with_options(
+    source: String,
+    globals: Rc<RefCell<GlobalNames>>,
+    opt_level: OptLevel,
+) -> Result<ObjFunction, Vec<Diagnostic>> {
+    let mut compiler = Compiler::new(None, FunctionType::TypeScript);
+    compiler.globals = globals;
+    compiler.opt_level = opt_level;
+    let function = compiler.compile(source);
+    if compiler.had_error {
+        Err(compiler.diagnostics)
+    } else {
+        Ok(function)
     }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Precedence {
     PrecNone,
     PrecAssignment,
@@ -101,14 +201,20 @@ enum Precedence {
     PrecAnd,
     PrecEquality,
     PrecComparison,
+    PrecBitOr,
+    PrecBitXor,
+    PrecBitAnd,
+    PrecShift,
     PrecTerm,
     PrecFactor,
     PrecUnary,
+    PrecExponent,
     PrecCall,
     PrecPrimary,
 }
 
 #[derive(Copy, Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 enum ExprssionType {
     GROUPING,
     CALL,
@@ -123,10 +229,11 @@ enum ExprssionType {
     THIS,
     OR,
     AND,
+    LIST,
+    INDEX,
+    INTERPOLATION,
 }
 
-type ParseFn = fn(can_assign: bool);
-
 #[derive(Copy, Clone, Debug)]
 struct ParseRule {
     pub prefix: Option<ExprssionType>,
@@ -142,12 +249,21 @@ lazy_static::lazy_static! {
         m.insert(TokenType::TokenRightParen,    ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenLeftBrace,     ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenRightBrace,    ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
+        m.insert(TokenType::TokenLeftBracket,   ParseRule { prefix: Some(ExprssionType::LIST),     infix: Some(ExprssionType::INDEX),  precedence: Precedence::PrecCall });
+        m.insert(TokenType::TokenRightBracket,  ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
+        m.insert(TokenType::TokenColon,         ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenDot,           ParseRule { prefix: None,                          infix: Some(ExprssionType::DOT),    precedence: Precedence::PrecCall });
         m.insert(TokenType::TokenMinus,         ParseRule { prefix: Some(ExprssionType::UNARY),    infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecTerm });
         m.insert(TokenType::TokenPlus,          ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecTerm });
         m.insert(TokenType::TokenSemicolon,     ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenSlash,         ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecFactor });
         m.insert(TokenType::TokenStar,          ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecFactor });
+        m.insert(TokenType::TokenStarStar,      ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecExponent });
+        m.insert(TokenType::TokenPercent,       ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecFactor });
+        m.insert(TokenType::TokenAmpersand,     ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecBitAnd });
+        m.insert(TokenType::TokenPipe,          ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecBitOr });
+        m.insert(TokenType::TokenCaret,         ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecBitXor });
+        m.insert(TokenType::TokenTilde,         ParseRule { prefix: Some(ExprssionType::UNARY),    infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenBang,          ParseRule { prefix: Some(ExprssionType::UNARY),    infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenBangEqual,     ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecEquality });
         m.insert(TokenType::TokenEqual,         ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
@@ -156,11 +272,16 @@ lazy_static::lazy_static! {
         m.insert(TokenType::TokenGreaterEqual,  ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecComparison });
         m.insert(TokenType::TokenLess,          ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecComparison });
         m.insert(TokenType::TokenLessEqual,     ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecComparison });
+        m.insert(TokenType::TokenLessLess,      ParseRule { prefix: None,                          infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecShift });
+        m.insert(TokenType::TokenGreaterGreater, ParseRule { prefix: None,                         infix: Some(ExprssionType::BINARY), precedence: Precedence::PrecShift });
         m.insert(TokenType::TokenIdentifier,    ParseRule { prefix: Some(ExprssionType::VARIABLE), infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenString,        ParseRule { prefix: Some(ExprssionType::STRING),   infix: None,                        precedence: Precedence::PrecNone });
+        m.insert(TokenType::TokenInterpolation, ParseRule { prefix: Some(ExprssionType::INTERPOLATION), infix: None,                  precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenNumber,        ParseRule { prefix: Some(ExprssionType::NUMBER),   infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenAnd,           ParseRule { prefix: None,                          infix: Some(ExprssionType::AND),    precedence: Precedence::PrecAnd });
+        m.insert(TokenType::TokenBreak,         ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenClass,         ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
+        m.insert(TokenType::TokenContinue,      ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenElse,          ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenFalse,         ParseRule { prefix: Some(ExprssionType::LITERAL),  infix: None,                        precedence: Precedence::PrecNone });
         m.insert(TokenType::TokenFor,           ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecNone });
@@ -181,6 +302,7 @@ lazy_static::lazy_static! {
         m.insert(TokenType::TokenMinusEqual,    ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecAssignment });
         m.insert(TokenType::TokenStarEqual,     ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecAssignment });
         m.insert(TokenType::TokenSlashEqual,    ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecAssignment });
+        m.insert(TokenType::TokenPercentEqual,  ParseRule { prefix: None,                          infix: None,                        precedence: Precedence::PrecAssignment });
         m
     };
 }
@@ -204,7 +326,20 @@ struct Upvalue {
     is_local: bool,
 }
 
+/// A loop whose body is being compiled, for `break` and `continue` to find.
+#[derive(Clone, Debug)]
+struct LoopContext {
+    label: Option<String>,
+    /// Where `continue` jumps back to: the increment clause of a `for`, or the condition.
+    continue_target: usize,
+    /// Locals deeper than this are declared inside the loop and must go on the way out.
+    scope_depth: isize,
+    /// `break` jumps, patched once the end of the loop is known.
+    break_jumps: Vec<isize>,
+}
+
 #[derive(PartialEq, Clone, Copy, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum FunctionType {
     TypeFunction,
     TypeScript,
@@ -221,11 +356,25 @@ pub(crate) struct Compiler {
     locals: Vec<Local>,
     upvalues: Vec<Upvalue>,
     scope_depth: isize,
+    /// The loops around the code being compiled, innermost last.
+    loops: Vec<LoopContext>,
     scanner: Option<Scanner>,
     current: Token,
     previous: Token,
     pub had_error: bool,
     panic_mode: bool,
+    pub(crate) diagnostics: Vec<Diagnostic>,
+    pub(crate) repl: bool,
+    pub(crate) globals: Rc<RefCell<GlobalNames>>,
+    pub(crate) strings: Rc<RefCell<Interner>>,
+    /// The last instruction emitted; the bytes of `code` alone are ambiguous.
+    last_instruction: Option<OpCode>,
+    /// Where `last_instruction` starts in `code`.
+    last_instruction_start: usize,
+    /// The furthest offset a jump lands on so far. Code that ends there may be the
+    /// tail of an `and` or `or`, so it is not folded.
+    last_jump_target: usize,
+    pub(crate) opt_level: OptLevel,
 }
 
 #[derive(Clone, Debug)]
@@ -242,10 +391,15 @@ impl Precedence {
             Precedence::PrecOr => Precedence::PrecAnd,
             Precedence::PrecAnd => Precedence::PrecEquality,
             Precedence::PrecEquality => Precedence::PrecComparison,
-            Precedence::PrecComparison => Precedence::PrecTerm,
+            Precedence::PrecComparison => Precedence::PrecBitOr,
+            Precedence::PrecBitOr => Precedence::PrecBitXor,
+            Precedence::PrecBitXor => Precedence::PrecBitAnd,
+            Precedence::PrecBitAnd => Precedence::PrecShift,
+            Precedence::PrecShift => Precedence::PrecTerm,
             Precedence::PrecTerm => Precedence::PrecFactor,
             Precedence::PrecFactor => Precedence::PrecUnary,
-            Precedence::PrecUnary => Precedence::PrecCall,
+            Precedence::PrecUnary => Precedence::PrecExponent,
+            Precedence::PrecExponent => Precedence::PrecCall,
             Precedence::PrecCall => Precedence::PrecPrimary,
             Precedence::PrecPrimary => Precedence::PrecPrimary, // highest
         }
@@ -255,10 +409,10 @@ impl Precedence {
 impl Compiler {
     pub(crate) fn new(enclosing: Option<Box<Compiler>>, function_type: FunctionType) -> Self {
         let mut function = ObjFunction::new();
-        if function_type != FunctionType::TypeScript {
-            if let Some(ref parent) = enclosing {
-                function.name = parent.previous.lexeme.clone();
-            }
+        if function_type != FunctionType::TypeScript
+            && let Some(ref parent) = enclosing
+        {
+            function.name = parent.previous.lexeme.clone();
         }
 
         let mut compiler = Compiler {
@@ -267,34 +421,35 @@ impl Compiler {
             locals: Vec::new(),
             upvalues: Vec::new(),
             scope_depth: 0,
+            loops: Vec::new(),
             function: Box::new(function),
             scanner: None,
-            current: Token {
-                token_type: TokenType::TokenSynthetic,
-                lexeme: "".to_owned(),
-                line: 0,
-            },
-            previous: Token {
-                token_type: TokenType::TokenSynthetic,
-                lexeme: "".to_owned(),
-                line: 0,
-            },
+            current: Token::synthetic(TokenType::TokenSynthetic, ""),
+            previous: Token::synthetic(TokenType::TokenSynthetic, ""),
             had_error: false,
             panic_mode: false,
+            diagnostics: Vec::new(),
+            repl: false,
+            globals: enclosing
+                .as_ref()
+                .map_or_else(Default::default, |parent| Rc::clone(&parent.globals)),
+            strings: enclosing
+                .as_ref()
+                .map_or_else(Default::default, |parent| Rc::clone(&parent.strings)),
+            last_instruction: None,
+            last_instruction_start: 0,
+            last_jump_target: 0,
+            opt_level: enclosing
+                .as_ref()
+                .map_or_else(Default::default, |parent| parent.opt_level),
             class_compiler: enclosing.as_ref().and_then(|p| p.class_compiler.clone()),
         };
 
         let local_name = match function_type {
-            FunctionType::TypeMethod | FunctionType::TypeInitializer => Token {
-                token_type: TokenType::TokenThis,
-                lexeme: "this".to_owned(),
-                line: 0,
-            },
-            _ => Token {
-                token_type: TokenType::TokenSynthetic,
-                lexeme: "".to_owned(),
-                line: 0,
-            },
+            FunctionType::TypeMethod | FunctionType::TypeInitializer => {
+                Token::synthetic(TokenType::TokenThis, "this")
+            }
+            _ => Token::synthetic(TokenType::TokenSynthetic, ""),
         };
 
         compiler.locals.push(Local {
@@ -310,33 +465,88 @@ impl Compiler {
     }
 
     fn emit_byte(&mut self, byte: OpCode) {
+        if matches!(
+            byte,
+            OpCode::GetProperty(_) | OpCode::SetProperty(_) | OpCode::Invoke(_, _)
+        ) && self.current_chunk().caches.len() == MAX_INLINE_CACHES
+        {
+            self.error("Too many property accesses in one chunk.".to_owned());
+            return;
+        }
         let line = self.previous.line;
+        let start = self.current_chunk().code.len();
         self.current_chunk().write_chunk(byte, line);
+        if !matches!(byte, OpCode::Data(_)) {
+            self.last_instruction = Some(byte);
+            self.last_instruction_start = start;
+        }
     }
     fn emit_bytes(&mut self, byte1: OpCode, byte2: OpCode) {
         self.emit_byte(byte1);
         self.emit_byte(byte2);
     }
-    fn emit_jump(&mut self, instruction: fn(i16) -> OpCode) -> isize {
-        self.emit_byte(instruction(0));
-        self.current_chunk().count - 1
+    fn emit_jump(&mut self, instruction: fn(u16) -> OpCode) -> isize {
+        self.emit_byte(instruction(u16::MAX));
+        self.current_chunk().count - 3
     }
     fn patch_jump(&mut self, jump_index: isize) {
-        let offset = self.current_chunk().count - jump_index - 1;
-
-        let opcode = &mut self.current_chunk().code[jump_index as usize];
-
-        match opcode {
-            OpCode::Jump(o) | OpCode::JumpIfFalse(o) | OpCode::Loop(o) => {
-                *o = offset as i16;
-            }
-            _ => panic!("Invalid jump patch"),
-        }
+        // Jumps are taken from the end of the three-byte instruction.
+        let offset = self.current_chunk().count - jump_index - 3;
+        let Ok(offset) = u16::try_from(offset) else {
+            self.error("Too much code to jump over.".to_owned());
+            return;
+        };
+        self.current_chunk().patch_jump(jump_index as usize, offset);
+        self.last_jump_target = self.current_chunk().code.len();
     }
     fn emit_constant(&mut self, value: Value) {
-        let constant_index = self.current_chunk().add_constant(value);
+        let constant_index = self.make_constant(value);
         self.emit_byte(OpCode::Constant(constant_index));
     }
+    fn emit_literal(&mut self, value: Value) {
+        if value.is_nil() {
+            self.emit_byte(OpCode::Nil);
+        } else if value.is_bool() {
+            self.emit_byte(if value.as_bool() {
+                OpCode::True
+            } else {
+                OpCode::False
+            });
+        } else {
+            self.emit_constant(value);
+        }
+    }
+    /// The value the last instruction pushes, if it is a literal that starts at `start`
+    /// and no jump lands after it.
+    fn literal_at(&mut self, start: usize) -> Option<Value> {
+        if self.last_instruction_start != start || self.last_jump_target > start {
+            return None;
+        }
+        match self.last_instruction? {
+            OpCode::Constant(index) => Some(self.current_chunk().constants[index as usize].clone()),
+            OpCode::True => Some(Value::Bool(true)),
+            OpCode::False => Some(Value::Bool(false)),
+            OpCode::Nil => Some(Value::Nil),
+            _ => None,
+        }
+    }
+    /// Replaces the literals compiled from `start` on with the one `value` they fold to.
+    fn replace_with_literal(&mut self, start: usize, value: Value) {
+        let chunk = self.current_chunk();
+        // The operands' constants are the last ones added, so they can go as well.
+        let mut first_constant = chunk.constants.len();
+        let mut offset = start;
+        while offset < chunk.code.len() {
+            let (instruction, next) = chunk.read(offset);
+            if let OpCode::Constant(index) = instruction {
+                first_constant = first_constant.min(index as usize);
+            }
+            offset = next;
+        }
+        chunk.truncate(start);
+        chunk.constants.truncate(first_constant);
+        self.emit_literal(value);
+    }
     fn emit_return(&mut self) {
         if self.function_type == FunctionType::TypeInitializer {
             self.emit_byte(OpCode::GetLocal(0));
@@ -349,17 +559,19 @@ impl Compiler {
         if self.panic_mode {
             return;
         }
-        eprint!("[line {}]", token.line);
-        match token.token_type {
-            TokenType::TokenEof => {
-                eprint!(" at end");
-            }
-            TokenType::TokenError => {}
-            _ => {
-                eprint!(" at {}", token.lexeme);
-            }
-        }
-        eprintln!(": {}", message);
+        self.panic_mode = true;
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message,
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            lexeme: match token.token_type {
+                TokenType::TokenError => None,
+                _ => Some(token.lexeme),
+            },
+            at_end: token.token_type == TokenType::TokenEof || token.at_end,
+        });
         self.had_error = true;
     }
 
@@ -381,7 +593,6 @@ impl Compiler {
         }
     }
     fn consume(&mut self, token_type: TokenType, message: String) {
-        println!("{:?} == {:?}", self.current.token_type, token_type);
         if self.current.token_type == token_type {
             self.advance();
         } else {
@@ -405,6 +616,7 @@ impl Compiler {
         let prefix_rule = match get_rule(self.previous.token_type.clone()).prefix {
             Some(rule) => rule,
             _ => {
+                self.error("Expect expression.".to_owned());
                 return;
             }
         };
@@ -413,10 +625,12 @@ impl Compiler {
             ExprssionType::UNARY => self.unary(can_assign),
             ExprssionType::VARIABLE => self.variable(can_assign),
             ExprssionType::STRING => self.string(can_assign),
+            ExprssionType::INTERPOLATION => self.interpolation(can_assign),
             ExprssionType::NUMBER => self.number(can_assign),
             ExprssionType::LITERAL => self.literal(can_assign),
             ExprssionType::SUPER => self.super_(can_assign),
             ExprssionType::THIS => self.this(can_assign),
+            ExprssionType::LIST => self.list(can_assign),
             _ => self.error("Incorrect prefix rule".to_owned()),
         }
         while precedence <= get_rule(self.current.token_type.clone()).precedence {
@@ -430,6 +644,7 @@ impl Compiler {
             match infix_rule {
                 ExprssionType::CALL => self.call(false),
                 ExprssionType::DOT => self.dot(can_assign),
+                ExprssionType::INDEX => self.index(can_assign),
                 ExprssionType::AND => self.and(false),
                 ExprssionType::OR => self.or(false),
                 ExprssionType::BINARY => self.binary(false),
@@ -437,16 +652,59 @@ impl Compiler {
             }
         }
     }
-    fn string(&mut self, can_assign: bool) {
-        let value = self.previous.lexeme.clone().replace("\"", "");
-        let obj_string = ObjString::from_string(value);
-        self.emit_constant(Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string)))));
+    fn string(&mut self, _can_assign: bool) {
+        let string = self.string_part();
+        self.emit_constant(string);
+    }
+    /// Compiles `"a${x}b${y}c"` to `"a" + str(x) + "b" + str(y) + "c"`, leaving out
+    /// the empty parts.
+    fn interpolation(&mut self, _can_assign: bool) {
+        let mut parts = 0;
+        loop {
+            let part = self.string_part();
+            if !part.as_string().data.is_empty() {
+                self.emit_constant(part);
+                self.join_part(&mut parts);
+            }
+            if self.previous.token_type == TokenType::TokenString {
+                break;
+            }
+            // Only the rest of a string after `}` starts with a brace.
+            if self.current.lexeme.starts_with('}') {
+                let mut brace = self.current.clone();
+                brace.lexeme = "}".to_owned();
+                brace.span.end = brace.span.start + 1;
+                self.error_et(brace, "Expect expression.".to_owned());
+                return;
+            }
+            self.expression();
+            self.emit_byte(OpCode::Stringify);
+            self.join_part(&mut parts);
+            if !self.match_token(TokenType::TokenInterpolation)
+                && !self.match_token(TokenType::TokenString)
+            {
+                self.error_at_current("Expect '}' after interpolated expression.".to_owned());
+                return;
+            }
+        }
+    }
+    fn join_part(&mut self, parts: &mut usize) {
+        if *parts > 0 {
+            self.emit_byte(OpCode::Add);
+        }
+        *parts += 1;
+    }
+    /// The text of the string token just consumed, interned.
+    fn string_part(&mut self) -> Value {
+        let text = unescape(string_contents(&self.previous.lexeme))
+            .expect("the scanner reports invalid escapes");
+        self.strings.borrow_mut().intern_owned(text)
     }
-    fn number(&mut self, can_assign: bool) {
+    fn number(&mut self, _can_assign: bool) {
         let value: f64 = self.previous.lexeme.parse().unwrap_or(0.0);
         self.emit_constant(Value::Number(value));
     }
-    fn literal(&mut self, can_assign: bool) {
+    fn literal(&mut self, _can_assign: bool) {
         match self.previous.token_type {
             TokenType::TokenFalse => self.emit_byte(OpCode::False),
             TokenType::TokenTrue => self.emit_byte(OpCode::True),
@@ -474,13 +732,65 @@ impl Compiler {
             self.emit_byte(OpCode::GetProperty(name));
         }
     }
-    fn and(&mut self, can_assign: bool) {
+    fn list(&mut self, _can_assign: bool) {
+        let mut count: isize = 0;
+        if !self.check(TokenType::TokenRightBracket) {
+            loop {
+                self.expression();
+                if count == u16::MAX as isize {
+                    self.error("Too many elements in a list literal.".to_owned());
+                }
+                count += 1;
+                if !self.match_token(TokenType::TokenComma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::TokenRightBracket,
+            "Expect ']' after list elements.".to_owned(),
+        );
+        self.emit_byte(OpCode::BuildList(count));
+    }
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(
+            TokenType::TokenRightBracket,
+            "Expect ']' after index.".to_owned(),
+        );
+        if !can_assign {
+            self.emit_byte(OpCode::GetIndex);
+            return;
+        }
+        let op = match self.current.token_type {
+            TokenType::TokenEqual => {
+                self.advance();
+                self.expression();
+                self.emit_byte(OpCode::SetIndex);
+                return;
+            }
+            TokenType::TokenPlusEqual => OpCode::Add,
+            TokenType::TokenMinusEqual => OpCode::Subtract,
+            TokenType::TokenStarEqual => OpCode::Multiply,
+            TokenType::TokenSlashEqual => OpCode::Divide,
+            TokenType::TokenPercentEqual => OpCode::Modulo,
+            _ => {
+                self.emit_byte(OpCode::GetIndex);
+                return;
+            }
+        };
+        self.advance();
+        // Keep the list and index under the current value for the store.
+        self.emit_byte(OpCode::Dup2);
+        self.compound_assignment(OpCode::GetIndex, OpCode::SetIndex, op);
+    }
+    fn and(&mut self, _can_assign: bool) {
         let end_jump = self.emit_jump(OpCode::JumpIfFalse);
         self.emit_byte(OpCode::Pop);
         self.parse_precedence(Precedence::PrecAnd);
         self.patch_jump(end_jump);
     }
-    fn or(&mut self, can_assign: bool) {
+    fn or(&mut self, _can_assign: bool) {
         let else_jump = self.emit_jump(OpCode::JumpIfFalse);
         let end_jump = self.emit_jump(OpCode::Jump);
         self.patch_jump(else_jump);
@@ -492,18 +802,27 @@ impl Compiler {
     fn expression(&mut self) {
         self.parse_precedence(Precedence::PrecAssignment);
     }
-    fn grouping(&mut self, can_assign: bool) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.consume(
             TokenType::TokenRightParen,
             "Expect ')' after expression.".to_owned(),
         );
     }
-    fn unary(&mut self, can_assign: bool) {
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous.token_type.clone();
+        let start = self.current_chunk().code.len();
         self.parse_precedence(Precedence::PrecUnary);
-        match self.previous.token_type {
+        if let Some(operand) = self.literal_at(start)
+            && let Some(value) = fold_unary(&operator, &operand)
+        {
+            self.replace_with_literal(start, value);
+            return;
+        }
+        match operator {
             TokenType::TokenBang => self.emit_byte(OpCode::Not),
             TokenType::TokenMinus => self.emit_byte(OpCode::Negate),
+            TokenType::TokenTilde => self.emit_byte(OpCode::BitNot),
             _ => {}
         }
     }
@@ -523,6 +842,10 @@ impl Compiler {
         -1
     }
     fn add_local(&mut self, name: Token) {
+        if self.locals.len() == MAX_SLOTS {
+            self.error("Too many local variables in function.".to_owned());
+            return;
+        }
         self.locals.push(Local {
             name,
             depth: -1,
@@ -536,6 +859,10 @@ impl Compiler {
             }
         }
 
+        if self.upvalues.len() == MAX_SLOTS {
+            self.error("Too many closure variables in function.".to_owned());
+            return 0;
+        }
         self.upvalues.push(Upvalue { index, is_local });
         self.function.upvalue_count = self.upvalues.len();
         (self.upvalues.len() - 1) as isize
@@ -555,24 +882,33 @@ impl Compiler {
         -1
     }
     fn make_constant(&mut self, value: Value) -> isize {
-        self.current_chunk().add_constant(value);
-        (self.current_chunk().constants.len() - 1) as isize
+        if self.current_chunk().constants.len() > MAX_LONG_OPERAND {
+            self.error("Too many constants in one chunk.".to_owned());
+            return 0;
+        }
+        self.current_chunk().add_constant(value)
     }
     fn identifier_constant_once(&mut self, name: &Token) -> isize {
-        let obj_string = ObjString::from_string(name.lexeme.clone());
-        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        if let Some(i) = self.current_chunk().constants.iter().position(|c| match c {
-            Value::Obj(o1) => match &*o1.borrow() {
-                Obj::String(s1) => name.lexeme == s1.data,
-                _ => false,
-            },
-            _ => false,
-        }) {
+        let value = self.strings.borrow_mut().intern(&name.lexeme);
+        if let Some(i) = self
+            .current_chunk()
+            .constants
+            .iter()
+            .position(|c| *c == value)
+        {
             i as isize
         } else {
             self.make_constant(value)
         }
     }
+    fn global_slot(&mut self, name: &Token) -> isize {
+        let slot = self.globals.borrow_mut().slot(&name.lexeme);
+        if slot > MAX_LONG_OPERAND {
+            self.error("Too many global variables.".to_owned());
+            return 0;
+        }
+        slot as isize
+    }
     fn named_variable(&mut self, name: Token, can_assign: bool) {
         let (get_op, set_op) = {
             let local = self.resolve_local(name.clone());
@@ -583,7 +919,7 @@ impl Compiler {
                 if upvalue != -1 {
                     (OpCode::GetUpvalue(upvalue), OpCode::SetUpvalue(upvalue))
                 } else {
-                    let global = self.identifier_constant_once(&name);
+                    let global = self.global_slot(&name);
                     (OpCode::GetGlobal(global), OpCode::SetGlobal(global))
                 }
             }
@@ -605,22 +941,27 @@ impl Compiler {
             // Compound assignments: x += 2, x -= 3, etc.
             TokenType::TokenPlusEqual => {
                 self.advance();
-                self.compound_assignment(get_op.clone(), set_op.clone(), OpCode::Add);
+                self.compound_assignment(get_op, set_op, OpCode::Add);
             }
 
             TokenType::TokenMinusEqual => {
                 self.advance();
-                self.compound_assignment(get_op.clone(), set_op.clone(), OpCode::Subtract);
+                self.compound_assignment(get_op, set_op, OpCode::Subtract);
             }
 
             TokenType::TokenStarEqual => {
                 self.advance();
-                self.compound_assignment(get_op.clone(), set_op.clone(), OpCode::Multiply);
+                self.compound_assignment(get_op, set_op, OpCode::Multiply);
             }
 
             TokenType::TokenSlashEqual => {
                 self.advance();
-                self.compound_assignment(get_op.clone(), set_op.clone(), OpCode::Divide);
+                self.compound_assignment(get_op, set_op, OpCode::Divide);
+            }
+
+            TokenType::TokenPercentEqual => {
+                self.advance();
+                self.compound_assignment(get_op, set_op, OpCode::Modulo);
             }
 
             _ => {
@@ -630,11 +971,7 @@ impl Compiler {
         }
     }
     fn synthetic_token(&self, text: String) -> Token {
-        Token {
-            token_type: TokenType::TokenSynthetic,
-            lexeme: text.to_owned(),
-            line: 0,
-        }
+        Token::synthetic(TokenType::TokenSynthetic, &text)
     }
     fn argument_list(&mut self) -> isize {
         let mut arg_count: isize = 0;
@@ -642,6 +979,9 @@ impl Compiler {
         if !self.check(TokenType::TokenRightParen) {
             loop {
                 self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.".to_owned());
+                }
                 arg_count += 1;
 
                 if !self.match_token(TokenType::TokenComma) {
@@ -684,7 +1024,7 @@ impl Compiler {
             self.emit_byte(OpCode::GetSuper(name));
         }
     }
-    fn this(&mut self, can_assign: bool) {
+    fn this(&mut self, _can_assign: bool) {
         if self.class_compiler.is_none() {
             self.error("Can't use 'this' outside of a class.".to_owned());
             return;
@@ -692,10 +1032,27 @@ impl Compiler {
 
         self.variable(false);
     }
-    fn binary(&mut self, can_assign: bool) {
+    fn binary(&mut self, _can_assign: bool) {
         let token_type = self.previous.token_type.clone();
         let parse_rule = get_rule(token_type.clone());
-        self.parse_precedence(parse_rule.precedence.next());
+        let left_start = self.last_instruction_start;
+        let left = self.literal_at(left_start);
+        let right_start = self.current_chunk().code.len();
+        // `**` is right-associative, and its right operand may be negated: `2 ** -1`.
+        let right_precedence = match token_type {
+            TokenType::TokenStarStar => Precedence::PrecUnary,
+            _ => parse_rule.precedence.next(),
+        };
+        self.parse_precedence(right_precedence);
+        if let Some(left) = left
+            && let Some(right) = self.literal_at(right_start)
+        {
+            let folded = fold_binary(&token_type, &left, &right, &mut self.strings.borrow_mut());
+            if let Some(value) = folded {
+                self.replace_with_literal(left_start, value);
+                return;
+            }
+        }
         match token_type {
             TokenType::TokenBangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
             TokenType::TokenEqualEqual => self.emit_byte(OpCode::Equal),
@@ -707,13 +1064,20 @@ impl Compiler {
             TokenType::TokenMinus => self.emit_byte(OpCode::Subtract),
             TokenType::TokenStar => self.emit_byte(OpCode::Multiply),
             TokenType::TokenSlash => self.emit_byte(OpCode::Divide),
+            TokenType::TokenPercent => self.emit_byte(OpCode::Modulo),
+            TokenType::TokenStarStar => self.emit_byte(OpCode::Power),
+            TokenType::TokenAmpersand => self.emit_byte(OpCode::BitAnd),
+            TokenType::TokenPipe => self.emit_byte(OpCode::BitOr),
+            TokenType::TokenCaret => self.emit_byte(OpCode::BitXor),
+            TokenType::TokenLessLess => self.emit_byte(OpCode::ShiftLeft),
+            TokenType::TokenGreaterGreater => self.emit_byte(OpCode::ShiftRight),
             _ => {}
         }
     }
     fn compound_assignment(&mut self, get_op: OpCode, set_op: OpCode, op: OpCode) {
         self.emit_byte(get_op);
         self.expression(); // parse right-hand side
-        self.emit_byte(op); // perform the compound operation (+, -, *, /)
+        self.emit_byte(op); // perform the compound operation (+, -, *, /, %)
         self.emit_byte(set_op); // assign back (SetLocal / SetGlobal / SetUpvalue)
     }
     fn synchronize(&mut self) {
@@ -724,6 +1088,8 @@ impl Compiler {
             }
             match self.current.token_type {
                 TokenType::TokenClass
+                | TokenType::TokenBreak
+                | TokenType::TokenContinue
                 | TokenType::TokenFun
                 | TokenType::TokenVar
                 | TokenType::TokenFor
@@ -747,7 +1113,7 @@ impl Compiler {
             if local.depth != -1 && local.depth < self.scope_depth {
                 break;
             }
-            if name == local.name {
+            if name.lexeme == local.name.lexeme {
                 self.error("Already a variable with this name in this scope.".to_owned());
             }
         }
@@ -773,27 +1139,44 @@ impl Compiler {
     }
     fn end_scope(&mut self) {
         self.scope_depth -= 1;
-        while let Some(local) = self.locals.last() {
-            if local.depth <= self.scope_depth {
-                break;
-            }
-
-            if local.is_captured {
+        self.discard_locals(self.scope_depth, false);
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth > self.scope_depth)
+        {
+            self.locals.pop();
+        }
+    }
+    /// Pops the locals deeper than `depth` off the stack, closing the captured ones,
+    /// while they stay declared for the code that follows. A jump out of a loop is
+    /// compiled before the rest of the body, where a closure may still capture any of
+    /// them, so `close_all` closes every one.
+    fn discard_locals(&mut self, depth: isize, close_all: bool) {
+        let captured: Vec<bool> = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| close_all || local.is_captured)
+            .collect();
+        for is_captured in captured {
+            if is_captured {
                 self.emit_byte(OpCode::CloseUpvalue);
             } else {
                 self.emit_byte(OpCode::Pop);
             }
-
-            self.locals.pop();
         }
     }
     fn end_compiler(&mut self) -> ObjFunction {
-        let ends_with_return = matches!(self.current_chunk().code.last(), Some(OpCode::Return));
-        if !ends_with_return {
+        if self.last_instruction != Some(OpCode::Return) {
             self.emit_return();
         }
+        if self.opt_level == OptLevel::O1 {
+            optimizer::optimize(self.current_chunk());
+        }
         let function = self.function.clone();
-        return *function;
+        *function
     }
     fn parse_variable(&mut self, error_message: String) -> isize {
         self.consume(TokenType::TokenIdentifier, error_message);
@@ -802,17 +1185,31 @@ impl Compiler {
         if self.scope_depth > 0 {
             -1
         } else {
-            self.identifier_constant_once(&self.previous.clone())
+            self.global_slot(&self.previous.clone())
         }
     }
     fn expression_statement(&mut self) {
         self.expression();
+        if self.echoes_expressions() {
+            // The REPL echoes bare expressions and lets the last one skip its ';'.
+            if !self.check(TokenType::TokenEof) {
+                self.consume(
+                    TokenType::TokenSemicolon,
+                    "Expect ';' after expression.".to_owned(),
+                );
+            }
+            self.emit_byte(OpCode::Print);
+            return;
+        }
         self.consume(
             TokenType::TokenSemicolon,
             "Expect ';' after expression.".to_owned(),
         );
         self.emit_byte(OpCode::Pop);
     }
+    fn echoes_expressions(&self) -> bool {
+        self.repl && self.function_type == FunctionType::TypeScript && self.scope_depth == 0
+    }
     fn return_statement(&mut self) {
         if self.function_type == FunctionType::TypeScript {
             self.error("Can't return from top-level code.".to_owned());
@@ -858,10 +1255,85 @@ impl Compiler {
         self.patch_jump(else_jump);
     }
     fn emit_loop(&mut self, loop_start: usize) {
-        let offset = self.current_chunk().code.len() - loop_start + 1;
-        self.emit_byte(OpCode::Loop(offset as i16));
+        // Loops jump back from the end of their own three-byte instruction.
+        let offset = self.current_chunk().code.len() - loop_start + 3;
+        let offset = u16::try_from(offset).unwrap_or_else(|_| {
+            self.error("Loop body too large.".to_owned());
+            0
+        });
+        self.emit_byte(OpCode::Loop(offset));
+    }
+    fn begin_loop(&mut self, label: Option<String>, continue_target: usize) {
+        self.loops.push(LoopContext {
+            label,
+            continue_target,
+            scope_depth: self.scope_depth,
+            break_jumps: Vec::new(),
+        });
+    }
+    /// Lands the loop's `break` jumps here.
+    fn end_loop(&mut self) {
+        let context = self.loops.pop().expect("a loop is open");
+        for jump in context.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+    fn labeled_statement(&mut self) {
+        self.advance();
+        let label = self.previous.lexeme.clone();
+        self.advance();
+        if self.match_token(TokenType::TokenWhile) {
+            self.while_statement(Some(label));
+        } else if self.match_token(TokenType::TokenFor) {
+            self.for_statement(Some(label));
+        } else {
+            self.error_at_current("Expect a loop after a label.".to_owned());
+        }
     }
-    fn for_statement(&mut self) {
+    /// Whether the statement ahead starts with `label:`.
+    fn at_label(&mut self) -> bool {
+        self.check(TokenType::TokenIdentifier)
+            && self.scanner.as_mut().unwrap().peek_token().token_type == TokenType::TokenColon
+    }
+    /// Parses the rest of a `break` or `continue` and finds the loop it applies to.
+    fn target_loop(&mut self, keyword: &str) -> Option<usize> {
+        let label = self
+            .match_token(TokenType::TokenIdentifier)
+            .then(|| self.previous.lexeme.clone());
+        let index = match &label {
+            Some(label) => self
+                .loops
+                .iter()
+                .rposition(|context| context.label.as_ref() == Some(label)),
+            None => self.loops.len().checked_sub(1),
+        };
+        match (&label, index) {
+            (_, Some(_)) => {}
+            (Some(label), None) => self.error(format!("No enclosing loop labeled '{label}'.")),
+            (None, None) => self.error(format!("Can't use '{keyword}' outside of a loop.")),
+        }
+        self.consume(
+            TokenType::TokenSemicolon,
+            format!("Expect ';' after '{keyword}'."),
+        );
+        index
+    }
+    fn break_statement(&mut self) {
+        let Some(index) = self.target_loop("break") else {
+            return;
+        };
+        self.discard_locals(self.loops[index].scope_depth, true);
+        let jump = self.emit_jump(OpCode::Jump);
+        self.loops[index].break_jumps.push(jump);
+    }
+    fn continue_statement(&mut self) {
+        let Some(index) = self.target_loop("continue") else {
+            return;
+        };
+        self.discard_locals(self.loops[index].scope_depth, true);
+        self.emit_loop(self.loops[index].continue_target);
+    }
+    fn for_statement(&mut self, label: Option<String>) {
         self.begin_scope();
         self.consume(
             TokenType::TokenLeftParen,
@@ -878,7 +1350,7 @@ impl Compiler {
         if !self.match_token(TokenType::TokenSemicolon) {
             self.expression();
             self.consume(TokenType::TokenSemicolon, "Expect ';'.".to_owned());
-            exit_jump = self.emit_jump(OpCode::JumpIfFalse) as isize;
+            exit_jump = self.emit_jump(OpCode::JumpIfFalse);
             self.emit_byte(OpCode::Pop);
         }
         if !self.match_token(TokenType::TokenRightParen) {
@@ -894,15 +1366,17 @@ impl Compiler {
             loop_start = increment_start;
             self.patch_jump(body_jump);
         }
+        self.begin_loop(label, loop_start as usize);
         self.statement();
         self.emit_loop(loop_start as usize);
         if exit_jump != -1 {
             self.patch_jump(exit_jump);
             self.emit_byte(OpCode::Pop);
         }
+        self.end_loop();
         self.end_scope();
     }
-    fn while_statement(&mut self) {
+    fn while_statement(&mut self, label: Option<String>) {
         let loop_start = self.current_chunk().count;
         self.consume(
             TokenType::TokenLeftParen,
@@ -915,10 +1389,12 @@ impl Compiler {
         );
         let exit_jomp = self.emit_jump(OpCode::JumpIfFalse);
         self.emit_byte(OpCode::Pop);
+        self.begin_loop(label, loop_start as usize);
         self.statement();
         self.emit_loop(loop_start as usize);
         self.patch_jump(exit_jomp);
         self.emit_byte(OpCode::Pop);
+        self.end_loop();
     }
     fn statement(&mut self) {
         if self.match_token(TokenType::TokenPrint) {
@@ -926,9 +1402,15 @@ impl Compiler {
         } else if self.match_token(TokenType::TokenIf) {
             self.if_statement();
         } else if self.match_token(TokenType::TokenWhile) {
-            self.while_statement();
+            self.while_statement(None);
         } else if self.match_token(TokenType::TokenFor) {
-            self.for_statement();
+            self.for_statement(None);
+        } else if self.match_token(TokenType::TokenBreak) {
+            self.break_statement();
+        } else if self.match_token(TokenType::TokenContinue) {
+            self.continue_statement();
+        } else if self.at_label() {
+            self.labeled_statement();
         } else if self.match_token(TokenType::TokenLeftBrace) {
             self.begin_scope();
             self.block();
@@ -956,6 +1438,9 @@ impl Compiler {
         if !compiler.check(TokenType::TokenRightParen) {
             loop {
                 compiler.function.arity += 1;
+                if compiler.function.arity > 255 {
+                    compiler.error_at_current("Can't have more than 255 parameters.".to_owned());
+                }
                 let _param_constant = compiler.parse_variable("Expect parameter name.".to_owned());
                 compiler.define_variable(0); // This marks it initialized in the local scope
                 if !compiler.match_token(TokenType::TokenComma) {
@@ -975,12 +1460,19 @@ impl Compiler {
         let mut function_obj = compiler.end_compiler();
         function_obj.name = function_name.clone();
 
-        // Synchronize scanner state back to self
+        // Synchronize scanner and error state back to self
         self.scanner = compiler.scanner.clone();
         self.current = compiler.current.clone();
         self.previous = compiler.previous.clone();
-        let function_value = Value::Obj(Rc::new(RefCell::new(Obj::Function(function_obj))));
-        let function_constant = self.current_chunk().add_constant(function_value);
+        self.had_error |= compiler.had_error;
+        self.panic_mode = compiler.panic_mode;
+        self.diagnostics.append(&mut compiler.diagnostics);
+        if let Some(enclosing) = &compiler.enclosing {
+            self.merge_captures(enclosing);
+        }
+        let function_value =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(function_obj)))));
+        let function_constant = self.make_constant(function_value);
         self.emit_byte(OpCode::Closure(function_constant));
 
         for upvalue in &compiler.upvalues {
@@ -989,6 +1481,21 @@ impl Compiler {
             self.emit_byte(OpCode::Data(upvalue.index as u8));
         }
     }
+    /// Takes back what a nested function recorded on its copy of this compiler: the
+    /// locals it captured, so leaving their scope closes them rather than popping them,
+    /// and the upvalues it added to pass a capture through.
+    fn merge_captures(&mut self, copy: &Compiler) {
+        for (local, copied) in self.locals.iter_mut().zip(&copy.locals) {
+            local.is_captured |= copied.is_captured;
+        }
+        if copy.upvalues.len() > self.upvalues.len() {
+            self.upvalues = copy.upvalues.clone();
+            self.function.upvalue_count = self.upvalues.len();
+        }
+        if let (Some(enclosing), Some(copied)) = (&mut self.enclosing, &copy.enclosing) {
+            enclosing.merge_captures(copied);
+        }
+    }
     fn method(&mut self) {
         self.consume(TokenType::TokenIdentifier, "Expect method name.".to_owned());
         let constant = self.identifier_constant_once(&self.previous.clone());
@@ -1012,7 +1519,7 @@ impl Compiler {
         self.function(FunctionType::TypeFunction);
 
         if self.scope_depth == 0 {
-            let global_index = self.identifier_constant_once(&name_token);
+            let global_index = self.global_slot(&name_token);
             self.emit_byte(OpCode::DefineGlobal(global_index));
         }
     }
@@ -1036,27 +1543,24 @@ impl Compiler {
         );
 
         if self.scope_depth == 0 {
-            let global_index = self.identifier_constant_once(&name_token);
+            let global_index = self.global_slot(&name_token);
             self.emit_byte(OpCode::DefineGlobal(global_index));
         } else {
             self.mark_initialized();
         }
     }
-    fn define_variable_by_name(&mut self, name_token: &Token) {
-        if self.scope_depth > 0 {
-            self.mark_initialized();
-            return;
-        }
-        let name_constant = self.identifier_constant_once(&name_token.clone());
-        self.emit_byte(OpCode::DefineGlobal(name_constant));
-    }
     fn class_declaration(&mut self) {
         self.consume(TokenType::TokenIdentifier, "Expect class name.".to_owned());
         let class_name = self.previous.clone();
         let name_constant = self.identifier_constant_once(&self.previous.clone());
         self.declare_variable();
         self.emit_byte(OpCode::Class(name_constant));
-        self.define_variable(name_constant);
+        let global = if self.scope_depth > 0 {
+            -1
+        } else {
+            self.global_slot(&class_name)
+        };
+        self.define_variable(global);
         self.class_compiler = Some(Box::new(ClassCompiler {
             enclosing: self.class_compiler.take(),
             has_super_class: false,
@@ -1133,30 +1637,38 @@ impl Compiler {
         while !self.match_token(TokenType::TokenEof) {
             self.declaration();
         }
-        let function = self.end_compiler();
-        function
+        self.end_compiler()
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::object::ObjFunction;
+    use crate::object::{ObjFunction, ObjString};
+    use rstest::rstest;
+
+    /// Encodes `code` the way the compiler would, with one line per instruction.
+    fn chunk(code: Vec<OpCode>, lines: Vec<usize>, constants: Vec<Value>) -> Chunk {
+        assert_eq!(code.len(), lines.len());
+        let mut chunk = Chunk::new();
+        for (instruction, line) in code.into_iter().zip(lines) {
+            chunk.write_chunk(instruction, line);
+        }
+        chunk.constants = constants;
+        chunk
+    }
 
     #[test]
     fn test_expression1() {
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
-                OpCode::Constant(1),
-                OpCode::Add,
                 OpCode::Pop,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 1, 1, 1, 1],
-            constants: vec![Value::Number(2f64), Value::Number(3f64)],
-            count: 6,
-        };
+            vec![1, 1, 1, 1],
+            vec![Value::Number(5f64)],
+        );
         let source = "2 + 3;".to_owned();
         let mut compiler = Compiler::new(None, FunctionType::TypeScript);
         compiler.compile(source);
@@ -1168,21 +1680,16 @@ mod tests {
 
     #[test]
     fn test_expression2() {
-        let obj_string = ObjString::from_string("x".to_owned());
-        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
-                OpCode::Constant(1),
-                OpCode::Add,
-                OpCode::DefineGlobal(2),
+                OpCode::DefineGlobal(0),
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 1, 1, 1, 1],
-            constants: vec![Value::Number(2f64), Value::Number(3f64), value],
-            count: 6,
-        };
+            vec![1, 1, 1, 1],
+            vec![Value::Number(5f64)],
+        );
         let source = "var x = 2 + 3;".to_owned();
         let mut compiler = Compiler::new(None, FunctionType::TypeScript);
         compiler.compile(source);
@@ -1193,27 +1700,23 @@ mod tests {
     }
     #[test]
     fn test_plus_equal() {
-        let obj_string = ObjString::from_string("x".to_owned());
-        let value_x = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),     // 10
-                OpCode::DefineGlobal(1), // var x = 10;
-                OpCode::GetGlobal(1),    // x (for +=)
-                OpCode::Constant(2),     // 2
+                OpCode::DefineGlobal(0), // var x = 10;
+                OpCode::GetGlobal(0),    // x (for +=)
+                OpCode::Constant(1),     // 2
                 OpCode::Add,             // x + 2
-                OpCode::SetGlobal(1),    // x = ...
+                OpCode::SetGlobal(0),    // x = ...
                 OpCode::Pop,             // pop assignment result
-                OpCode::GetGlobal(1),    // x (for print)
+                OpCode::GetGlobal(0),    // x (for print)
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 2, 2, 2, 2, 2, 3, 3, 3, 3],
-            constants: vec![Value::Number(10f64), value_x, Value::Number(2f64)],
-            count: 11, // ← Fixed
-        };
+            vec![1, 1, 2, 2, 2, 2, 2, 3, 3, 3, 3],
+            vec![Value::Number(10f64), Value::Number(2f64)],
+        );
 
         let source = r#"var x = 10;
                     x += 2;
@@ -1232,18 +1735,17 @@ mod tests {
     #[test]
     fn test_statement() {
         let obj_string = ObjString::from_string("test".to_owned());
-        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        let expected_chunk = Chunk {
-            code: vec![
+        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_string)))));
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 1, 1],
-            constants: vec![value],
-            count: 4,
-        };
+            vec![1, 1, 1, 1],
+            vec![value],
+        );
         let source = "print \"test\";".to_owned();
         let mut compiler = Compiler::new(None, FunctionType::TypeScript);
         compiler.compile(source);
@@ -1256,22 +1758,19 @@ mod tests {
     #[test]
     fn test_print_variable() {
         let obj_string = ObjString::from_string("test".to_owned());
-        let value1 = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        let obj_string = ObjString::from_string("x".to_owned());
-        let value2 = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        let expected_chunk = Chunk {
-            code: vec![
+        let value1 = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_string)))));
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
-                OpCode::DefineGlobal(1),
-                OpCode::GetGlobal(1),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 1, 1, 1, 1],
-            constants: vec![value1, value2],
-            count: 6,
-        };
+            vec![1, 1, 1, 1, 1, 1],
+            vec![value1],
+        );
 
         let source = "var x = \"test\"; print x;".to_owned();
         let mut compiler = Compiler::new(None, FunctionType::TypeScript);
@@ -1285,46 +1784,42 @@ mod tests {
 
     #[test]
     fn test_function() {
-        let expected_inner_chunk = Chunk {
-            code: vec![
+        let expected_inner_chunk = chunk(
+            vec![
                 OpCode::Constant(0), // "2"
                 OpCode::GetLocal(1), // x
                 OpCode::Add,
                 OpCode::GetLocal(2), // y
                 OpCode::Return,
             ],
-            lines: vec![2, 2, 2, 3, 3],
-            constants: vec![Value::Number(2f64)],
-            count: 5,
-        };
+            vec![2, 2, 2, 3, 3],
+            vec![Value::Number(2f64)],
+        );
         let mut internal_fn = ObjFunction::new();
         internal_fn.name = "test".to_string();
         internal_fn.arity = 1;
         internal_fn.chunk = expected_inner_chunk.clone();
 
-        let obj_string = ObjString::from_string("test".to_owned());
-        let value_str = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        let value_function = Value::Obj(Rc::new(RefCell::new(Obj::Function(internal_fn))));
+        let value_function = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(internal_fn)))));
 
-        let mut expected_chunk = Chunk {
-            code: vec![
+        let mut expected_chunk = chunk(
+            vec![
                 // fun test(x) { ... }
                 OpCode::Closure(0),
-                OpCode::DefineGlobal(1),
+                OpCode::DefineGlobal(0),
                 // print test(10);
-                OpCode::GetGlobal(1),
-                OpCode::Constant(2),
+                OpCode::GetGlobal(0),
+                OpCode::Constant(1),
                 OpCode::Call(1),
                 OpCode::Print,
                 // implicit script return
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![4, 4, 5, 5, 5, 5, 5, 5],
-            constants: vec![],
-            count: 8,
-        };
-        expected_chunk.constants = vec![value_function, value_str, Value::Number(10f64)];
+            vec![4, 4, 5, 5, 5, 5, 5, 5],
+            vec![],
+        );
+        expected_chunk.constants = vec![value_function, Value::Number(10f64)];
 
         let source = r#"fun test(x) {
                                 var y = 2 + x;
@@ -1349,15 +1844,13 @@ mod tests {
 
     #[test]
     fn test_block() {
-        let obj_string = ObjString::from_string("x".to_owned());
-        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
-                OpCode::DefineGlobal(1),
-                OpCode::Constant(2),
+                OpCode::DefineGlobal(0),
+                OpCode::Constant(1),
                 OpCode::GetLocal(1),
-                OpCode::GetGlobal(1),
+                OpCode::GetGlobal(0),
                 OpCode::Add,
                 OpCode::SetLocal(1),
                 OpCode::Pop,
@@ -1367,13 +1860,12 @@ mod tests {
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![2, 2, 4, 5, 5, 5, 5, 5, 6, 6, 7, 8, 8],
-            constants: vec![Value::Number(10f64), value, Value::Number(2f64)],
-            count: 13,
-        };
+            vec![2, 2, 4, 5, 5, 5, 5, 5, 6, 6, 7, 8, 8],
+            vec![Value::Number(10f64), Value::Number(2f64)],
+        );
 
         let source = r#"
-                            var x = 10
+                            var x = 10;
                             {
                                 var y = 2;
                                 y = y + x;
@@ -1393,19 +1885,18 @@ mod tests {
     #[test]
     fn test_class() {
         let obj_string = ObjString::from_string("test".to_owned());
-        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        let expected_init_chunk = Chunk {
-            count: 5,
-            code: vec![
+        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_string)))));
+        let expected_init_chunk = chunk(
+            vec![
                 OpCode::GetLocal(1),
                 OpCode::SetProperty(0),
                 OpCode::Pop,
                 OpCode::GetLocal(0),
                 OpCode::Return,
             ],
-            lines: vec![3, 3, 3, 4, 4],
-            constants: vec![value.clone()],
-        };
+            vec![3, 3, 3, 4, 4],
+            vec![value.clone()],
+        );
 
         let mut expected_init_fn = ObjFunction::new();
         expected_init_fn.name = "init".to_owned();
@@ -1413,9 +1904,8 @@ mod tests {
         expected_init_fn.upvalue_count = 0;
         expected_init_fn.chunk = expected_init_chunk;
 
-        let expected_do_staff_chunk = Chunk {
-            count: 6,
-            code: vec![
+        let expected_do_staff_chunk = chunk(
+            vec![
                 OpCode::GetProperty(0),
                 OpCode::GetLocal(1),
                 OpCode::Add,
@@ -1423,9 +1913,9 @@ mod tests {
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![6, 6, 6, 6, 7, 7],
-            constants: vec![value],
-        };
+            vec![6, 6, 6, 6, 7, 7],
+            vec![value],
+        );
 
         let mut expected_do_staff_fn = ObjFunction::new();
         expected_do_staff_fn.name = "doStaff".to_owned();
@@ -1434,22 +1924,23 @@ mod tests {
         expected_do_staff_fn.chunk = expected_do_staff_chunk;
 
         let obj_string_class = ObjString::from_string("TestClass".to_owned());
-        let value_class = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_class))));
+        let value_class = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_class,
+        )))));
         let obj_init = ObjString::from_string("init".to_owned());
-        let value_init = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_init))));
+        let value_init = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_init)))));
         let obj_method = ObjString::from_string("doStaff".to_owned());
-        let value_method = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_method))));
-        let obj_obj = ObjString::from_string("obj".to_owned());
-        let value_obj = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_obj))));
-
-        let value_function_init =
-            Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_init_fn))));
-        let value_function_do_staff =
-            Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_do_staff_fn))));
-
-        let expected_chunk = Chunk {
-            count: 18,
-            code: vec![
+        let value_method = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_method)))));
+
+        let value_function_init = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_init_fn,
+        )))));
+        let value_function_do_staff = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_do_staff_fn,
+        )))));
+
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Class(0),
                 OpCode::DefineGlobal(0),
                 OpCode::GetGlobal(0),
@@ -1461,26 +1952,25 @@ mod tests {
                 OpCode::GetGlobal(0),
                 OpCode::Constant(5),
                 OpCode::Call(1),
-                OpCode::DefineGlobal(6),
-                OpCode::GetGlobal(6),
-                OpCode::Constant(7),
+                OpCode::DefineGlobal(1),
+                OpCode::GetGlobal(1),
+                OpCode::Constant(6),
                 OpCode::Invoke(3, 1),
                 OpCode::Pop,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 1, 4, 4, 7, 7, 8, 9, 9, 9, 9, 10, 10, 10, 10, 10, 10],
-            constants: vec![
+            vec![1, 1, 1, 4, 4, 7, 7, 8, 9, 9, 9, 9, 10, 10, 10, 10, 10, 10],
+            vec![
                 value_class,
                 value_init,
                 value_function_init,
                 value_method,
                 value_function_do_staff,
                 Value::Number(2f64),
-                value_obj,
                 Value::Number(4f64),
             ],
-        };
+        );
 
         let source = r#"class TestClass {
                                 init(x) {
@@ -1504,21 +1994,20 @@ mod tests {
     #[test]
     fn test_class2() {
         let obj_string = ObjString::from_string("test".to_owned());
-        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
+        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_string)))));
 
         // init(x)
-        let expected_init_chunk = Chunk {
-            count: 5,
-            code: vec![
+        let expected_init_chunk = chunk(
+            vec![
                 OpCode::GetLocal(1),
                 OpCode::SetProperty(0),
                 OpCode::Pop,
                 OpCode::GetLocal(0),
                 OpCode::Return,
             ],
-            lines: vec![3, 3, 3, 4, 4],
-            constants: vec![value.clone()],
-        };
+            vec![3, 3, 3, 4, 4],
+            vec![value.clone()],
+        );
 
         let mut expected_init_fn = ObjFunction::new();
         expected_init_fn.name = "init".to_owned();
@@ -1527,17 +2016,16 @@ mod tests {
         expected_init_fn.chunk = expected_init_chunk;
 
         // doStaff(y) { return this.test + y; }
-        let expected_do_staff_chunk = Chunk {
-            count: 4,
-            code: vec![
+        let expected_do_staff_chunk = chunk(
+            vec![
                 OpCode::GetProperty(0),
                 OpCode::GetLocal(1),
                 OpCode::Add,
                 OpCode::Return,
             ],
-            lines: vec![6, 6, 6, 6],
-            constants: vec![value],
-        };
+            vec![6, 6, 6, 6],
+            vec![value],
+        );
 
         let mut expected_do_staff_fn = ObjFunction::new();
         expected_do_staff_fn.name = "doStaff".to_owned();
@@ -1546,23 +2034,23 @@ mod tests {
         expected_do_staff_fn.chunk = expected_do_staff_chunk;
 
         let obj_string_class = ObjString::from_string("TestClass".to_owned());
-        let value_class = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_class))));
+        let value_class = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_class,
+        )))));
         let obj_init = ObjString::from_string("init".to_owned());
-        let value_init = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_init))));
+        let value_init = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_init)))));
         let obj_method = ObjString::from_string("doStaff".to_owned());
-        let value_method = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_method))));
-        let obj_obj = ObjString::from_string("obj".to_owned());
-        let value_obj = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_obj))));
-        let obj_sum = ObjString::from_string("sum".to_owned());
-        let value_sum = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_sum))));
-
-        let value_function_init =
-            Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_init_fn))));
-        let value_function_do_staff =
-            Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_do_staff_fn))));
-
-        let expected_chunk = Chunk {
-            code: vec![
+        let value_method = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_method)))));
+
+        let value_function_init = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_init_fn,
+        )))));
+        let value_function_do_staff = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_do_staff_fn,
+        )))));
+
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Class(0),
                 OpCode::DefineGlobal(0),
                 OpCode::GetGlobal(0),
@@ -1574,47 +2062,44 @@ mod tests {
                 OpCode::GetGlobal(0),
                 OpCode::Constant(5),
                 OpCode::Call(1),
-                OpCode::DefineGlobal(6),
+                OpCode::DefineGlobal(1),
+                OpCode::Constant(6),
+                OpCode::DefineGlobal(2),
+                OpCode::GetGlobal(2),
                 OpCode::Constant(7),
-                OpCode::DefineGlobal(8),
-                OpCode::GetGlobal(8),
-                OpCode::Constant(9),
                 OpCode::Less,
-                OpCode::JumpIfFalse(9),
+                OpCode::JumpIfFalse(19),
                 OpCode::Pop,
-                OpCode::GetGlobal(8),
-                OpCode::GetGlobal(6),
-                OpCode::Constant(10),
+                OpCode::GetGlobal(2),
+                OpCode::GetGlobal(1),
+                OpCode::Constant(8),
                 OpCode::Invoke(3, 1),
                 OpCode::Add,
-                OpCode::SetGlobal(8),
+                OpCode::SetGlobal(2),
                 OpCode::Pop,
-                OpCode::Loop(13),
+                OpCode::Loop(27),
                 OpCode::Pop,
-                OpCode::GetGlobal(8),
+                OpCode::GetGlobal(2),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![
+            vec![
                 1, 1, 1, 4, 4, 7, 7, 8, 9, 9, 9, 9, 10, 10, 11, 11, 11, 11, 11, 12, 12, 12, 12, 12,
                 12, 12, 13, 13, 14, 14, 14, 14,
             ],
-            constants: vec![
+            vec![
                 value_class,             // 0: "TestClass"
                 value_init,              // 1: "init"
                 value_function_init,     // 2: init fn
                 value_method,            // 3: "doStaff"
                 value_function_do_staff, // 4: doStaff fn
                 Value::Number(2f64),     // 5: 2
-                value_obj,               // 6: "obj"
-                Value::Number(0f64),     // 7: 0
-                value_sum,               // 8: "sum"
-                Value::Number(1000f64),  // 9: 1000
-                Value::Number(4f64),     // 10: 4
+                Value::Number(0f64),     // 6: 0
+                Value::Number(1000f64),  // 7: 1000
+                Value::Number(4f64),     // 8: 4
             ],
-            count: 32,
-        };
+        );
 
         let source = r#"class TestClass {
                                 init(x) {
@@ -1643,13 +2128,9 @@ mod tests {
 
     #[test]
     fn test_class3() {
-        let obj_string = ObjString::from_string("test".to_owned());
-        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-
         // init() { this.test1=1; this.test2=2; this.test3=2; }
-        let expected_init_chunk = Chunk {
-            count: 11,
-            code: vec![
+        let expected_init_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
                 OpCode::SetProperty(1),
                 OpCode::Pop,
@@ -1662,21 +2143,21 @@ mod tests {
                 OpCode::GetLocal(0),
                 OpCode::Return,
             ],
-            lines: vec![3, 3, 3, 3, 3, 3, 3, 3, 3, 4, 4],
-            constants: vec![
+            vec![3, 3, 3, 3, 3, 3, 3, 3, 3, 4, 4],
+            vec![
                 Value::Number(1f64),
-                Value::Obj(Rc::new(RefCell::new(Obj::String(ObjString::from_string(
-                    "test1".to_owned(),
+                Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+                    ObjString::from_string("test1".to_owned()),
                 ))))),
                 Value::Number(2f64),
-                Value::Obj(Rc::new(RefCell::new(Obj::String(ObjString::from_string(
-                    "test2".to_owned(),
+                Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+                    ObjString::from_string("test2".to_owned()),
                 ))))),
-                Value::Obj(Rc::new(RefCell::new(Obj::String(ObjString::from_string(
-                    "test3".to_owned(),
+                Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+                    ObjString::from_string("test3".to_owned()),
                 ))))),
             ],
-        };
+        );
 
         let mut expected_init_fn = ObjFunction::new();
         expected_init_fn.name = "init".to_owned();
@@ -1688,14 +2169,13 @@ mod tests {
             let mut f = ObjFunction::new();
             f.name = name.to_owned();
             f.arity = 0;
-            f.chunk = Chunk {
-                count: 2,
-                code: vec![OpCode::GetProperty(0), OpCode::Return],
-                lines: vec![0, 0],
-                constants: vec![Value::Obj(Rc::new(RefCell::new(Obj::String(
+            f.chunk = chunk(
+                vec![OpCode::GetProperty(0), OpCode::Return],
+                vec![0, 0],
+                vec![Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
                     ObjString::from_string(prop.to_owned()),
-                ))))],
-            };
+                )))))],
+            );
             f
         };
 
@@ -1704,33 +2184,37 @@ mod tests {
         let expected_staff3_fn = make_staff("staff3", "test3");
 
         let obj_string_class = ObjString::from_string("TestClass".to_owned());
-        let value_class = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_class))));
-        let value_init_name = Value::Obj(Rc::new(RefCell::new(Obj::String(
+        let value_class = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_class,
+        )))));
+        let value_init_name = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
             ObjString::from_string("init".to_owned()),
-        ))));
-        let value_staff1_name = Value::Obj(Rc::new(RefCell::new(Obj::String(
+        )))));
+        let value_staff1_name = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
             ObjString::from_string("staff1".to_owned()),
-        ))));
-        let value_staff2_name = Value::Obj(Rc::new(RefCell::new(Obj::String(
+        )))));
+        let value_staff2_name = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
             ObjString::from_string("staff2".to_owned()),
-        ))));
-        let value_staff3_name = Value::Obj(Rc::new(RefCell::new(Obj::String(
+        )))));
+        let value_staff3_name = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
             ObjString::from_string("staff3".to_owned()),
-        ))));
-        let value_obj_name = Value::Obj(Rc::new(RefCell::new(Obj::String(
-            ObjString::from_string("obj".to_owned()),
-        ))));
-        let value_sum_name = Value::Obj(Rc::new(RefCell::new(Obj::String(
-            ObjString::from_string("sum".to_owned()),
-        ))));
-
-        let value_init = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_init_fn))));
-        let value_staff1 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_staff1_fn))));
-        let value_staff2 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_staff2_fn))));
-        let value_staff3 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_staff3_fn))));
-
-        let expected_chunk = Chunk {
-            code: vec![
+        )))));
+
+        let value_init = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_init_fn,
+        )))));
+        let value_staff1 = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_staff1_fn,
+        )))));
+        let value_staff2 = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_staff2_fn,
+        )))));
+        let value_staff3 = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_staff3_fn,
+        )))));
+
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Class(0),
                 OpCode::DefineGlobal(0),
                 OpCode::GetGlobal(0),
@@ -1745,38 +2229,38 @@ mod tests {
                 OpCode::Pop,
                 OpCode::GetGlobal(0),
                 OpCode::Call(0),
-                OpCode::DefineGlobal(9),
+                OpCode::DefineGlobal(1),
+                OpCode::Constant(9),
+                OpCode::DefineGlobal(2),
+                OpCode::GetGlobal(2),
                 OpCode::Constant(10),
-                OpCode::DefineGlobal(11),
-                OpCode::GetGlobal(11),
-                OpCode::Constant(12),
                 OpCode::Less,
-                OpCode::JumpIfFalse(14),
+                OpCode::JumpIfFalse(33),
                 OpCode::Pop,
-                OpCode::GetGlobal(11),
-                OpCode::GetGlobal(9),
+                OpCode::GetGlobal(2),
+                OpCode::GetGlobal(1),
                 OpCode::Invoke(3, 0), // staff1
                 OpCode::Add,
-                OpCode::GetGlobal(9),
+                OpCode::GetGlobal(1),
                 OpCode::Invoke(5, 0), // staff2
                 OpCode::Add,
-                OpCode::GetGlobal(9),
+                OpCode::GetGlobal(1),
                 OpCode::Invoke(7, 0), // staff3
                 OpCode::Add,
-                OpCode::SetGlobal(11),
+                OpCode::SetGlobal(2),
                 OpCode::Pop,
-                OpCode::Loop(18),
+                OpCode::Loop(41),
                 OpCode::Pop,
-                OpCode::GetGlobal(11),
+                OpCode::GetGlobal(2),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![
+            vec![
                 1, 1, 1, 6, 6, 7, 7, 8, 8, 9, 9, 10, 11, 11, 11, 12, 12, 13, 13, 13, 13, 13, 14,
                 14, 14, 14, 14, 14, 14, 14, 14, 14, 14, 14, 15, 15, 16, 16, 16, 16,
             ],
-            constants: vec![
+            vec![
                 value_class,
                 value_init_name,
                 value_init,
@@ -1786,13 +2270,10 @@ mod tests {
                 value_staff2,
                 value_staff3_name,
                 value_staff3,
-                value_obj_name,
                 Value::Number(0f64),
-                value_sum_name,
                 Value::Number(1000f64),
             ],
-            count: 37,
-        };
+        );
 
         let source = r#"class TestClass {
                             init() {
@@ -1824,9 +2305,8 @@ mod tests {
     #[test]
     fn test_class4() {
         // init() { this.test1=1; this.test2=2; this.test3=2; }
-        let expected_init_chunk = Chunk {
-            count: 11,
-            code: vec![
+        let expected_init_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
                 OpCode::SetProperty(1),
                 OpCode::Pop,
@@ -1839,21 +2319,21 @@ mod tests {
                 OpCode::GetLocal(0),
                 OpCode::Return,
             ],
-            lines: vec![3, 3, 3, 3, 3, 3, 3, 3, 3, 4, 4],
-            constants: vec![
+            vec![3, 3, 3, 3, 3, 3, 3, 3, 3, 4, 4],
+            vec![
                 Value::Number(1f64),
-                Value::Obj(Rc::new(RefCell::new(Obj::String(ObjString::from_string(
-                    "test1".to_owned(),
+                Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+                    ObjString::from_string("test1".to_owned()),
                 ))))),
                 Value::Number(2f64),
-                Value::Obj(Rc::new(RefCell::new(Obj::String(ObjString::from_string(
-                    "test2".to_owned(),
+                Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+                    ObjString::from_string("test2".to_owned()),
                 ))))),
-                Value::Obj(Rc::new(RefCell::new(Obj::String(ObjString::from_string(
-                    "test3".to_owned(),
+                Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+                    ObjString::from_string("test3".to_owned()),
                 ))))),
             ],
-        };
+        );
 
         let mut expected_init_fn = ObjFunction::new();
         expected_init_fn.name = "init".to_owned();
@@ -1861,16 +2341,20 @@ mod tests {
         expected_init_fn.chunk = expected_init_chunk;
 
         let obj_string_class = ObjString::from_string("TestClass".to_owned());
-        let value_class = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_class))));
+        let value_class = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_class,
+        )))));
 
-        let value_init_name = Value::Obj(Rc::new(RefCell::new(Obj::String(
+        let value_init_name = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
             ObjString::from_string("init".to_owned()),
-        ))));
+        )))));
 
-        let value_init = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_init_fn))));
+        let value_init = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_init_fn,
+        )))));
 
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Class(0),
                 OpCode::DefineGlobal(0),
                 OpCode::GetGlobal(0),
@@ -1880,10 +2364,9 @@ mod tests {
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 1, 6, 6, 6, 6, 6],
-            constants: vec![value_class, value_init_name, value_init],
-            count: 8,
-        };
+            vec![1, 1, 1, 6, 6, 6, 6, 6],
+            vec![value_class, value_init_name, value_init],
+        );
 
         let source = r#"class TestClass {
                         init() {
@@ -1904,38 +2387,34 @@ mod tests {
 
     #[test]
     fn test_while() {
-        let obj_string = ObjString::from_string("i".to_owned());
-        let value = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string))));
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
-                OpCode::DefineGlobal(1),
-                OpCode::GetGlobal(1),
-                OpCode::Constant(2),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
+                OpCode::Constant(1),
                 OpCode::Less,
-                OpCode::JumpIfFalse(9),
+                OpCode::JumpIfFalse(15),
                 OpCode::Pop,
-                OpCode::GetGlobal(1),
+                OpCode::GetGlobal(0),
                 OpCode::Print,
-                OpCode::GetGlobal(1),
-                OpCode::Constant(3),
+                OpCode::GetGlobal(0),
+                OpCode::Constant(2),
                 OpCode::Add,
-                OpCode::SetGlobal(1),
+                OpCode::SetGlobal(0),
                 OpCode::Pop,
-                OpCode::Loop(13),
+                OpCode::Loop(23),
                 OpCode::Pop,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 2, 2, 2, 2, 2, 3, 3, 4, 4, 4, 4, 4, 5, 5, 5, 5],
-            constants: vec![
+            vec![1, 1, 2, 2, 2, 2, 2, 3, 3, 4, 4, 4, 4, 4, 5, 5, 5, 5],
+            vec![
                 Value::Number(0f64),
-                value,
                 Value::Number(5f64),
                 Value::Number(1f64),
             ],
-            count: 18,
-        };
+        );
 
         let source = r#"var i = 0;
                             while (i < 5) {
@@ -1954,13 +2433,13 @@ mod tests {
 
     #[test]
     fn test_while_block() {
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
                 OpCode::GetLocal(1),
                 OpCode::Constant(1),
                 OpCode::Less,
-                OpCode::JumpIfFalse(9),
+                OpCode::JumpIfFalse(15),
                 OpCode::Pop,
                 OpCode::GetLocal(1),
                 OpCode::Print,
@@ -1969,20 +2448,19 @@ mod tests {
                 OpCode::Add,
                 OpCode::SetLocal(1),
                 OpCode::Pop,
-                OpCode::Loop(13),
+                OpCode::Loop(23),
                 OpCode::Pop,
                 OpCode::Pop,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![2, 3, 3, 3, 3, 3, 4, 4, 5, 5, 5, 5, 5, 6, 6, 7, 7, 7],
-            constants: vec![
+            vec![2, 3, 3, 3, 3, 3, 4, 4, 5, 5, 5, 5, 5, 6, 6, 7, 7, 7],
+            vec![
                 Value::Number(0f64),
                 Value::Number(5f64),
                 Value::Number(1f64),
             ],
-            count: 18,
-        };
+        );
 
         let source = r#"{
                                 var i = 0;
@@ -2003,47 +2481,43 @@ mod tests {
 
     #[test]
     fn test_if() {
-        let obj_string_a = ObjString::from_string("a".to_owned());
-        let value_a = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_a))));
-        let obj_string_b = ObjString::from_string("b".to_owned());
-        let value_b = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_b))));
         let obj_string_a_less_b = ObjString::from_string("a is less than b".to_owned());
-        let value_a_less_b = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_a_less_b))));
+        let value_a_less_b = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_a_less_b,
+        )))));
         let obj_string_a_greater_b = ObjString::from_string("a is greater than b".to_owned());
-        let value_a_greater_b =
-            Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_a_greater_b))));
+        let value_a_greater_b = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_a_greater_b,
+        )))));
 
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
+                OpCode::DefineGlobal(0),
+                OpCode::Constant(1),
                 OpCode::DefineGlobal(1),
-                OpCode::Constant(2),
-                OpCode::DefineGlobal(3),
+                OpCode::GetGlobal(0),
                 OpCode::GetGlobal(1),
-                OpCode::GetGlobal(3),
                 OpCode::Less,
-                OpCode::JumpIfFalse(4),
+                OpCode::JumpIfFalse(7),
                 OpCode::Pop,
-                OpCode::Constant(4),
+                OpCode::Constant(2),
                 OpCode::Print,
-                OpCode::Jump(3),
+                OpCode::Jump(4),
                 OpCode::Pop,
-                OpCode::Constant(5),
+                OpCode::Constant(3),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 2, 2, 3, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7],
-            constants: vec![
+            vec![1, 1, 2, 2, 3, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7],
+            vec![
                 Value::Number(2f64),
-                value_a,
                 Value::Number(3f64),
-                value_b,
                 value_a_less_b,
                 value_a_greater_b,
             ],
-            count: 17,
-        };
+        );
 
         let source = r#"var a = 2;
                             var b = 3;
@@ -2064,28 +2538,27 @@ mod tests {
 
     #[test]
     fn test_closure1() {
-        let expected_inner_fun2_chunk = Chunk {
-            count: 4,
-            code: vec![
+        let expected_inner_fun2_chunk = chunk(
+            vec![
                 OpCode::GetUpvalue(0),
                 OpCode::Constant(0),
                 OpCode::Add,
                 OpCode::Return,
             ],
-            lines: vec![4, 4, 4, 4],
-            constants: vec![Value::Number(2f64)],
-        };
+            vec![4, 4, 4, 4],
+            vec![Value::Number(2f64)],
+        );
 
         let mut expected_fun2 = ObjFunction::new();
         expected_fun2.name = "fun2".to_owned();
         expected_fun2.arity = 0;
         expected_fun2.upvalue_count = 1;
         expected_fun2.chunk = expected_inner_fun2_chunk;
-        let value_expected_fun2 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun2))));
+        let value_expected_fun2 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun2)))));
 
-        let expected_inner_fun1_chunk = Chunk {
-            count: 7,
-            code: vec![
+        let expected_inner_fun1_chunk = chunk(
+            vec![
                 OpCode::GetLocal(1),
                 OpCode::Constant(0),
                 OpCode::Add,
@@ -2094,9 +2567,9 @@ mod tests {
                 OpCode::Data(2),
                 OpCode::Return,
             ],
-            lines: vec![2, 2, 2, 5, 5, 5, 5],
-            constants: vec![Value::Number(1f64), value_expected_fun2],
-        };
+            vec![2, 2, 2, 5, 5, 5, 5],
+            vec![Value::Number(1f64), value_expected_fun2],
+        );
 
         let mut expected_fun1 = ObjFunction::new();
         expected_fun1.name = "fun1".to_owned();
@@ -2104,37 +2577,26 @@ mod tests {
         expected_fun1.upvalue_count = 0;
         expected_fun1.chunk = expected_inner_fun1_chunk;
 
-        let value_expected_fun1 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun1))));
-
-        let obj_string_fun1 = ObjString::from_string("fun1".to_owned());
-        let value_string_fun1 = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_fun1))));
+        let value_expected_fun1 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun1)))));
 
-        let obj_string_c = ObjString::from_string("c".to_owned());
-        let value_string_c = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_c))));
-
-        let expected_chunk = Chunk {
-            count: 11,
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Closure(0),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
+                OpCode::Constant(1),
+                OpCode::Call(1),
                 OpCode::DefineGlobal(1),
                 OpCode::GetGlobal(1),
-                OpCode::Constant(2),
-                OpCode::Call(1),
-                OpCode::DefineGlobal(3),
-                OpCode::GetGlobal(3),
                 OpCode::Call(0),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![7, 7, 8, 8, 8, 8, 9, 9, 9, 9, 9],
-            constants: vec![
-                value_expected_fun1,
-                value_string_fun1,
-                Value::Number(10f64),
-                value_string_c,
-            ],
-        };
+            vec![7, 7, 8, 8, 8, 8, 9, 9, 9, 9, 9],
+            vec![value_expected_fun1, Value::Number(10f64)],
+        );
 
         let source = r#"fun fun1(x) {
                                 var y = x + 1;
@@ -2157,9 +2619,8 @@ mod tests {
 
     #[test]
     fn test_closure2() {
-        let expected_inner_fun2_chunk = Chunk {
-            count: 7,
-            code: vec![
+        let expected_inner_fun2_chunk = chunk(
+            vec![
                 OpCode::GetUpvalue(0),
                 OpCode::GetUpvalue(1),
                 OpCode::Add,
@@ -2168,9 +2629,9 @@ mod tests {
                 OpCode::Add,
                 OpCode::Return,
             ],
-            lines: vec![5, 5, 5, 6, 6, 6, 6],
-            constants: vec![],
-        };
+            vec![5, 5, 5, 6, 6, 6, 6],
+            vec![],
+        );
 
         let mut expected_fun2 = ObjFunction::new();
         expected_fun2.name = "fun2".to_owned();
@@ -2178,11 +2639,11 @@ mod tests {
         expected_fun2.upvalue_count = 3;
         expected_fun2.chunk = expected_inner_fun2_chunk;
 
-        let value_expected_fun2 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun2))));
+        let value_expected_fun2 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun2)))));
 
-        let expected_inner_fun1_chunk = Chunk {
-            count: 11,
-            code: vec![
+        let expected_inner_fun1_chunk = chunk(
+            vec![
                 OpCode::GetLocal(1),
                 OpCode::Constant(0),
                 OpCode::Add,
@@ -2195,13 +2656,13 @@ mod tests {
                 OpCode::Data(1),
                 OpCode::Return,
             ],
-            lines: vec![2, 2, 2, 7, 7, 7, 7, 7, 7, 7, 8],
-            constants: vec![
+            vec![2, 2, 2, 7, 7, 7, 7, 7, 7, 7, 8],
+            vec![
                 Value::Number(1f64),
                 Value::Number(10f64),
                 value_expected_fun2,
             ],
-        };
+        );
 
         let mut expected_fun1 = ObjFunction::new();
         expected_fun1.name = "fun1".to_owned();
@@ -2209,37 +2670,26 @@ mod tests {
         expected_fun1.upvalue_count = 0;
         expected_fun1.chunk = expected_inner_fun1_chunk;
 
-        let value_expected_fun1 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun1))));
-
-        let obj_string_fun1 = ObjString::from_string("fun1".to_owned());
-        let value_string_fun1 = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_fun1))));
+        let value_expected_fun1 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun1)))));
 
-        let obj_string_c = ObjString::from_string("c".to_owned());
-        let value_string_c = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_c))));
-
-        let expected_chunk = Chunk {
-            count: 11,
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Closure(0),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
+                OpCode::Constant(1),
+                OpCode::Call(1),
                 OpCode::DefineGlobal(1),
                 OpCode::GetGlobal(1),
-                OpCode::Constant(2),
-                OpCode::Call(1),
-                OpCode::DefineGlobal(3),
-                OpCode::GetGlobal(3),
                 OpCode::Call(0),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![9, 9, 10, 10, 10, 10, 11, 11, 11, 11, 11],
-            constants: vec![
-                value_expected_fun1,
-                value_string_fun1,
-                Value::Number(10f64),
-                value_string_c,
-            ],
-        };
+            vec![9, 9, 10, 10, 10, 10, 11, 11, 11, 11, 11],
+            vec![value_expected_fun1, Value::Number(10f64)],
+        );
 
         let source = r#"fun fun1(x) {
                                 var y = x + 1;
@@ -2264,17 +2714,16 @@ mod tests {
 
     #[test]
     fn test_closure3() {
-        let expected_inner_fun2_chunk = Chunk {
-            count: 4,
-            code: vec![
+        let expected_inner_fun2_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
                 OpCode::Constant(1),
                 OpCode::Add,
                 OpCode::Return,
             ],
-            lines: vec![3, 3, 3, 3],
-            constants: vec![Value::Number(2f64), Value::Number(2f64)],
-        };
+            vec![3, 3, 3, 3],
+            vec![Value::Number(2f64), Value::Number(2f64)],
+        );
 
         let mut expected_fun2 = ObjFunction::new();
         expected_fun2.name = "fun2".to_owned();
@@ -2282,14 +2731,14 @@ mod tests {
         expected_fun2.upvalue_count = 0;
         expected_fun2.chunk = expected_inner_fun2_chunk;
 
-        let value_expected_fun2 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun2))));
+        let value_expected_fun2 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun2)))));
 
-        let expected_inner_fun1_chunk = Chunk {
-            count: 3,
-            code: vec![OpCode::Closure(0), OpCode::GetLocal(1), OpCode::Return],
-            lines: vec![4, 5, 5],
-            constants: vec![value_expected_fun2],
-        };
+        let expected_inner_fun1_chunk = chunk(
+            vec![OpCode::Closure(0), OpCode::GetLocal(1), OpCode::Return],
+            vec![4, 5, 5],
+            vec![value_expected_fun2],
+        );
 
         let mut expected_fun1 = ObjFunction::new();
         expected_fun1.name = "fun1".to_owned();
@@ -2297,31 +2746,25 @@ mod tests {
         expected_fun1.upvalue_count = 0;
         expected_fun1.chunk = expected_inner_fun1_chunk;
 
-        let value_expected_fun1 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun1))));
-
-        let obj_string_fun1 = ObjString::from_string("fun1".to_owned());
-        let value_string_fun1 = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_fun1))));
+        let value_expected_fun1 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun1)))));
 
-        let obj_string_c = ObjString::from_string("c".to_owned());
-        let value_string_c = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_c))));
-
-        let expected_chunk = Chunk {
-            count: 10,
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Closure(0),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
+                OpCode::Call(0),
                 OpCode::DefineGlobal(1),
                 OpCode::GetGlobal(1),
                 OpCode::Call(0),
-                OpCode::DefineGlobal(2),
-                OpCode::GetGlobal(2),
-                OpCode::Call(0),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![6, 6, 7, 7, 7, 8, 8, 8, 8, 8],
-            constants: vec![value_expected_fun1, value_string_fun1, value_string_c],
-        };
+            vec![6, 6, 7, 7, 7, 8, 8, 8, 8, 8],
+            vec![value_expected_fun1],
+        );
 
         let source = r#"fun fun1() {
                                 fun fun2() {
@@ -2345,31 +2788,30 @@ mod tests {
     fn test_closure4() {
         // inner-most: fun fun3() { return x + 5; }
         let obj_string_x = ObjString::from_string("x".to_owned());
-        let value_string_x = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_x))));
+        let value_string_x = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_string_x)))));
 
-        let expected_fun3_chunk = Chunk {
-            count: 4,
-            code: vec![
+        let expected_fun3_chunk = chunk(
+            vec![
                 OpCode::GetGlobal(0),
                 OpCode::Constant(1),
                 OpCode::Add,
                 OpCode::Return,
             ],
-            lines: vec![5, 5, 5, 5],
-            constants: vec![value_string_x, Value::Number(5f64)],
-        };
+            vec![5, 5, 5, 5],
+            vec![value_string_x, Value::Number(5f64)],
+        );
         let mut expected_fun3 = ObjFunction::new();
         expected_fun3.name = "fun3".to_owned();
         expected_fun3.arity = 0;
         expected_fun3.upvalue_count = 0;
         expected_fun3.chunk = expected_fun3_chunk;
 
-        let value_expected_fun3 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun3))));
+        let value_expected_fun3 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun3)))));
 
         // middle: fun fun2() { fun fun3() { ... } return fun3 }
-        let expected_fun2_chunk = Chunk {
-            count: 7,
-            code: vec![
+        let expected_fun2_chunk = chunk(
+            vec![
                 OpCode::Closure(0),
                 OpCode::Data(1),
                 OpCode::Data(1),
@@ -2378,9 +2820,9 @@ mod tests {
                 OpCode::GetLocal(1),
                 OpCode::Return,
             ],
-            lines: vec![6, 7, 7, 7, 7, 7, 7],
-            constants: vec![value_expected_fun3],
-        };
+            vec![6, 7, 7, 7, 7, 7, 7],
+            vec![value_expected_fun3],
+        );
         let mut expected_fun2 = ObjFunction::new();
         expected_fun2.name = "fun2".to_owned();
         expected_fun2.arity = 0;
@@ -2388,14 +2830,14 @@ mod tests {
         expected_fun2.chunk = expected_fun2_chunk;
 
         let obj_string_x = ObjString::from_string("x".to_owned());
-        let value_string_x = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_x))));
+        let value_string_x = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_string_x)))));
 
-        let value_expected_fun2 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun2))));
+        let value_expected_fun2 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun2)))));
 
         // outer: fun fun1() { let x = 10; ... return fun2; }
-        let expected_fun1_chunk = Chunk {
-            count: 14,
-            code: vec![
+        let expected_fun1_chunk = chunk(
+            vec![
                 OpCode::GetGlobal(0),
                 OpCode::Pop,
                 OpCode::Constant(1),
@@ -2411,41 +2853,36 @@ mod tests {
                 OpCode::GetLocal(1),
                 OpCode::Return,
             ],
-            lines: vec![2, 2, 2, 2, 2, 8, 8, 8, 8, 8, 8, 8, 9, 9],
-            constants: vec![value_string_x, Value::Number(10f64), value_expected_fun2],
-        };
+            vec![2, 2, 2, 2, 2, 8, 8, 8, 8, 8, 8, 8, 9, 9],
+            vec![value_string_x, Value::Number(10f64), value_expected_fun2],
+        );
         let mut expected_fun1 = ObjFunction::new();
         expected_fun1.name = "fun1".to_owned();
         expected_fun1.arity = 0;
         expected_fun1.upvalue_count = 0;
         expected_fun1.chunk = expected_fun1_chunk;
 
-        let obj_string_fun1 = ObjString::from_string("fun1".to_owned());
-        let value_string_fun1 = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_fun1))));
-        let obj_string_c = ObjString::from_string("c".to_owned());
-        let value_string_c = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_c))));
-
-        let value_expected_fun1 = Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_fun1))));
+        let value_expected_fun1 =
+            Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(expected_fun1)))));
 
         // script chunk: var c = fun1(); print c()();
-        let expected_chunk = Chunk {
-            count: 11,
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Closure(0),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
+                OpCode::Call(0),
                 OpCode::DefineGlobal(1),
                 OpCode::GetGlobal(1),
                 OpCode::Call(0),
-                OpCode::DefineGlobal(2),
-                OpCode::GetGlobal(2),
-                OpCode::Call(0),
                 OpCode::Call(0),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![10, 10, 11, 11, 11, 12, 12, 12, 12, 12, 12],
-            constants: vec![value_expected_fun1, value_string_fun1, value_string_c],
-        };
+            vec![10, 10, 11, 11, 11, 12, 12, 12, 12, 12, 12],
+            vec![value_expected_fun1],
+        );
         let source = r#"fun fun1() {
                                 var x = 10;
                                 fun fun2() {
@@ -2471,14 +2908,14 @@ mod tests {
     #[test]
     fn test_recursion() {
         let obj_string_test = ObjString::from_string("test".to_owned());
-        let value_string_test = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_test))));
-        let expected_inner_test_chunk = Chunk {
-            count: 17,
-            code: vec![
+        let value_string_test =
+            Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(obj_string_test)))));
+        let expected_inner_test_chunk = chunk(
+            vec![
                 OpCode::GetLocal(1),
                 OpCode::Constant(0),
                 OpCode::Greater,
-                OpCode::JumpIfFalse(10),
+                OpCode::JumpIfFalse(17),
                 OpCode::Pop,
                 OpCode::GetGlobal(1),
                 OpCode::GetLocal(1),
@@ -2493,14 +2930,14 @@ mod tests {
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6],
-            constants: vec![
+            vec![2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6],
+            vec![
                 Value::Number(0f64),
                 value_string_test,
                 Value::Number(1f64),
                 Value::Number(1f64),
             ],
-        };
+        );
 
         let mut expected_test_fn = ObjFunction::new();
         expected_test_fn.name = "test".to_owned();
@@ -2508,31 +2945,24 @@ mod tests {
         expected_test_fn.upvalue_count = 0;
         expected_test_fn.chunk = expected_inner_test_chunk;
 
-        let obj_string_test = ObjString::from_string("test".to_owned());
-        let value_string_test = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_test))));
-
-        let value_expected_test_fn =
-            Value::Obj(Rc::new(RefCell::new(Obj::Function(expected_test_fn))));
+        let value_expected_test_fn = Value::Obj(Rc::new(RefCell::new(Obj::Function(Rc::new(
+            expected_test_fn,
+        )))));
 
-        let expected_chunk = Chunk {
-            count: 8,
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Closure(0),
-                OpCode::DefineGlobal(1),
-                OpCode::GetGlobal(1),
-                OpCode::Constant(2),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
+                OpCode::Constant(1),
                 OpCode::Call(1),
                 OpCode::Pop,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![6, 6, 7, 7, 7, 7, 7, 7],
-            constants: vec![
-                value_expected_test_fn,
-                value_string_test,
-                Value::Number(4f64),
-            ],
-        };
+            vec![6, 6, 7, 7, 7, 7, 7, 7],
+            vec![value_expected_test_fn, Value::Number(4f64)],
+        );
 
         let source = r#"fun test(i) {
                             if (i > 0) {
@@ -2553,10 +2983,10 @@ mod tests {
 
     #[test]
     fn test_short_circuit_or_compiles_correct_jumps() {
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::True,
-                OpCode::JumpIfFalse(1),
+                OpCode::JumpIfFalse(3),
                 OpCode::Jump(2),
                 OpCode::Pop,
                 OpCode::False,
@@ -2564,10 +2994,9 @@ mod tests {
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 1, 1, 1, 1, 1, 1],
-            constants: vec![],
-            count: 8,
-        };
+            vec![1, 1, 1, 1, 1, 1, 1, 1],
+            vec![],
+        );
 
         let source = "true or false;".to_owned();
         let mut compiler = Compiler::new(None, FunctionType::TypeScript);
@@ -2581,8 +3010,8 @@ mod tests {
 
     #[test]
     fn test_short_circuit_and_compiles_correct_jumps() {
-        let expected_chunk = Chunk {
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::False,
                 OpCode::JumpIfFalse(2),
                 OpCode::Pop,
@@ -2591,10 +3020,9 @@ mod tests {
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![1, 1, 1, 1, 1, 1, 1],
-            constants: vec![],
-            count: 7,
-        };
+            vec![1, 1, 1, 1, 1, 1, 1],
+            vec![],
+        );
 
         let source = "false and true;".to_owned();
         let mut compiler = Compiler::new(None, FunctionType::TypeScript);
@@ -2610,19 +3038,19 @@ mod tests {
     fn test_inheritance() {
         // Superclass method: doStaff() { print "Inheritance works"; }
         let obj_string_inheritance = ObjString::from_string("Inheritance works".to_owned());
-        let value_string_inheritance =
-            Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_inheritance))));
-        let expected_super_do_staff_chunk = Chunk {
-            count: 4,
-            code: vec![
+        let value_string_inheritance = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_inheritance,
+        )))));
+        let expected_super_do_staff_chunk = chunk(
+            vec![
                 OpCode::Constant(0),
                 OpCode::Print,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![3, 3, 4, 4],
-            constants: vec![value_string_inheritance],
-        };
+            vec![3, 3, 4, 4],
+            vec![value_string_inheritance],
+        );
         let mut expected_super_do_staff_fn = ObjFunction::new();
         expected_super_do_staff_fn.name = "doStaff".to_owned();
         expected_super_do_staff_fn.arity = 0;
@@ -2631,20 +3059,20 @@ mod tests {
 
         // Subclass override: doStaff() { super.doStaff(); }
         let obj_string_do_staff = ObjString::from_string("doStaff".to_owned());
-        let value_string_do_staff =
-            Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_do_staff))));
+        let value_string_do_staff = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_do_staff,
+        )))));
 
-        let expected_sub_do_staff_chunk = Chunk {
-            count: 4,
-            code: vec![
+        let expected_sub_do_staff_chunk = chunk(
+            vec![
                 OpCode::Invoke(0, 0),
                 OpCode::Pop,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![8, 8, 9, 9],
-            constants: vec![value_string_do_staff],
-        };
+            vec![8, 8, 9, 9],
+            vec![value_string_do_staff],
+        );
         let mut expected_sub_do_staff_fn = ObjFunction::new();
         expected_sub_do_staff_fn.name = "doStaff".to_owned();
         expected_sub_do_staff_fn.arity = 0;
@@ -2653,30 +3081,29 @@ mod tests {
 
         // Script chunk
         let obj_string_super_class = ObjString::from_string("superClass".to_owned());
-        let value_string_super_class =
-            Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_super_class))));
+        let value_string_super_class = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_super_class,
+        )))));
 
         let obj_string_do_staff = ObjString::from_string("doStaff".to_owned());
-        let value_string_do_staff =
-            Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_do_staff))));
+        let value_string_do_staff = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_do_staff,
+        )))));
 
         let obj_string_sub_class = ObjString::from_string("subClass".to_owned());
-        let value_string_sub_class =
-            Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_sub_class))));
-
-        let obj_string_obj = ObjString::from_string("obj".to_owned());
-        let value_string_obj = Value::Obj(Rc::new(RefCell::new(Obj::String(obj_string_obj))));
+        let value_string_sub_class = Value::Obj(Rc::new(RefCell::new(Obj::String(Rc::new(
+            obj_string_sub_class,
+        )))));
 
         let value_expected_super_do_staff_fn = Value::Obj(Rc::new(RefCell::new(Obj::Function(
-            expected_super_do_staff_fn,
+            Rc::new(expected_super_do_staff_fn),
         ))));
         let value_expected_sub_do_staff_fn = Value::Obj(Rc::new(RefCell::new(Obj::Function(
-            expected_sub_do_staff_fn,
+            Rc::new(expected_sub_do_staff_fn),
         ))));
 
-        let expected_chunk = Chunk {
-            count: 25,
-            code: vec![
+        let expected_chunk = chunk(
+            vec![
                 OpCode::Class(0),
                 OpCode::DefineGlobal(0),
                 OpCode::GetGlobal(0),
@@ -2684,37 +3111,37 @@ mod tests {
                 OpCode::Method(1),
                 OpCode::Pop,
                 OpCode::Class(3),
-                OpCode::DefineGlobal(3),
+                OpCode::DefineGlobal(1),
                 OpCode::GetGlobal(0),
-                OpCode::GetGlobal(3),
+                OpCode::GetGlobal(1),
                 OpCode::Inherit,
                 OpCode::Closure(4),
                 OpCode::Data(1),
                 OpCode::Data(1),
                 OpCode::Method(1),
                 OpCode::Pop,
-                OpCode::Pop,
-                OpCode::GetGlobal(3),
+                // `super` is captured by the method, so its scope closes it.
+                OpCode::CloseUpvalue,
+                OpCode::GetGlobal(1),
                 OpCode::Call(0),
-                OpCode::DefineGlobal(5),
-                OpCode::GetGlobal(5),
+                OpCode::DefineGlobal(2),
+                OpCode::GetGlobal(2),
                 OpCode::Invoke(1, 0),
                 OpCode::Pop,
                 OpCode::Nil,
                 OpCode::Return,
             ],
-            lines: vec![
+            vec![
                 1, 1, 1, 4, 4, 5, 6, 6, 6, 6, 6, 9, 9, 9, 9, 10, 10, 11, 11, 11, 12, 12, 12, 12, 12,
             ],
-            constants: vec![
+            vec![
                 value_string_super_class,         // 0
                 value_string_do_staff,            // 1
                 value_expected_super_do_staff_fn, // 2
                 value_string_sub_class,           // 3
                 value_expected_sub_do_staff_fn,   // 4
-                value_string_obj,                 // 5
             ],
-        };
+        );
         let source = r#"class superClass {
                                     doStaff() {
                                       print "Inheritance works";
@@ -2736,4 +3163,171 @@ mod tests {
         assert_eq!(chunk.lines, expected_chunk.lines);
         assert_constants_eq(&chunk.constants, &expected_chunk.constants);
     }
+
+    #[rstest]
+    #[case("1 + 2 * 3 - 2;", "5")]
+    #[case("-(4 / 2);", "-2")]
+    #[case("!(1 < 2);", "false")]
+    #[case("2 >= 2 == !nil;", "true")]
+    #[case("nil != false;", "true")]
+    #[case("\"a\" + \"b\" + \"c\";", "abc")]
+    #[case("7 % 3 * 2;", "2")]
+    #[case("2 ** 3 ** 2;", "512")]
+    #[case("-2 ** 2;", "-4")]
+    #[case("2 ** -1;", "0.5")]
+    #[case("1 | 6 & 3 ^ 1;", "3")]
+    #[case("1 + 1 << 2;", "8")]
+    #[case("1 | 2 == 3;", "true")]
+    #[case("~5;", "-6")]
+    fn literal_expressions_are_folded(#[case] source: &str, #[case] folded: &str) {
+        let chunk = compile(source.to_owned()).unwrap().chunk;
+        let instructions = chunk.instructions();
+        let value = match instructions[0] {
+            OpCode::Constant(index) => chunk.constants[index as usize].clone(),
+            OpCode::True => Value::Bool(true),
+            OpCode::False => Value::Bool(false),
+            other => panic!("expected a literal, got {other:?}"),
+        };
+        assert_eq!(value.to_string(), folded);
+        assert_eq!(
+            &instructions[1..],
+            [OpCode::Pop, OpCode::Nil, OpCode::Return]
+        );
+        assert!(chunk.constants.len() <= 1, "{:?}", chunk.constants);
+    }
+
+    #[rstest]
+    #[case("-\"x\";", OpCode::Negate)]
+    #[case("1 + \"a\";", OpCode::Add)]
+    #[case("(nil or 1) + 2;", OpCode::Add)]
+    #[case("var a; a * 2 + 1;", OpCode::Add)]
+    #[case("1.5 & 1;", OpCode::BitAnd)]
+    #[case("1 << -1;", OpCode::ShiftLeft)]
+    #[case("~0.5;", OpCode::BitNot)]
+    fn other_expressions_are_left_for_the_vm(#[case] source: &str, #[case] op: OpCode) {
+        let chunk = compile(source.to_owned()).unwrap().chunk;
+        assert!(chunk.instructions().contains(&op));
+    }
+
+    #[rstest]
+    #[case::locals(
+        format!("{{ {} }}", (0..256).map(|i| format!("var a{i};")).collect::<String>()),
+        "Too many local variables in function."
+    )]
+    #[case::arguments(
+        format!("f({});", vec!["0"; 256].join(",")),
+        "Can't have more than 255 arguments."
+    )]
+    #[case::jump(
+        format!("if (true) {{ {} }}", "print 1;".repeat(22_000)),
+        "Too much code to jump over."
+    )]
+    #[case::loop_body(
+        format!("while (true) {{ {} }}", "print 1;".repeat(22_000)),
+        "Loop body too large."
+    )]
+    #[case::property_accesses(
+        format!("var a; {}", "a.b;".repeat(MAX_INLINE_CACHES + 1)),
+        "Too many property accesses in one chunk."
+    )]
+    fn operand_limits_are_reported(#[case] source: String, #[case] message: &str) {
+        let diagnostics = compile(source).expect_err("source should not compile");
+        assert!(
+            diagnostics.iter().any(|d| d.message == message),
+            "{diagnostics:?}"
+        );
+    }
+
+    #[rstest]
+    #[case(
+        "break;",
+        "[line 1] Error at 'break': Can't use 'break' outside of a loop."
+    )]
+    #[case(
+        "while (true) { fun f() { continue; } }",
+        "[line 1] Error at 'continue': Can't use 'continue' outside of a loop."
+    )]
+    #[case(
+        "a: while (true) { while (true) break b; }",
+        "[line 1] Error at 'b': No enclosing loop labeled 'b'."
+    )]
+    #[case(
+        "a: print 1;",
+        "[line 1] Error at 'print': Expect a loop after a label."
+    )]
+    #[case(
+        "while (true) break",
+        "[line 1] Error at end: Expect ';' after 'break'."
+    )]
+    fn misplaced_loop_exits_are_reported(#[case] source: &str, #[case] message: &str) {
+        let diagnostics = compile(source.to_owned()).expect_err("source should not compile");
+        assert_eq!(diagnostics[0].to_string(), message);
+    }
+
+    #[test]
+    fn lists_and_indexes_compile_to_their_opcodes() {
+        let function = compile("var xs = [nil, true]; xs[0] += xs[1];".to_owned()).unwrap();
+        let instructions = function.chunk.instructions();
+        assert!(instructions.contains(&OpCode::BuildList(2)));
+        let dup = instructions
+            .iter()
+            .position(|op| *op == OpCode::Dup2)
+            .unwrap();
+        assert_eq!(instructions[dup + 1], OpCode::GetIndex);
+        assert!(instructions[dup..].contains(&OpCode::SetIndex));
+    }
+
+    #[rstest]
+    #[case(
+        "print [1, 2;",
+        "[line 1] Error at ';': Expect ']' after list elements."
+    )]
+    #[case(
+        "var xs; print xs[0;",
+        "[line 1] Error at ';': Expect ']' after index."
+    )]
+    fn unclosed_brackets_are_reported(#[case] source: &str, #[case] message: &str) {
+        let diagnostics = compile(source.to_owned()).expect_err("source should not compile");
+        assert_eq!(diagnostics[0].to_string(), message);
+    }
+
+    #[test]
+    fn list_literals_have_an_element_limit() {
+        let source = format!("print [{}];", vec!["nil"; u16::MAX as usize + 1].join(", "));
+        let diagnostics = compile(source).expect_err("source should not compile");
+        assert!(
+            diagnostics[0]
+                .to_string()
+                .ends_with("Too many elements in a list literal."),
+            "{diagnostics:?}"
+        );
+    }
+
+    #[rstest]
+    #[case(r#"var x; "a${x}b";"#, &[OpCode::Constant(0), OpCode::GetGlobal(0), OpCode::Stringify, OpCode::Add, OpCode::Constant(1), OpCode::Add])]
+    #[case(r#"var x; "${x}";"#, &[OpCode::GetGlobal(0), OpCode::Stringify])]
+    #[case(r#"var x; "${x}${x}";"#, &[OpCode::GetGlobal(0), OpCode::Stringify, OpCode::GetGlobal(0), OpCode::Stringify, OpCode::Add])]
+    fn interpolations_concatenate_their_parts(#[case] source: &str, #[case] expected: &[OpCode]) {
+        let instructions = compile(source.to_owned()).unwrap().chunk.instructions();
+        // Skip `var x;`, and the `Pop` of the expression statement and the implicit return.
+        assert_eq!(&instructions[2..instructions.len() - 3], expected);
+    }
+
+    #[test]
+    fn string_constants_keep_escaped_quotes() {
+        let chunk = compile(r#""say \"hi\"\n";"#.to_owned()).unwrap().chunk;
+        assert_eq!(chunk.constants[0].to_string(), "say \"hi\"\n");
+    }
+
+    #[rstest]
+    #[case(
+        r#"var a; print "${a b}";"#,
+        "[line 1] Error at 'b': Expect '}' after interpolated expression."
+    )]
+    #[case(r#"print "a${}b";"#, "[line 1] Error at '}': Expect expression.")]
+    #[case(r#"print "\x";"#, r"[line 1] Error: Unknown escape sequence '\x'.")]
+    fn malformed_strings_are_reported(#[case] source: &str, #[case] message: &str) {
+        let diagnostics = compile(source.to_owned()).expect_err("source should not compile");
+        assert_eq!(diagnostics[0].to_string(), message);
+    }
 }
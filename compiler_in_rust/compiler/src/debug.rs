@@ -0,0 +1,419 @@
+use crate::chunks::{Chunk, OpCode};
+use crate::globals::GlobalNames;
+use crate::object::{Obj, ObjFunction};
+use crate::value::Value;
+use std::fmt::Write;
+
+/// How `--trace` reports each instruction before the VM dispatches it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// The stack on one line, then the disassembled instruction.
+    Text,
+    /// One JSON object per instruction, so traces of two runs can be diffed.
+    Json,
+}
+
+/// Disassembles a compiled function and, after it, every function found in its
+/// constant table (methods, nested functions and closures), depth first. Global
+/// slots are named from `globals`, the table the function was compiled with.
+pub fn disassemble_function(function: &ObjFunction, globals: &GlobalNames) -> String {
+    let mut out = String::new();
+    write_function(&mut out, function, globals);
+    out
+}
+
+fn write_function(out: &mut String, function: &ObjFunction, globals: &GlobalNames) {
+    let name = if function.name.is_empty() {
+        "<script>"
+    } else {
+        function.name.as_str()
+    };
+    out.push_str(&disassemble_chunk(&function.chunk, name, globals));
+
+    for constant in &function.chunk.constants {
+        if let Some(obj) = constant.obj()
+            && let Obj::Function(nested) = &*obj.borrow()
+        {
+            out.push('\n');
+            write_function(out, nested, globals);
+        }
+    }
+}
+
+pub fn disassemble_chunk(chunk: &Chunk, name: &str, globals: &GlobalNames) -> String {
+    let mut out = format!("== {name} ==\n");
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(&mut out, chunk, globals, offset);
+    }
+    out
+}
+
+/// Appends one instruction to `out` and returns the offset of the next one.
+pub fn disassemble_instruction(
+    out: &mut String,
+    chunk: &Chunk,
+    globals: &GlobalNames,
+    offset: usize,
+) -> usize {
+    let _ = write!(out, "{offset:04} ");
+    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+        out.push_str("   | ");
+    } else {
+        let _ = write!(out, "{:4} ", chunk.lines[offset]);
+    }
+
+    let (instruction, next) = chunk.read(offset);
+    let name = &instruction_name(chunk, offset, &instruction);
+    match instruction {
+        OpCode::DefineGlobal(slot) | OpCode::GetGlobal(slot) | OpCode::SetGlobal(slot) => {
+            let global = usize::try_from(slot)
+                .ok()
+                .filter(|&slot| slot < globals.len())
+                .map_or("<unknown global>", |slot| globals.name(slot));
+            let _ = writeln!(out, "{name:<16} {slot:4} '{global}'");
+        }
+        OpCode::Constant(index)
+        | OpCode::GetProperty(index)
+        | OpCode::SetProperty(index)
+        | OpCode::GetSuper(index)
+        | OpCode::Class(index)
+        | OpCode::Method(index) => constant_instruction(out, name, chunk, index),
+        OpCode::GetLocal(slot)
+        | OpCode::SetLocal(slot)
+        | OpCode::GetUpvalue(slot)
+        | OpCode::SetUpvalue(slot)
+        | OpCode::Call(slot)
+        | OpCode::SetLocalPop(slot)
+        | OpCode::BuildList(slot) => {
+            let _ = writeln!(out, "{name:<16} {slot:4}");
+        }
+        OpCode::AddLocals(a, b) => {
+            let _ = writeln!(out, "{name:<16} {a:4} {b:4}");
+        }
+        OpCode::AddConstant(index) => constant_instruction(out, name, chunk, index),
+        OpCode::Invoke(index, arg_count) | OpCode::SuperInvoke(index, arg_count) => {
+            let _ = write!(out, "{name:<16} ({arg_count} args) {index:4} ");
+            write_constant(out, chunk, index);
+        }
+        OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) => {
+            let target = next + jump as usize;
+            let _ = writeln!(out, "{name:<16} {offset:4} -> {target}");
+        }
+        OpCode::Loop(jump) => {
+            let target = next as isize - jump as isize;
+            let _ = writeln!(out, "{name:<16} {offset:4} -> {target}");
+        }
+        OpCode::Closure(index) => {
+            constant_instruction(out, name, chunk, index);
+            return closure_upvalues(out, chunk, index, next);
+        }
+        OpCode::Data(byte) => {
+            let _ = writeln!(out, "{name:<16} {byte:4}");
+        }
+        _ => {
+            let _ = writeln!(out, "{name}");
+        }
+    }
+    next
+}
+
+/// The `OP_*` name of the instruction at `offset`, with `_LONG` for the wide form.
+fn instruction_name(chunk: &Chunk, offset: usize, instruction: &OpCode) -> String {
+    let name = op_name(instruction);
+    if chunk.is_long(offset) {
+        format!("{name}_LONG")
+    } else {
+        name.to_owned()
+    }
+}
+
+fn constant_instruction(out: &mut String, name: &str, chunk: &Chunk, index: isize) {
+    let _ = write!(out, "{name:<16} {index:4} ");
+    write_constant(out, chunk, index);
+}
+
+fn write_constant(out: &mut String, chunk: &Chunk, index: isize) {
+    match chunk.constants.get(index as usize) {
+        Some(value) => {
+            let _ = writeln!(out, "'{value}'");
+        }
+        None => out.push_str("<missing constant>\n"),
+    }
+}
+
+/// Prints the byte pairs that follow a `Closure` and describe its upvalues.
+fn closure_upvalues(out: &mut String, chunk: &Chunk, index: isize, mut offset: usize) -> usize {
+    for _ in 0..chunk.upvalue_count(index) {
+        let (Some(is_local), Some(slot)) = (chunk.code.get(offset), chunk.code.get(offset + 1))
+        else {
+            break;
+        };
+        let kind = if *is_local == 1 { "local" } else { "upvalue" };
+        let _ = writeln!(out, "{offset:04}    |                     {kind} {slot}");
+        offset += 2;
+    }
+    offset
+}
+
+/// Formats the trace record for the instruction at `ip`, before it executes.
+pub fn trace_instruction(
+    format: TraceFormat,
+    function: &ObjFunction,
+    ip: usize,
+    stack: &[Value],
+    globals: &GlobalNames,
+) -> String {
+    let name = if function.name.is_empty() {
+        "<script>"
+    } else {
+        function.name.as_str()
+    };
+    let mut out = String::new();
+    match format {
+        TraceFormat::Text => {
+            out.push_str("          ");
+            for value in stack {
+                let _ = write!(out, "[ {value} ]");
+            }
+            let _ = write!(out, "\n{name:<10}");
+            disassemble_instruction(&mut out, &function.chunk, globals, ip);
+        }
+        TraceFormat::Json => {
+            let (instruction, _) = function.chunk.read(ip);
+            let operands: Vec<String> = operands(&instruction)
+                .iter()
+                .map(|operand| operand.to_string())
+                .collect();
+            let stack: Vec<String> = stack
+                .iter()
+                .map(|value| json_string(&value.to_string()))
+                .collect();
+            let _ = writeln!(
+                out,
+                "{{\"function\":{},\"ip\":{},\"line\":{},\"op\":\"{}\",\"operands\":[{}],\"stack\":[{}]}}",
+                json_string(name),
+                ip,
+                function.chunk.lines[ip],
+                instruction_name(&function.chunk, ip, &instruction),
+                operands.join(","),
+                stack.join(","),
+            );
+        }
+    }
+    out
+}
+
+fn operands(instruction: &OpCode) -> Vec<i64> {
+    match *instruction {
+        OpCode::Constant(operand)
+        | OpCode::DefineGlobal(operand)
+        | OpCode::SetGlobal(operand)
+        | OpCode::SetLocal(operand)
+        | OpCode::GetGlobal(operand)
+        | OpCode::GetLocal(operand)
+        | OpCode::GetUpvalue(operand)
+        | OpCode::SetUpvalue(operand)
+        | OpCode::SetProperty(operand)
+        | OpCode::GetProperty(operand)
+        | OpCode::Call(operand)
+        | OpCode::Closure(operand)
+        | OpCode::Method(operand)
+        | OpCode::Class(operand)
+        | OpCode::GetSuper(operand)
+        | OpCode::AddConstant(operand)
+        | OpCode::SetLocalPop(operand)
+        | OpCode::BuildList(operand) => vec![operand as i64],
+        OpCode::AddLocals(a, b) => vec![a as i64, b as i64],
+        OpCode::Invoke(index, arg_count) | OpCode::SuperInvoke(index, arg_count) => {
+            vec![index as i64, arg_count as i64]
+        }
+        OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) | OpCode::Loop(offset) => {
+            vec![offset as i64]
+        }
+        OpCode::Data(byte) => vec![byte as i64],
+        _ => vec![],
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn op_name(instruction: &OpCode) -> &'static str {
+    match instruction {
+        OpCode::Constant(_) => "OP_CONSTANT",
+        OpCode::DefineGlobal(_) => "OP_DEFINE_GLOBAL",
+        OpCode::SetGlobal(_) => "OP_SET_GLOBAL",
+        OpCode::SetLocal(_) => "OP_SET_LOCAL",
+        OpCode::GetGlobal(_) => "OP_GET_GLOBAL",
+        OpCode::GetLocal(_) => "OP_GET_LOCAL",
+        OpCode::GetUpvalue(_) => "OP_GET_UPVALUE",
+        OpCode::SetUpvalue(_) => "OP_SET_UPVALUE",
+        OpCode::SetProperty(_) => "OP_SET_PROPERTY",
+        OpCode::GetProperty(_) => "OP_GET_PROPERTY",
+        OpCode::Call(_) => "OP_CALL",
+        OpCode::Invoke(_, _) => "OP_INVOKE",
+        OpCode::SuperInvoke(_, _) => "OP_SUPER_INVOKE",
+        OpCode::Jump(_) => "OP_JUMP",
+        OpCode::JumpIfFalse(_) => "OP_JUMP_IF_FALSE",
+        OpCode::Loop(_) => "OP_LOOP",
+        OpCode::Closure(_) => "OP_CLOSURE",
+        OpCode::Method(_) => "OP_METHOD",
+        OpCode::Nil => "OP_NIL",
+        OpCode::True => "OP_TRUE",
+        OpCode::False => "OP_FALSE",
+        OpCode::Add => "OP_ADD",
+        OpCode::Subtract => "OP_SUBTRACT",
+        OpCode::Multiply => "OP_MULTIPLY",
+        OpCode::Divide => "OP_DIVIDE",
+        OpCode::Not => "OP_NOT",
+        OpCode::Equal => "OP_EQUAL",
+        OpCode::Greater => "OP_GREATER",
+        OpCode::Less => "OP_LESS",
+        OpCode::Negate => "OP_NEGATE",
+        OpCode::Return => "OP_RETURN",
+        OpCode::Print => "OP_PRINT",
+        OpCode::Pop => "OP_POP",
+        OpCode::Class(_) => "OP_CLASS",
+        OpCode::Inherit => "OP_INHERIT",
+        OpCode::GetSuper(_) => "OP_GET_SUPER",
+        OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+        OpCode::Data(_) => "OP_DATA",
+        OpCode::Nop => "OP_NOP",
+        OpCode::AddLocals(_, _) => "OP_ADD_LOCALS",
+        OpCode::AddConstant(_) => "OP_ADD_CONSTANT",
+        OpCode::NotEqual => "OP_NOT_EQUAL",
+        OpCode::SetLocalPop(_) => "OP_SET_LOCAL_POP",
+        OpCode::BuildList(_) => "OP_BUILD_LIST",
+        OpCode::GetIndex => "OP_GET_INDEX",
+        OpCode::SetIndex => "OP_SET_INDEX",
+        OpCode::Dup2 => "OP_DUP2",
+        OpCode::Stringify => "OP_STRINGIFY",
+        OpCode::Modulo => "OP_MODULO",
+        OpCode::Power => "OP_POWER",
+        OpCode::BitAnd => "OP_BIT_AND",
+        OpCode::BitOr => "OP_BIT_OR",
+        OpCode::BitXor => "OP_BIT_XOR",
+        OpCode::BitNot => "OP_BIT_NOT",
+        OpCode::ShiftLeft => "OP_SHIFT_LEFT",
+        OpCode::ShiftRight => "OP_SHIFT_RIGHT",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile_with_globals;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn compile(source: &str) -> (ObjFunction, GlobalNames) {
+        let globals = Rc::new(RefCell::new(GlobalNames::new()));
+        let function = compile_with_globals(source.to_owned(), Rc::clone(&globals)).unwrap();
+        (function, globals.take())
+    }
+
+    #[test]
+    fn disassemble_chunk_lists_offsets_lines_and_constants() {
+        let (function, globals) = compile("var x = 2 + 3;\nprint x;");
+        let listing = disassemble_chunk(&function.chunk, "<script>", &globals);
+
+        let expected = "\
+== <script> ==
+0000    1 OP_CONSTANT         0 '5'
+0002    | OP_DEFINE_GLOBAL    0 'x'
+0004    2 OP_GET_GLOBAL       0 'x'
+0006    | OP_PRINT
+0007    | OP_NIL
+0008    | OP_RETURN
+";
+        assert_eq!(listing, expected);
+    }
+
+    #[test]
+    fn disassemble_jumps_show_targets() {
+        let (function, globals) = compile("while (false) print 1;");
+        let listing = disassemble_chunk(&function.chunk, "<script>", &globals);
+
+        assert!(listing.contains("0001    | OP_JUMP_IF_FALSE    1 -> 11"));
+        assert!(listing.contains("0008    | OP_LOOP             8 -> 0"));
+    }
+
+    #[test]
+    fn disassemble_names_the_long_forms() {
+        let source: String = (0..300).map(|i| format!("print {i};\n")).collect();
+        let (function, globals) = compile(&source);
+        let listing = disassemble_chunk(&function.chunk, "<script>", &globals);
+
+        assert!(listing.contains("0765  256 OP_CONSTANT       255 '255'\n"));
+        assert!(listing.contains("0768  257 OP_CONSTANT_LONG  256 '256'\n"));
+        assert!(listing.contains("0773  258 OP_CONSTANT_LONG  257 '257'\n"));
+    }
+
+    #[test]
+    fn disassemble_function_recurses_into_closures_and_methods() {
+        let source = r#"
+            fun outer() {
+              var a = 1;
+              fun inner() { return a; }
+              return inner;
+            }
+            class Cake {
+              eat() { return "yum"; }
+            }
+        "#;
+        let (function, globals) = compile(source);
+        let listing = disassemble_function(&function, &globals);
+
+        assert!(listing.starts_with("== <script> ==\n"));
+        assert!(listing.contains("== outer ==\n"));
+        assert!(listing.contains("== inner ==\n"));
+        assert!(listing.contains("== eat ==\n"));
+        assert!(listing.contains("OP_CLOSURE          1 '<fn>inner<fn>'\n"));
+        assert!(listing.contains("|                     local 1\n"));
+        assert!(listing.contains("OP_GET_UPVALUE      0\n"));
+        assert!(listing.contains("OP_METHOD"));
+    }
+
+    #[test]
+    fn trace_text_shows_stack_then_instruction() {
+        let (function, globals) = compile("print nil + 2;");
+        let stack = [Value::Nil, Value::Nil, Value::Number(2.0)];
+        let record = trace_instruction(TraceFormat::Text, &function, 3, &stack, &globals);
+
+        assert_eq!(
+            record,
+            "          [ nil ][ nil ][ 2 ]\n<script>  0003    | OP_ADD\n"
+        );
+    }
+
+    #[test]
+    fn trace_json_is_one_object_per_line() {
+        let (function, globals) = compile("print \"a\\\\b\";");
+        let stack = [Value::Nil];
+        let record = trace_instruction(TraceFormat::Json, &function, 0, &stack, &globals);
+
+        assert_eq!(
+            record,
+            "{\"function\":\"<script>\",\"ip\":0,\"line\":1,\"op\":\"OP_CONSTANT\",\"operands\":[0],\"stack\":[\"nil\"]}\n"
+        );
+        assert_eq!(json_string("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+
+/// A byte range into the source text, `start..end`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A compile-time problem together with where it was found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    /// 1-based column of the first character of `span`.
+    pub column: usize,
+    pub span: Span,
+    /// The offending token's text (empty at the end of the input), or `None` when the
+    /// scanner rejected the text itself.
+    pub lexeme: Option<String>,
+    /// The error was found at the end of the input, so more input might fix it.
+    pub at_end: bool,
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic with the offending source line and a caret underline:
+    ///
+    /// ```text
+    /// error: Expect ';' after value.
+    ///  --> line 1, column 8
+    ///   |
+    /// 1 | print 1
+    ///   |        ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let text = source
+            .lines()
+            .nth(self.line.saturating_sub(1))
+            .unwrap_or("");
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let indent = " ".repeat(self.column.saturating_sub(1));
+        let underline = source
+            .get(self.span.start..self.span.end)
+            .and_then(|spanned| spanned.lines().next())
+            .map_or(0, |first| first.chars().count())
+            .max(1);
+
+        format!(
+            "{severity}: {message}\n{gutter}--> line {line}, column {column}\n\
+             {gutter} |\n{number} | {text}\n{gutter} | {indent}{carets}\n",
+            severity = self.severity,
+            message = self.message,
+            line = self.line,
+            column = self.column,
+            carets = "^".repeat(underline),
+        )
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// The one-line `[line N] Error at 'x': message` form used by clox.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        write!(f, "[line {}] {label}", self.line)?;
+        match &self.lexeme {
+            Some(_) if self.at_end => write!(f, " at end")?,
+            Some(lexeme) => write!(f, " at '{lexeme}'")?,
+            None => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        compile(source.to_owned()).expect_err("source should not compile")
+    }
+
+    #[test]
+    fn error_at_token_has_position_and_span() {
+        let source = "var a = 1;\nvar b = ;";
+        let diagnostics = diagnostics(source);
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                severity: Severity::Error,
+                message: "Expect expression.".to_owned(),
+                line: 2,
+                column: 9,
+                span: Span { start: 19, end: 20 },
+                lexeme: Some(";".to_owned()),
+                at_end: false,
+            }]
+        );
+        assert_eq!(&source[19..20], ";");
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 2] Error at ';': Expect expression."
+        );
+    }
+
+    #[test]
+    fn error_at_end() {
+        let diagnostics = diagnostics("print 1");
+        assert!(diagnostics[0].at_end);
+        assert_eq!(diagnostics[0].column, 8);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 1] Error at end: Expect ';' after value."
+        );
+    }
+
+    #[test]
+    fn scanner_errors_have_no_lexeme() {
+        let diagnostics = diagnostics("print \"open;");
+        assert_eq!(diagnostics[0].lexeme, None);
+        assert!(diagnostics[0].at_end);
+        assert_eq!(diagnostics[0].span, Span { start: 6, end: 12 });
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 1] Error: Unterminated string."
+        );
+    }
+
+    #[test]
+    fn errors_in_nested_functions_are_collected() {
+        let diagnostics = diagnostics("fun f() {\n  print 1\n}\nvar = 2;");
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "[line 3] Error at '}': Expect ';' after value.",
+                "[line 4] Error at '=': Expect variable name.",
+                "[line 4] Error at end: Expect '}' after block.",
+            ]
+        );
+    }
+
+    #[test]
+    fn render_underlines_the_span() {
+        let source = "var x = 1;\nprint x +* 2;\n";
+        let rendered = diagnostics(source)[0].render(source);
+        assert_eq!(
+            rendered,
+            "error: Expect expression.\n --> line 2, column 10\n  |\n2 | print x +* 2;\n  |          ^\n"
+        );
+
+        let source = "print notakeyword notakeyword;";
+        let rendered = diagnostics(source)[0].render(source);
+        assert!(rendered.ends_with("  |                   ^^^^^^^^^^^\n"));
+    }
+
+    #[test]
+    fn render_counts_columns_in_characters() {
+        let source = "print \"héllo\" @;";
+        let diagnostic = &diagnostics(source)[0];
+        assert_eq!(diagnostic.column, 15);
+        assert!(diagnostic.render(source).ends_with("|               ^\n"));
+    }
+}
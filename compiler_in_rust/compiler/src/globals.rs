@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+/// Numbers global variable names as they are compiled. The VM shares its table with
+/// the compiler, so a slot keeps its meaning across REPL entries and `interpret` calls.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GlobalNames {
+    slots: HashMap<String, usize>,
+    names: Vec<String>,
+}
+
+impl GlobalNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The slot for `name`, allocating the next free one the first time it is seen.
+    pub fn slot(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.slots.get(name) {
+            return slot;
+        }
+        let slot = self.names.len();
+        self.slots.insert(name.to_owned(), slot);
+        self.names.push(name.to_owned());
+        slot
+    }
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+    pub fn name(&self, slot: usize) -> &str {
+        &self.names[slot]
+    }
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_allocated_once_per_name() {
+        let mut names = GlobalNames::new();
+        assert_eq!(names.slot("a"), 0);
+        assert_eq!(names.slot("b"), 1);
+        assert_eq!(names.slot("a"), 0);
+        assert_eq!(names.get("b"), Some(1));
+        assert_eq!(names.get("c"), None);
+        assert_eq!(names.name(1), "b");
+        assert_eq!(names.len(), 2);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::object::{Obj, ObjString};
+use crate::value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Holds one string object per distinct text. The compiler and the VM share a table,
+/// so equal strings are the same object and compare by identity.
+#[derive(Debug, Default, Clone)]
+pub struct Interner {
+    strings: HashMap<String, Rc<RefCell<Obj>>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The string object for `text`, created the first time the text is seen.
+    pub fn intern(&mut self, text: &str) -> Value {
+        match self.strings.get(text) {
+            Some(obj) => Value::Obj(Rc::clone(obj)),
+            None => self.insert(text.to_owned()),
+        }
+    }
+    /// Like [`Interner::intern`], but keeps `text` as the object's data when it is new.
+    pub fn intern_owned(&mut self, text: String) -> Value {
+        match self.strings.get(&text) {
+            Some(obj) => Value::Obj(Rc::clone(obj)),
+            None => self.insert(text),
+        }
+    }
+    /// Swaps a string made outside the table, e.g. by a native, for its interned
+    /// object. Other values are returned unchanged.
+    pub fn canonical(&mut self, value: Value) -> Value {
+        let text = match value.obj() {
+            Some(obj) => match &*obj.borrow() {
+                Obj::String(string) => string.data.clone(),
+                _ => return value.clone(),
+            },
+            None => return value,
+        };
+        match self.strings.get(&text) {
+            Some(interned) => Value::Obj(Rc::clone(interned)),
+            None => {
+                self.strings.insert(text, value.as_obj());
+                value
+            }
+        }
+    }
+    /// Drops the strings that nothing but this table refers to.
+    pub fn remove_unused(&mut self) {
+        self.strings.retain(|_, obj| Rc::strong_count(obj) > 1);
+    }
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+    fn insert(&mut self, text: String) -> Value {
+        let obj = Rc::new(RefCell::new(Obj::String(Rc::new(ObjString::from_string(
+            text.clone(),
+        )))));
+        self.strings.insert(text, Rc::clone(&obj));
+        Value::Obj(obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::obj_val;
+
+    #[test]
+    fn equal_texts_share_one_object() {
+        let mut strings = Interner::new();
+        let a = strings.intern("abc");
+        let b = strings.intern_owned("abc".to_owned());
+        let c = strings.intern("abd");
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(strings.len(), 2);
+
+        let outside = obj_val(Obj::String(Rc::new(ObjString::copy_from_str("abc"))));
+        assert_ne!(outside, a);
+        assert_eq!(strings.canonical(outside), a);
+        assert_eq!(strings.canonical(Value::Number(1f64)), Value::Number(1f64));
+    }
+}
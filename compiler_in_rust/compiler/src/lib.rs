@@ -0,0 +1,45 @@
+//! A bytecode virtual machine for Lox, following the clox design from
+//! *Crafting Interpreters*.
+//!
+//! ```
+//! use compiler::{InterpretError, RuntimeErrorKind, Value, VM};
+//!
+//! fn answer(_arg_count: usize, _args: &[Value]) -> Value {
+//!     Value::Number(42.0)
+//! }
+//!
+//! let mut vm = VM::new();
+//! vm.define_native("answer", answer);
+//! assert_eq!(vm.interpret("var a = answer();".to_owned()), Ok(()));
+//!
+//! match vm.interpret("answer() + nil;".to_owned()) {
+//!     Err(InterpretError::Runtime(error)) => {
+//!         assert_eq!(error.kind, RuntimeErrorKind::TypeError);
+//!     }
+//!     _ => unreachable!(),
+//! }
+//! ```
+
+pub mod chunks;
+pub mod compiler;
+pub mod debug;
+pub mod diagnostics;
+pub mod globals;
+pub mod interner;
+mod memory;
+pub mod object;
+pub mod optimizer;
+mod scanner;
+pub mod value;
+pub mod vm;
+
+pub use compiler::{compile, compile_with_globals, compile_with_options};
+pub use diagnostics::Diagnostic;
+pub use globals::GlobalNames;
+pub use memory::GcStats;
+pub use object::NativeFn;
+pub use optimizer::OptLevel;
+pub use value::Value;
+pub use vm::{
+    InterpretError, ReplResult, RuntimeError, RuntimeErrorKind, SharedBuffer, VM, VMConfig,
+};
@@ -1,82 +1,353 @@
-use crate::vm::VM;
-use std::fs;
-
-mod chunks;
-mod compiler;
-mod memory;
-mod object;
-mod scanner;
-mod value;
-mod vm;
-
-fn main() -> std::io::Result<()> {
-    let scripts_location = "/mnt/d/Projects/crafting_interpreters/compiler_in_rust/lox_scripts/";
-    let mut vm = VM::new();
-    //    let contents = fs::read_to_string(scripts_location.to_owned() + "plus_equal.lox")?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "2plus2.lox",
-    // )?; // OK
-    //  let contents = fs::read_to_string(scripts_location.to_owned() + "benchmark.lox")?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "block1.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "block2.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "class_0.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "class_1.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "class_2.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "class_3.lox",
-    // )?; // OK
-    let contents = fs::read_to_string(scripts_location.to_owned() + "class_4.lox")?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "class_5.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "closure.lox",
-    // )?; // OK
-    //  let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "echo.lox",
-    // )?; // OK
-    //  let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "example1.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "example2.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "expresions.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "fib1.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "fib2.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "fib3.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "for.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "while.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "while_block.lox",
-    // )?; // OK
-    // let contents = fs::read_to_string(scripts_location.to_owned() + "recursion.lox")?; // OK
-    // let contents = fs::read_to_string(
-    //     scripts_location.to_owned() + "/test.lox",
-    // )?; // OK
-    vm.interpret(contents.to_owned());
-
-    Ok(())
+use compiler::debug::{self, TraceFormat};
+use compiler::{GlobalNames, InterpretError, OptLevel, ReplResult, VM, VMConfig};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::process;
+use std::rc::Rc;
+use std::{env, fs, io};
+
+const EXIT_USAGE: i32 = 64;
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+const EXIT_IO_ERROR: i32 = 74;
+
+const USAGE: &str = "Usage: compiler [flags]               (start a REPL)
+       compiler [flags] run [flags] <file> [args...]
+       compiler [flags] run [flags] - [args...]      (read the script from stdin)
+       compiler [flags] -e '<source>' [args...]
+
+Flags:
+  --disassemble    print the compiled bytecode instead of running the script (not in the REPL)
+  --trace          print each instruction and the value stack to stderr
+  --trace=json     the same trace as one JSON object per line
+  --stress-gc      collect garbage after every allocation
+  --gc-stats       print garbage collector statistics to stderr on exit
+  -O0              run the code as compiled (the default)
+  -O1              run the peephole optimizer over the compiled code
+  --max-frames=N   fail with a stack overflow past N nested calls
+  --max-stack=N    fail with a stack overflow past N values on the stack";
+
+enum Source {
+    File(String),
+    Stdin,
+    Inline(String),
+}
+
+#[derive(Default)]
+struct Flags {
+    disassemble: bool,
+    trace: Option<TraceFormat>,
+    stress_gc: bool,
+    gc_stats: bool,
+    opt_level: OptLevel,
+    config: VMConfig,
+}
+
+struct Options {
+    source: Source,
+    script_args: Vec<String>,
+    flags: Flags,
+}
+
+enum Command {
+    Repl(Flags),
+    Run(Options),
+}
+
+/// The positive number after the `=` of a `--flag=N` argument.
+fn parse_limit(arg: &str) -> Option<usize> {
+    let (_, limit) = arg.split_once('=')?;
+    limit.parse().ok().filter(|&limit| limit > 0)
+}
+
+/// Consumes leading flag arguments; returns `None` on an unknown or malformed `--flag`.
+fn take_flags<'a>(mut args: &'a [String], flags: &mut Flags) -> Option<&'a [String]> {
+    while let [arg, rest @ ..] = args {
+        match arg.as_str() {
+            "--disassemble" => flags.disassemble = true,
+            "--trace" => flags.trace = Some(TraceFormat::Text),
+            "--trace=json" => flags.trace = Some(TraceFormat::Json),
+            "--stress-gc" => flags.stress_gc = true,
+            "--gc-stats" => flags.gc_stats = true,
+            "-O0" => flags.opt_level = OptLevel::O0,
+            "-O1" => flags.opt_level = OptLevel::O1,
+            _ if arg.starts_with("--max-frames=") => flags.config.max_frames = parse_limit(arg)?,
+            _ if arg.starts_with("--max-stack=") => flags.config.max_stack = parse_limit(arg)?,
+            _ if arg.starts_with("--") => return None,
+            _ => break,
+        }
+        args = rest;
+    }
+    Some(args)
+}
+
+fn parse_args(args: &[String]) -> Option<Command> {
+    let mut flags = Flags::default();
+    let args = take_flags(args, &mut flags)?;
+    let (source, rest) = match args {
+        // There is no script to disassemble; every other flag applies to the REPL's VM.
+        [] if flags.disassemble => return None,
+        [] => return Some(Command::Repl(flags)),
+        [command, rest @ ..] if command == "run" => {
+            let [path, rest @ ..] = take_flags(rest, &mut flags)? else {
+                return None;
+            };
+            let source = if path == "-" {
+                Source::Stdin
+            } else {
+                Source::File(path.clone())
+            };
+            (source, rest)
+        }
+        [flag, code, rest @ ..] if flag == "-e" => (Source::Inline(code.clone()), rest),
+        _ => return None,
+    };
+
+    Some(Command::Run(Options {
+        source,
+        script_args: rest.to_vec(),
+        flags,
+    }))
+}
+
+fn disassemble(source: String, opt_level: OptLevel) -> i32 {
+    let globals = Rc::new(RefCell::new(GlobalNames::new()));
+    match compiler::compile_with_options(source.clone(), Rc::clone(&globals), opt_level) {
+        Ok(function) => {
+            print!(
+                "{}",
+                debug::disassemble_function(&function, &globals.borrow())
+            );
+            0
+        }
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprint!("{}", diagnostic.render(&source));
+            }
+            EXIT_COMPILE_ERROR
+        }
+    }
+}
+
+fn read_source(source: Source) -> io::Result<String> {
+    match source {
+        Source::File(path) => fs::read_to_string(path),
+        Source::Stdin => {
+            let mut contents = String::new();
+            io::stdin().read_to_string(&mut contents)?;
+            Ok(contents)
+        }
+        Source::Inline(code) => Ok(code),
+    }
+}
+
+fn source_name(source: &Source) -> String {
+    match source {
+        Source::File(path) => format!("\"{path}\""),
+        Source::Stdin => "<stdin>".to_owned(),
+        Source::Inline(_) => "<inline>".to_owned(),
+    }
+}
+
+fn exit_code(result: &Result<(), InterpretError>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(InterpretError::Compile(_)) => EXIT_COMPILE_ERROR,
+        Err(InterpretError::Runtime(_)) => EXIT_RUNTIME_ERROR,
+    }
+}
+
+/// A VM set up as the command-line flags ask.
+fn vm_with_flags(flags: &Flags) -> VM {
+    let mut vm = VM::with_config(flags.config);
+    vm.set_render_diagnostics(true);
+    vm.set_trace(flags.trace);
+    vm.set_stress_gc(flags.stress_gc);
+    vm.set_opt_level(flags.opt_level);
+    vm
+}
+
+fn repl(vm: &mut VM) {
+    let stdin = io::stdin();
+    let mut buffer = String::new();
+    loop {
+        print!("{}", if buffer.is_empty() { "> " } else { "... " });
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                println!();
+                break;
+            }
+            Ok(_) => {}
+        }
+
+        if !buffer.is_empty() && line.trim().is_empty() {
+            // A blank line gives up on the continuation and reports what is missing.
+            let _ = vm.interpret(std::mem::take(&mut buffer));
+            continue;
+        }
+
+        buffer.push_str(&line);
+        if let ReplResult::Done(_) = vm.interpret_repl(buffer.clone()) {
+            buffer.clear();
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Some(Command::Run(options)) => options,
+        Some(Command::Repl(flags)) => {
+            let mut vm = vm_with_flags(&flags);
+            repl(&mut vm);
+            if flags.gc_stats {
+                eprintln!("{}", vm.gc_stats());
+            }
+            return;
+        }
+        None => {
+            eprintln!("{USAGE}");
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    let name = source_name(&options.source);
+    let contents = match read_source(options.source) {
+        Ok(contents) => contents,
+        Err(error) => {
+            eprintln!("Could not read {name}: {error}.");
+            process::exit(EXIT_IO_ERROR);
+        }
+    };
+
+    if options.flags.disassemble {
+        process::exit(disassemble(contents, options.flags.opt_level));
+    }
+
+    let mut vm = vm_with_flags(&options.flags);
+    vm.set_args(options.script_args);
+    let result = vm.interpret(contents);
+    if options.flags.gc_stats {
+        eprintln!("{}", vm.gc_stats());
+    }
+
+    process::exit(exit_code(&result));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn run_options(list: &[&str]) -> Options {
+        match parse_args(&args(list)) {
+            Some(Command::Run(options)) => options,
+            _ => panic!("expected a run command for {list:?}"),
+        }
+    }
+
+    fn repl_flags(list: &[&str]) -> Flags {
+        match parse_args(&args(list)) {
+            Some(Command::Repl(flags)) => flags,
+            _ => panic!("expected the REPL for {list:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_no_args_starts_repl() {
+        assert!(matches!(parse_args(&args(&[])), Some(Command::Repl(_))));
+    }
+
+    #[test]
+    fn parse_flags_without_a_script_configure_the_repl() {
+        let flags = repl_flags(&["-O1", "--trace", "--stress-gc", "--gc-stats"]);
+        assert_eq!(flags.opt_level, OptLevel::O1);
+        assert_eq!(flags.trace, Some(TraceFormat::Text));
+        assert!(flags.stress_gc && flags.gc_stats);
+        assert_eq!(repl_flags(&["--max-stack=5000"]).config.max_stack, 5000);
+    }
+
+    #[test]
+    fn parse_run_file_with_script_args() {
+        let options = run_options(&["run", "fib.lox", "10", "--fast"]);
+        assert!(matches!(options.source, Source::File(ref path) if path == "fib.lox"));
+        assert_eq!(options.script_args, args(&["10", "--fast"]));
+    }
+
+    #[test]
+    fn parse_run_stdin() {
+        let options = run_options(&["run", "-"]);
+        assert!(matches!(options.source, Source::Stdin));
+        assert!(options.script_args.is_empty());
+    }
+
+    #[test]
+    fn parse_inline_source() {
+        let options = run_options(&["-e", "print 1;", "a"]);
+        assert!(matches!(options.source, Source::Inline(ref code) if code == "print 1;"));
+        assert_eq!(options.script_args, args(&["a"]));
+    }
+
+    #[test]
+    fn parse_flags_before_command_and_before_path() {
+        let options = run_options(&["--disassemble", "run", "a.lox", "--disassemble"]);
+        assert!(options.flags.disassemble);
+        assert_eq!(options.script_args, args(&["--disassemble"]));
+
+        let options = run_options(&["run", "--disassemble", "a.lox"]);
+        assert!(options.flags.disassemble);
+        assert!(matches!(options.source, Source::File(ref path) if path == "a.lox"));
+
+        assert!(!run_options(&["run", "a.lox"]).flags.disassemble);
+
+        let options = run_options(&["--trace", "-e", "print 1;"]);
+        assert_eq!(options.flags.trace, Some(TraceFormat::Text));
+        let options = run_options(&["run", "--trace=json", "a.lox"]);
+        assert_eq!(options.flags.trace, Some(TraceFormat::Json));
+
+        let options = run_options(&["--stress-gc", "run", "--gc-stats", "a.lox"]);
+        assert!(options.flags.stress_gc && options.flags.gc_stats);
+
+        assert_eq!(run_options(&["run", "a.lox"]).flags.opt_level, OptLevel::O0);
+        let options = run_options(&["-O1", "run", "a.lox", "-O0"]);
+        assert_eq!(options.flags.opt_level, OptLevel::O1);
+        assert_eq!(options.script_args, args(&["-O0"]));
+
+        assert_eq!(
+            run_options(&["run", "a.lox"]).flags.config,
+            VMConfig::default()
+        );
+        let options = run_options(&["--max-frames=100", "run", "--max-stack=5000", "a.lox"]);
+        assert_eq!(options.flags.config.max_frames, 100);
+        assert_eq!(options.flags.config.max_stack, 5000);
+    }
+
+    #[test]
+    fn parse_rejects_bad_usage() {
+        assert!(parse_args(&args(&["--bogus", "run", "a.lox"])).is_none());
+        assert!(parse_args(&args(&["run"])).is_none());
+        assert!(parse_args(&args(&["-e"])).is_none());
+        assert!(parse_args(&args(&["compile", "x.lox"])).is_none());
+        assert!(parse_args(&args(&["--max-frames=", "run", "a.lox"])).is_none());
+        assert!(parse_args(&args(&["--max-stack=0", "run", "a.lox"])).is_none());
+        assert!(parse_args(&args(&["--max-frames=-1", "run", "a.lox"])).is_none());
+        assert!(parse_args(&args(&["--disassemble"])).is_none());
+        assert!(parse_args(&args(&["--bogus"])).is_none());
+    }
+
+    #[test]
+    fn exit_codes_follow_book_convention() {
+        let mut vm = VM::new();
+        vm.set_output(io::sink());
+        vm.set_error_output(io::sink());
+        assert_eq!(exit_code(&vm.interpret("print 1;".to_owned())), 0);
+        assert_eq!(exit_code(&vm.interpret("print 1".to_owned())), 65);
+        assert_eq!(exit_code(&vm.interpret("print nil + 1;".to_owned())), 70);
+    }
 }
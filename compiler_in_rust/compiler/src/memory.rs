@@ -1,3 +1,16 @@
+use crate::interner::Interner;
+use crate::object::{
+    MapKey, Obj, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList, ObjMap, ObjString,
+    ObjUpvalue,
+};
+use crate::value::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem::size_of;
+use std::ptr;
+use std::rc::{Rc, Weak};
+
 #[macro_export]
 macro_rules! grow_capacity {
     ($capacity:expr) => {{
@@ -5,3 +18,359 @@ macro_rules! grow_capacity {
         if cap < 8 { 8 } else { cap * 2 }
     }};
 }
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const FIRST_GC: usize = 1024 * 1024;
+
+/// Running totals for the collector, reported by `--gc-stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub objects_allocated: usize,
+    pub objects_freed: usize,
+    /// Estimated size of the objects that are still tracked.
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    pub next_gc: usize,
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gc: {} collections, {} objects allocated, {} freed, {} bytes live, {} bytes freed, next at {} bytes",
+            self.collections,
+            self.objects_allocated,
+            self.objects_freed,
+            self.bytes_allocated,
+            self.bytes_freed,
+            self.next_gc
+        )
+    }
+}
+
+enum Tracked {
+    Obj(Weak<RefCell<Obj>>),
+    Upvalue(Weak<RefCell<ObjUpvalue>>),
+}
+
+impl Tracked {
+    fn is_alive(&self) -> bool {
+        match self {
+            Tracked::Obj(obj) => obj.strong_count() > 0,
+            Tracked::Upvalue(upvalue) => upvalue.strong_count() > 0,
+        }
+    }
+}
+
+/// Every object the VM allocated, with its estimated size.
+///
+/// Objects are reference counted, so anything outside a cycle is freed as soon as it
+/// is unreachable. A collection finds the objects that can no longer be reached from
+/// the roots and empties their fields and closed upvalues; that breaks the cycles
+/// they sit in and lets the reference counts free them. Objects that are owned from
+/// outside the heap, such as values an embedder holds, count as roots.
+pub struct Heap {
+    objects: Vec<(Tracked, usize)>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress: bool,
+    allocated_since_gc: bool,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+            stress: false,
+            allocated_since_gc: false,
+            stats: GcStats::default(),
+        }
+    }
+    /// Collects after every allocation instead of when the heap has grown.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+    pub fn track(&mut self, value: &Value) {
+        if value.is_obj() {
+            let obj = value.as_obj();
+            let size = size_of::<RefCell<Obj>>() + payload_size(&obj.borrow());
+            self.record(Tracked::Obj(Rc::downgrade(&obj)), size);
+        }
+    }
+    pub fn track_upvalue(&mut self, upvalue: &Rc<RefCell<ObjUpvalue>>) {
+        self.record(
+            Tracked::Upvalue(Rc::downgrade(upvalue)),
+            size_of::<RefCell<ObjUpvalue>>(),
+        );
+    }
+    fn record(&mut self, tracked: Tracked, size: usize) {
+        self.objects.push((tracked, size));
+        self.bytes_allocated += size;
+        self.allocated_since_gc = true;
+        self.stats.objects_allocated += 1;
+    }
+    pub fn should_collect(&self) -> bool {
+        (self.stress && self.allocated_since_gc) || self.bytes_allocated > self.next_gc
+    }
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            bytes_allocated: self.bytes_allocated,
+            next_gc: self.next_gc,
+            ..self.stats
+        }
+    }
+    /// Finishes marking from the roots already in `marker`, then sweeps.
+    pub fn collect(&mut self, mut marker: Marker, strings: &mut Interner) {
+        marker.trace_references();
+        self.mark_owned_elsewhere(&mut marker);
+        self.sweep(&marker);
+        strings.remove_unused();
+
+        // Whatever only a broken cycle or the string table held has been dropped by now.
+        let (mut objects_freed, mut bytes_freed) = (0, 0);
+        self.objects.retain(|(tracked, size)| {
+            if tracked.is_alive() {
+                return true;
+            }
+            objects_freed += 1;
+            bytes_freed += size;
+            false
+        });
+        self.bytes_allocated -= bytes_freed;
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(FIRST_GC);
+        self.allocated_since_gc = false;
+        self.stats.collections += 1;
+        self.stats.objects_freed += objects_freed;
+        self.stats.bytes_freed += bytes_freed;
+    }
+    /// Marks the unreached objects with more owners than the unreached objects account
+    /// for, along with everything they reach.
+    fn mark_owned_elsewhere(&self, marker: &mut Marker) {
+        let unreached: Vec<Rc<RefCell<Obj>>> = self
+            .objects
+            .iter()
+            .filter_map(|(tracked, _)| match tracked {
+                Tracked::Obj(obj) => obj.upgrade().filter(|obj| !marker.is_marked(obj)),
+                Tracked::Upvalue(_) => None,
+            })
+            .collect();
+        if unreached.is_empty() {
+            return;
+        }
+
+        // The census skips what is already reached and must not mark anything itself.
+        let mut census = Marker {
+            marked: marker.marked.clone(),
+            gray_stack: Vec::new(),
+            references: Some(HashMap::new()),
+        };
+        for obj in &unreached {
+            census.blacken(&obj.borrow());
+        }
+        let references = census.references.unwrap_or_default();
+
+        for obj in unreached {
+            let internal = references.get(&Rc::as_ptr(&obj).cast()).copied();
+            // One of the owners is `unreached` itself.
+            if Rc::strong_count(&obj) > internal.unwrap_or(0) + 1 {
+                marker.mark_value(&Value::Obj(obj));
+            }
+        }
+        marker.trace_references();
+    }
+    fn sweep(&self, marker: &Marker) {
+        for (tracked, _) in &self.objects {
+            match tracked {
+                Tracked::Obj(obj) => {
+                    if let Some(obj) = obj.upgrade()
+                        && !marker.is_marked(&obj)
+                    {
+                        match &mut *obj.borrow_mut() {
+                            Obj::Instance(instance) => instance.fields.clear(),
+                            Obj::List(list) => list.items.clear(),
+                            Obj::Map(map) => map.clear(),
+                            _ => {}
+                        }
+                    }
+                }
+                Tracked::Upvalue(upvalue) => {
+                    if let Some(upvalue) = upvalue.upgrade()
+                        && !marker.is_marked(&upvalue)
+                    {
+                        upvalue.borrow_mut().closed = Value::Nil;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn payload_size(obj: &Obj) -> usize {
+    match obj {
+        Obj::String(string) => size_of::<ObjString>() + string.data.len(),
+        Obj::Closure(closure) => {
+            size_of::<ObjClosure>() + closure.upvalues.len() * size_of::<Rc<RefCell<ObjUpvalue>>>()
+        }
+        Obj::Class(klass) => size_of::<ObjClass>() + klass.name.len(),
+        Obj::Instance(_) => size_of::<ObjInstance>(),
+        Obj::List(list) => size_of::<ObjList>() + list.items.len() * size_of::<Value>(),
+        Obj::Map(map) => {
+            let entry = 2 * size_of::<Value>() + size_of::<MapKey>() + size_of::<usize>();
+            size_of::<ObjMap>() + map.len() * entry
+        }
+        _ => 0,
+    }
+}
+
+/// The mark phase: remembers every object reached so far and the ones whose
+/// references still need tracing.
+#[derive(Default)]
+pub struct Marker {
+    marked: HashSet<*const ()>,
+    gray_stack: Vec<Value>,
+    /// When set, references to objects are counted here instead of being followed.
+    references: Option<HashMap<*const (), usize>>,
+}
+
+impl Marker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn is_marked<T>(&self, object: &Rc<T>) -> bool {
+        self.marked.contains(&(Rc::as_ptr(object) as *const ()))
+    }
+    /// Marks `object`, returning false if it already was.
+    fn mark<T>(&mut self, object: &Rc<T>) -> bool {
+        self.mark_ptr(Rc::as_ptr(object))
+    }
+    fn mark_ptr<T>(&mut self, object: *const T) -> bool {
+        self.marked.insert(object as *const ())
+    }
+    pub fn mark_value(&mut self, value: &Value) {
+        let Some(obj) = value.obj() else {
+            return;
+        };
+        if let Some(references) = &mut self.references {
+            *references.entry(ptr::from_ref(obj).cast()).or_default() += 1;
+        } else if self.mark_ptr(obj) {
+            self.gray_stack.push(value.clone());
+        }
+    }
+    pub fn mark_closure(&mut self, closure: &Rc<ObjClosure>) {
+        if !self.mark(closure) {
+            return;
+        }
+        self.mark_function(&closure.function);
+        for upvalue in &closure.upvalues {
+            self.mark_upvalue(upvalue);
+        }
+    }
+    pub fn mark_upvalue(&mut self, upvalue: &Rc<RefCell<ObjUpvalue>>) {
+        if self.mark(upvalue) {
+            // Open upvalues point into the stack, which is marked on its own.
+            let closed = upvalue.borrow().closed.clone();
+            self.mark_value(&closed);
+        }
+    }
+    fn mark_function(&mut self, function: &Rc<ObjFunction>) {
+        if self.mark(function) {
+            for constant in &function.chunk.constants {
+                self.mark_value(constant);
+            }
+        }
+    }
+    fn mark_class(&mut self, klass: &Rc<ObjClass>) {
+        if self.mark(klass) {
+            for method in klass.methods.values() {
+                self.mark_value(method);
+            }
+        }
+    }
+    fn trace_references(&mut self) {
+        while let Some(value) = self.gray_stack.pop() {
+            if let Some(obj) = value.obj() {
+                self.blacken(&obj.borrow());
+            }
+        }
+    }
+    fn blacken(&mut self, obj: &Obj) {
+        match obj {
+            Obj::String(_) | Obj::Native(_) => {}
+            Obj::Function(function) => self.mark_function(function),
+            Obj::Closure(closure) => self.mark_closure(closure),
+            Obj::Upvalue(upvalue) => self.mark_value(&upvalue.closed),
+            Obj::Class(klass) => self.mark_class(klass),
+            Obj::Instance(instance) => {
+                self.mark_class(&instance.klass);
+                for value in instance.fields.iter().flatten() {
+                    self.mark_value(value);
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                self.mark_value(&bound.receiver);
+                self.mark_closure(&bound.method);
+            }
+            Obj::List(list) => {
+                for item in &list.items {
+                    self.mark_value(item);
+                }
+            }
+            Obj::Map(map) => {
+                for (key, value) in map.entries() {
+                    self.mark_value(key);
+                    self.mark_value(value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Table;
+    use crate::value::obj_val;
+
+    /// An instance whose field `me` refers back to itself.
+    fn self_cycle(heap: &mut Heap, klass: &Rc<ObjClass>) -> Value {
+        let value = obj_val(Obj::Instance(ObjInstance::new(Rc::clone(klass))));
+        heap.track(&value);
+        if let Some(obj) = value.obj()
+            && let Obj::Instance(instance) = &mut *obj.borrow_mut()
+        {
+            let name = Rc::new(ObjString::copy_from_str("me"));
+            instance.set_field(&name, value.clone());
+        }
+        value
+    }
+
+    #[test]
+    fn collect_breaks_unmarked_cycles_only() {
+        let klass = Rc::new(ObjClass::new("C".to_owned(), Table::default()));
+        let mut heap = Heap::new();
+        let kept = self_cycle(&mut heap, &klass);
+        let dropped = Rc::downgrade(&self_cycle(&mut heap, &klass).as_obj());
+
+        let mut marker = Marker::new();
+        marker.mark_value(&kept);
+        heap.collect(marker, &mut Interner::new());
+
+        assert!(dropped.upgrade().is_none());
+        let stats = heap.stats();
+        assert_eq!((stats.collections, stats.objects_freed), (1, 1));
+        assert_eq!(stats.bytes_allocated, stats.bytes_freed);
+        assert!(
+            matches!(&*kept.as_obj().borrow(), Obj::Instance(i) if i.fields.iter().flatten().count() == 1)
+        );
+    }
+}
@@ -1,37 +1,91 @@
 use crate::chunks::Chunk;
 use crate::value::Value;
+use crate::vm::{RuntimeError, VM};
 use std::cell::RefCell;
-use std::{collections::HashMap, ptr, rc::Rc};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::rc::{Rc, Weak};
+use std::{fmt, ptr};
 
 pub type NativeFn = fn(arg_count: usize, args: &[Value]) -> Value;
+/// A native built into the VM, which can read its state and fail like any other call.
+pub type BuiltinFn = fn(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError>;
 
 /* ================== OBJECT ================== */
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Obj {
-    String(ObjString),
-    Function(ObjFunction),
-    Closure(ObjClosure),
+    String(Rc<ObjString>),
+    Function(Rc<ObjFunction>),
+    Closure(Rc<ObjClosure>),
     Native(ObjNative),
     Upvalue(ObjUpvalue),
     Class(Rc<ObjClass>),
     Instance(ObjInstance),
     BoundMethod(ObjBoundMethod),
+    List(ObjList),
+    Map(ObjMap),
 }
 
-impl Obj {
-    pub fn print(&self) {
+thread_local! {
+    /// The lists and maps being printed, innermost last, so one that contains itself ends.
+    static PRINTING: RefCell<Vec<*const Obj>> = const { RefCell::new(Vec::new()) };
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Obj::String(s) => print!("{}", s.data),
-            Obj::Function(f) => print!("<fn>{}<fn>", f.name),
-            Obj::Closure(_) => print!("<closure>"),
-            Obj::Native(_) => print!("<native fn>"),
-            Obj::Upvalue(_) => print!("<upvalue>"),
-            Obj::Class(c) => print!("<class {}>", c.name),
-            Obj::Instance(_) => print!("<instance>"),
-            Obj::BoundMethod(_) => print!("<bound method>"),
+            Obj::String(s) => write!(f, "{}", s.data),
+            Obj::Function(function) => write!(f, "<fn>{}<fn>", function.name),
+            Obj::Closure(_) => write!(f, "<closure>"),
+            Obj::Native(_) => write!(f, "<native fn>"),
+            Obj::Upvalue(_) => write!(f, "<upvalue>"),
+            Obj::Class(c) => write!(f, "<class {}>", c.name),
+            Obj::Instance(_) => write!(f, "<instance>"),
+            Obj::BoundMethod(_) => write!(f, "<bound method>"),
+            Obj::List(list) => write_nested(f, self, "[...]", |f| write_items(f, &list.items)),
+            Obj::Map(map) => write_nested(f, self, "{...}", |f| write_entries(f, map.entries())),
+        }
+    }
+}
+
+/// Writes a list or map with `write`, or `cycle` if it is already being written.
+fn write_nested(
+    f: &mut fmt::Formatter<'_>,
+    obj: &Obj,
+    cycle: &str,
+    write: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+) -> fmt::Result {
+    let this = obj as *const Obj;
+    if PRINTING.with_borrow(|printing| printing.contains(&this)) {
+        return write!(f, "{cycle}");
+    }
+    PRINTING.with_borrow_mut(|printing| printing.push(this));
+    let result = write(f);
+    PRINTING.with_borrow_mut(|printing| printing.pop());
+    result
+}
+
+fn write_items(f: &mut fmt::Formatter<'_>, items: &[Value]) -> fmt::Result {
+    write!(f, "[")?;
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    write!(f, "]")
+}
+
+fn write_entries(f: &mut fmt::Formatter<'_>, entries: &[(Value, Value)]) -> fmt::Result {
+    write!(f, "{{")?;
+    for (i, (key, value)) in entries.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
         }
+        write!(f, "{key}: {value}")?;
     }
+    write!(f, "}}")
 }
 
 /* ================== FUNCTION ================== */
@@ -55,6 +109,12 @@ impl ObjFunction {
     }
 }
 
+impl Default for ObjFunction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /* ================== CLOSURE ================== */
 
 #[derive(Debug, Clone, PartialEq)]
@@ -121,12 +181,24 @@ impl ObjUpvalue {
 
 /* ================== STRING ================== */
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Eq)]
 pub struct ObjString {
     pub data: String,
     pub hash: u64,
 }
 
+impl PartialEq for ObjString {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other) || (self.hash == other.hash && self.data == other.data)
+    }
+}
+
+impl Hash for ObjString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
 impl ObjString {
     pub fn from_string(s: String) -> Self {
         let hash = hash_string(&s);
@@ -144,24 +216,39 @@ impl ObjString {
 
 /* ================== NATIVE ================== */
 
+#[derive(Debug, Clone, Copy)]
+pub enum NativeFunction {
+    /// Defined by the embedder through `VM::define_native`.
+    Host(NativeFn),
+    Builtin(BuiltinFn),
+}
+
 #[derive(Debug, Clone)]
 pub struct ObjNative {
-    pub function: NativeFn,
+    pub function: NativeFunction,
 }
 
 impl ObjNative {
     pub fn new(function: NativeFn) -> Self {
-        Self { function }
+        Self {
+            function: NativeFunction::Host(function),
+        }
     }
 
-    pub fn call(&self, arg_count: usize, args: &[Value]) -> Value {
-        (self.function)(arg_count, args)
+    pub fn builtin(function: BuiltinFn) -> Self {
+        Self {
+            function: NativeFunction::Builtin(function),
+        }
     }
 }
 
 impl PartialEq for ObjNative {
     fn eq(&self, other: &Self) -> bool {
-        ptr::fn_addr_eq(self.function, other.function)
+        match (self.function, other.function) {
+            (NativeFunction::Host(a), NativeFunction::Host(b)) => ptr::fn_addr_eq(a, b),
+            (NativeFunction::Builtin(a), NativeFunction::Builtin(b)) => ptr::fn_addr_eq(a, b),
+            _ => false,
+        }
     }
 }
 impl Eq for ObjNative {}
@@ -171,12 +258,25 @@ impl Eq for ObjNative {}
 #[derive(Debug, Clone, PartialEq)]
 pub struct ObjClass {
     pub name: String,
-    pub methods: HashMap<String, Value>,
+    pub methods: Table,
+    /// The slot of every field name used on instances of this class. Adding a method
+    /// makes a new `ObjClass`, and the copies keep sharing one layout.
+    pub field_slots: Rc<RefCell<Table<usize>>>,
 }
 
 impl ObjClass {
-    pub fn new(name: String, methods: HashMap<String, Value>) -> Self {
-        Self { name, methods }
+    pub fn new(name: String, methods: Table) -> Self {
+        Self {
+            name,
+            methods,
+            field_slots: Rc::default(),
+        }
+    }
+    /// The slot for the field `name`, allocating the next one the first time it is used.
+    pub fn field_slot(&self, name: &Rc<ObjString>) -> usize {
+        let mut slots = self.field_slots.borrow_mut();
+        let next = slots.len();
+        *slots.entry(Rc::clone(name)).or_insert(next)
     }
 }
 
@@ -185,15 +285,29 @@ impl ObjClass {
 #[derive(Debug, Clone, PartialEq)]
 pub struct ObjInstance {
     pub klass: Rc<ObjClass>,
-    pub fields: HashMap<String, Value>,
+    /// Field values by their slot in `klass`; `None` where this instance has not set one.
+    pub fields: Vec<Option<Value>>,
 }
 
 impl ObjInstance {
     pub fn new(klass: Rc<ObjClass>) -> Self {
         Self {
             klass,
-            fields: HashMap::new(),
+            fields: Vec::new(),
+        }
+    }
+    pub fn field_at(&self, slot: usize) -> Option<&Value> {
+        self.fields.get(slot)?.as_ref()
+    }
+    pub fn set_field(&mut self, name: &Rc<ObjString>, value: Value) {
+        let slot = self.klass.field_slot(name);
+        self.set_field_at(slot, value);
+    }
+    pub fn set_field_at(&mut self, slot: usize, value: Value) {
+        if slot >= self.fields.len() {
+            self.fields.resize(slot + 1, None);
         }
+        self.fields[slot] = Some(value);
     }
 }
 
@@ -205,6 +319,187 @@ pub struct ObjBoundMethod {
     pub method: Rc<ObjClosure>,
 }
 
+/* ================== LIST ================== */
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ObjList {
+    pub items: Vec<Value>,
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self { items }
+    }
+}
+
+/* ================== MAP ================== */
+
+/// A value that can key a map. Numbers compare by value, so `0` and `-0` are one key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    String(Rc<ObjString>),
+}
+
+impl MapKey {
+    /// The key for `value`, or `None` if it has no stable identity to hash.
+    pub fn new(value: &Value) -> Option<Self> {
+        if value.is_nil() {
+            Some(MapKey::Nil)
+        } else if value.is_bool() {
+            Some(MapKey::Bool(value.as_bool()))
+        } else if value.is_number() {
+            Some(MapKey::Number((value.as_number() + 0.0).to_bits()))
+        } else if let Obj::String(string) = &*value.obj()?.borrow() {
+            Some(MapKey::String(Rc::clone(string)))
+        } else {
+            None
+        }
+    }
+}
+
+/// A hash map that iterates in insertion order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ObjMap {
+    entries: Vec<(Value, Value)>,
+    indices: HashMap<MapKey, usize>,
+}
+
+impl ObjMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The keys and values, oldest first.
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        self.indices.get(key).map(|&index| &self.entries[index].1)
+    }
+
+    pub fn contains_key(&self, key: &MapKey) -> bool {
+        self.indices.contains_key(key)
+    }
+
+    /// Sets the value under `key`. A new key goes last; an existing one keeps its place.
+    pub fn insert(&mut self, key: MapKey, key_value: Value, value: Value) {
+        match self.indices.get(&key) {
+            Some(&index) => self.entries[index].1 = value,
+            None => {
+                self.indices.insert(key, self.entries.len());
+                self.entries.push((key_value, value));
+            }
+        }
+    }
+
+    /// Removes `key`, keeping the order of the entries after it.
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let index = self.indices.remove(key)?;
+        let (_, value) = self.entries.remove(index);
+        for later in self.indices.values_mut().filter(|later| **later > index) {
+            *later -= 1;
+        }
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.indices.clear();
+    }
+}
+
+/* ================== INLINE CACHE ================== */
+
+/// What a property name resolved to on an instance.
+pub enum Property {
+    Field(Value),
+    Method(Value),
+}
+
+/// Remembers how the property instruction that owns it resolved its name on the last
+/// class it saw: the field slot, and the method if the class has one.
+#[derive(Debug, Default, Clone)]
+pub struct InlineCache {
+    /// Weak, so a cache neither keeps a class alive nor mistakes a new class that
+    /// reuses a freed one's address for it.
+    class: Weak<ObjClass>,
+    slot: usize,
+    method: Weak<RefCell<Obj>>,
+}
+
+impl InlineCache {
+    /// The slot of the cached name if `instance` is of the cached class.
+    pub fn slot(&self, instance: &ObjInstance) -> Option<usize> {
+        ptr::eq(self.class.as_ptr(), Rc::as_ptr(&instance.klass)).then_some(self.slot)
+    }
+    /// Resolves the cached name on `instance`; `None` on a miss or if the name is
+    /// neither a field nor a method.
+    pub fn lookup(&self, instance: &ObjInstance) -> Option<Property> {
+        let slot = self.slot(instance)?;
+        match instance.field_at(slot) {
+            Some(value) => Some(Property::Field(value.clone())),
+            None => self
+                .method
+                .upgrade()
+                .map(|method| Property::Method(Value::Obj(method))),
+        }
+    }
+    /// Points the cache at `name` on `klass`, giving the name a field slot so a field
+    /// set later is found before the method it shadows.
+    pub fn fill(&mut self, klass: &Rc<ObjClass>, name: &Rc<ObjString>) -> usize {
+        self.class = Rc::downgrade(klass);
+        self.slot = klass.field_slot(name);
+        self.method = match klass.methods.get(name) {
+            Some(method) if method.is_obj() => Rc::downgrade(&method.as_obj()),
+            _ => Weak::new(),
+        };
+        self.slot
+    }
+}
+
+impl PartialEq for InlineCache {
+    fn eq(&self, other: &Self) -> bool {
+        self.class.ptr_eq(&other.class)
+            && self.slot == other.slot
+            && self.method.ptr_eq(&other.method)
+    }
+}
+
+/* ================== TABLE ================== */
+
+/// Maps interned names to values, hashing each by its precomputed hash.
+pub type Table<V = Value> = HashMap<Rc<ObjString>, V, BuildHasherDefault<StringHasher>>;
+
+/// Passes an [`ObjString`]'s precomputed hash through instead of hashing its text again.
+#[derive(Default)]
+pub struct StringHasher(u64);
+
+impl Hasher for StringHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(16777619);
+        }
+    }
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+}
+
 /* ================== HASH ================== */
 
 pub fn hash_string(s: &str) -> u64 {
@@ -0,0 +1,280 @@
+use crate::chunks::{Chunk, OpCode};
+
+/// How much the compiler optimizes each chunk after compiling it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    /// Run the code exactly as it was compiled.
+    #[default]
+    O0,
+    /// Run the peephole pass: thread jumps, fuse superinstructions and drop `Nop`s.
+    O1,
+}
+
+/// One decoded instruction. Jumps keep their target as an index into the list rather
+/// than an offset, so instructions can be merged and removed around them.
+#[derive(Clone, Copy, Debug)]
+struct Instruction {
+    op: OpCode,
+    line: usize,
+    target: Option<usize>,
+}
+
+/// Rewrites `chunk` in place with the same behaviour and fewer instructions.
+pub fn optimize(chunk: &mut Chunk) {
+    let mut instructions = decode(chunk);
+    thread_jumps(&mut instructions, &offsets(chunk));
+    let instructions = fuse(&instructions);
+
+    let mut optimized = Chunk::new();
+    optimized.constants = std::mem::take(&mut chunk.constants);
+    encode(&mut optimized, &instructions);
+    *chunk = optimized;
+}
+
+/// The instructions of `chunk`, with a closure's upvalue bytes as `Data`.
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let starts = offsets(chunk);
+    let index_of = |offset: usize| {
+        starts
+            .binary_search(&offset)
+            .expect("jumps land on an instruction")
+    };
+    let mut instructions = Vec::with_capacity(starts.len());
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (op, next) = chunk.read(offset);
+        let target = match op {
+            OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) => Some(next + jump as usize),
+            OpCode::Loop(jump) => Some(next - jump as usize),
+            _ => None,
+        };
+        instructions.push(Instruction {
+            op,
+            line: chunk.lines[offset],
+            target: target.map(index_of),
+        });
+        let end = chunk.next_offset(offset);
+        for &byte in &chunk.code[next..end] {
+            instructions.push(Instruction {
+                op: OpCode::Data(byte),
+                line: chunk.lines[offset],
+                target: None,
+            });
+        }
+        offset = end;
+    }
+    instructions
+}
+
+/// The offset of every entry `decode` returns, plus the end of the code.
+fn offsets(chunk: &Chunk) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (_, next) = chunk.read(offset);
+        let end = chunk.next_offset(offset);
+        offsets.push(offset);
+        offsets.extend(next..end);
+        offset = end;
+    }
+    offsets.push(offset);
+    offsets
+}
+
+fn is_unconditional(op: OpCode) -> bool {
+    matches!(op, OpCode::Jump(_) | OpCode::Loop(_))
+}
+
+/// Points every jump whose target is an unconditional jump at that jump's target
+/// instead. A conditional jump only moves forward, as it has no backward form, and no
+/// jump moves further than its two-byte operand reaches.
+fn thread_jumps(instructions: &mut [Instruction], offsets: &[usize]) {
+    for index in 0..instructions.len() {
+        let Some(mut target) = instructions[index].target else {
+            continue;
+        };
+        let conditional = !is_unconditional(instructions[index].op);
+        // A chain longer than the code is a loop of jumps; leave it alone.
+        for _ in 0..instructions.len() {
+            let Some(next_target) = instructions
+                .get(target)
+                .filter(|next| is_unconditional(next.op))
+                .and_then(|next| next.target)
+            else {
+                break;
+            };
+            let distance = offsets[next_target].abs_diff(offsets[index] + 3);
+            if next_target == target
+                || (conditional && next_target <= index)
+                || distance > u16::MAX as usize
+            {
+                break;
+            }
+            target = next_target;
+        }
+        instructions[index].target = Some(target);
+    }
+}
+
+/// Fuses superinstructions and drops `Nop`s. An instruction a jump lands on is never
+/// folded into the one before it.
+fn fuse(instructions: &[Instruction]) -> Vec<Instruction> {
+    let mut is_target = vec![false; instructions.len() + 1];
+    for target in instructions
+        .iter()
+        .filter_map(|instruction| instruction.target)
+    {
+        is_target[target] = true;
+    }
+
+    // Where each old index ends up, so jump targets can follow it.
+    let mut moved_to = vec![0; instructions.len() + 1];
+    let mut fused: Vec<Instruction> = Vec::with_capacity(instructions.len());
+    let mut index = 0;
+    while index < instructions.len() {
+        let instruction = instructions[index];
+        let window3 = window::<3>(instructions, &is_target, index);
+        let window2 = window::<2>(instructions, &is_target, index);
+        let (op, length) = match (window3, window2) {
+            (Some([OpCode::GetLocal(a), OpCode::GetLocal(b), OpCode::Add]), _) => {
+                (Some(OpCode::AddLocals(a, b)), 3)
+            }
+            (_, Some([OpCode::Constant(constant), OpCode::Add]))
+                if constant <= u8::MAX as isize =>
+            {
+                (Some(OpCode::AddConstant(constant)), 2)
+            }
+            (_, Some([OpCode::Equal, OpCode::Not])) => (Some(OpCode::NotEqual), 2),
+            (_, Some([OpCode::SetLocal(slot), OpCode::Pop])) => {
+                (Some(OpCode::SetLocalPop(slot)), 2)
+            }
+            _ if instruction.op == OpCode::Nop => (None, 1),
+            _ => (Some(instruction.op), 1),
+        };
+        moved_to[index..index + length].fill(fused.len());
+        if let Some(op) = op {
+            fused.push(Instruction { op, ..instruction });
+        }
+        index += length;
+    }
+    moved_to[instructions.len()] = fused.len();
+
+    for instruction in &mut fused {
+        instruction.target = instruction.target.map(|target| moved_to[target]);
+    }
+    fused
+}
+
+/// The `N` instructions from `index` on, unless a jump lands inside them.
+fn window<const N: usize>(
+    instructions: &[Instruction],
+    is_target: &[bool],
+    index: usize,
+) -> Option<[OpCode; N]> {
+    let window = instructions.get(index..index + N)?;
+    if is_target[index + 1..index + N].contains(&true) {
+        return None;
+    }
+    Some(std::array::from_fn(|i| window[i].op))
+}
+
+/// Writes `instructions` to `chunk`, choosing `Jump` or `Loop` by each target's
+/// direction and filling in the offsets once every instruction is placed.
+fn encode(chunk: &mut Chunk, instructions: &[Instruction]) {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    for (index, instruction) in instructions.iter().enumerate() {
+        offsets.push(chunk.code.len());
+        let op = match (instruction.op, instruction.target) {
+            (OpCode::Jump(_) | OpCode::Loop(_), Some(target)) if target <= index => OpCode::Loop(0),
+            (OpCode::Jump(_) | OpCode::Loop(_), Some(_)) => OpCode::Jump(0),
+            (op, _) => op,
+        };
+        chunk.write_chunk(op, instruction.line);
+    }
+    offsets.push(chunk.code.len());
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        if let Some(target) = instruction.target {
+            let offset = offsets[index];
+            let jump = offsets[target].abs_diff(offset + 3);
+            chunk.patch_jump(offset, jump as u16);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile_with_options;
+    use crate::object::ObjFunction;
+    use rstest::rstest;
+
+    fn compile(source: &str, opt_level: OptLevel) -> ObjFunction {
+        compile_with_options(source.to_owned(), Default::default(), opt_level).unwrap()
+    }
+
+    #[rstest]
+    #[case("{ var a; var b; print a + b; }", OpCode::AddLocals(1, 2))]
+    #[case("{ var a; print a + 1; }", OpCode::AddConstant(0))]
+    #[case("{ var a; print a != 2; }", OpCode::NotEqual)]
+    #[case("{ var a; a = 1; }", OpCode::SetLocalPop(1))]
+    fn common_sequences_become_superinstructions(#[case] source: &str, #[case] fused: OpCode) {
+        let plain = compile(source, OptLevel::O0).chunk;
+        let optimized = compile(source, OptLevel::O1).chunk;
+        assert!(!plain.instructions().contains(&fused));
+        assert!(optimized.instructions().contains(&fused));
+        assert!(optimized.code.len() < plain.code.len());
+        assert_eq!(optimized.code.len(), optimized.lines.len());
+    }
+
+    #[test]
+    fn jumps_to_jumps_are_threaded() {
+        let source = "var a; if (a) { if (a) print 1; else print 2; } else print 3;";
+        let chunk = compile(source, OptLevel::O1).chunk;
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let (instruction, next) = chunk.read(offset);
+            if let OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) = instruction {
+                let (target, _) = chunk.read(next + jump as usize);
+                assert!(!matches!(target, OpCode::Jump(_)), "jump at {offset}");
+            }
+            offset = chunk.next_offset(offset);
+        }
+    }
+
+    #[test]
+    fn nops_are_dropped_and_jumps_follow_their_targets() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::False, 1);
+        chunk.write_chunk(OpCode::JumpIfFalse(2), 1);
+        chunk.write_chunk(OpCode::Nop, 2);
+        chunk.write_chunk(OpCode::Nop, 2);
+        chunk.write_chunk(OpCode::Nil, 3);
+        chunk.write_chunk(OpCode::Jump(0), 3);
+        chunk.write_chunk(OpCode::Loop(12), 4);
+        optimize(&mut chunk);
+        assert_eq!(
+            chunk.instructions(),
+            vec![
+                OpCode::False,
+                OpCode::JumpIfFalse(0),
+                OpCode::Nil,
+                OpCode::Loop(7),
+                OpCode::Loop(10),
+            ]
+        );
+        assert_eq!(chunk.lines, vec![1, 1, 1, 1, 3, 3, 3, 3, 4, 4, 4]);
+    }
+
+    #[test]
+    fn a_jump_target_is_not_fused_away() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::GetLocal(1), 1);
+        chunk.write_chunk(OpCode::GetLocal(2), 1);
+        chunk.write_chunk(OpCode::Add, 1);
+        chunk.write_chunk(OpCode::Loop(6), 1);
+        let before = chunk.instructions();
+        optimize(&mut chunk);
+        assert_eq!(chunk.instructions(), before);
+    }
+}
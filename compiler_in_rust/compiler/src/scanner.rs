@@ -1,16 +1,28 @@
+use crate::diagnostics::Span;
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[allow(clippy::enum_variant_names)]
 pub enum TokenType {
     TokenLeftParen,
     TokenRightParen,
     TokenLeftBrace,
     TokenRightBrace,
+    TokenLeftBracket,
+    TokenRightBracket,
     TokenComma,
+    TokenColon,
     TokenDot,
     TokenMinus,
     TokenPlus,
     TokenSemicolon,
     TokenSlash,
     TokenStar,
+    TokenStarStar,
+    TokenPercent,
+    TokenAmpersand,
+    TokenPipe,
+    TokenCaret,
+    TokenTilde,
     TokenBang,
     TokenBangEqual,
     TokenEqual,
@@ -19,11 +31,18 @@ pub enum TokenType {
     TokenGreaterEqual,
     TokenLess,
     TokenLessEqual,
+    TokenLessLess,
+    TokenGreaterGreater,
     TokenIdentifier,
     TokenString,
+    /// The part of a string literal up to and including a `${` that starts an
+    /// interpolated expression. The string goes on after the matching `}`.
+    TokenInterpolation,
     TokenNumber,
     TokenAnd,
+    TokenBreak,
     TokenClass,
+    TokenContinue,
     TokenElse,
     TokenFalse,
     TokenFor,
@@ -45,20 +64,49 @@ pub enum TokenType {
     TokenMinusEqual,
     TokenStarEqual,
     TokenSlashEqual,
+    TokenPercentEqual,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
+    /// The line the token starts on.
     pub line: usize,
+    /// 1-based column, counted in characters from the start of the token's line.
+    pub column: usize,
+    pub span: Span,
+    /// An error token for text the input ended partway through, such as an unterminated
+    /// string, which more input could still complete.
+    pub at_end: bool,
 }
+
+impl Token {
+    /// A token that does not come from the source, such as the implicit `this`.
+    pub fn synthetic(token_type: TokenType, lexeme: &str) -> Self {
+        Token {
+            token_type,
+            lexeme: lexeme.to_owned(),
+            line: 0,
+            column: 0,
+            span: Span::default(),
+            at_end: false,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Scanner {
     source: String,
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,
+    start_line: usize,
+    start_column: usize,
+    /// How many interpolated expressions are open. The `}` that ends one goes back
+    /// to scanning its string.
+    interpolations: usize,
 }
 
 impl Scanner {
@@ -68,6 +116,10 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
+            interpolations: 0,
         }
     }
     fn is_at_end(&self) -> bool {
@@ -78,16 +130,40 @@ impl Scanner {
         Token {
             token_type,
             lexeme: lexeme.to_string(),
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
+            at_end: false,
         }
     }
     fn error_token(&self, message: String) -> Token {
         Token {
             token_type: TokenType::TokenError,
             lexeme: message,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
+            at_end: false,
+        }
+    }
+    /// An error token for text that runs into the end of the input.
+    fn unterminated_token(&self, message: String) -> Token {
+        Token {
+            at_end: true,
+            ..self.error_token(message)
         }
     }
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.current,
+        }
+    }
+    fn newline(&mut self) {
+        self.advance();
+        self.line += 1;
+        self.line_start = self.current;
+    }
     fn advance(&mut self) -> char {
         if self.is_at_end() {
             let error_message = format!(
@@ -139,17 +215,10 @@ impl Scanner {
                 ' ' | '\r' | '\t' => {
                     self.advance();
                 }
-                '\n' => {
-                    self.line += 1;
-                    self.advance();
-                }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '\n' => self.newline(),
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => {
@@ -190,7 +259,18 @@ impl Scanner {
 
         match bytes[self.start] {
             b'a' => self.check_keyword(1, 2, "nd", TokenType::TokenAnd),
-            b'c' => self.check_keyword(1, 4, "lass", TokenType::TokenClass),
+            b'b' => self.check_keyword(1, 4, "reak", TokenType::TokenBreak),
+            b'c' => {
+                if self.current - self.start > 1 && self.start + 1 < bytes.len() {
+                    match bytes[self.start + 1] {
+                        b'l' => self.check_keyword(2, 3, "ass", TokenType::TokenClass),
+                        b'o' => self.check_keyword(2, 6, "ntinue", TokenType::TokenContinue),
+                        _ => TokenType::TokenIdentifier,
+                    }
+                } else {
+                    TokenType::TokenIdentifier
+                }
+            }
             b'e' => self.check_keyword(1, 3, "lse", TokenType::TokenElse),
             b'f' => {
                 if self.current - self.start > 1 && self.start + 1 < bytes.len() {
@@ -245,23 +325,60 @@ impl Scanner {
 
         self.make_token(TokenType::TokenNumber)
     }
+    /// Scans the rest of a string literal, from its opening `"` or from the `}` that
+    /// ends an interpolated expression.
     fn string(&mut self) -> Token {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+        let token_type = loop {
+            match self.peek() {
+                _ if self.is_at_end() => {
+                    return self.unterminated_token("Unterminated string.".to_owned());
+                }
+                '"' => break TokenType::TokenString,
+                '$' if self.peek_next() == '{' => {
+                    self.advance();
+                    self.interpolations += 1;
+                    break TokenType::TokenInterpolation;
+                }
+                '\n' => self.newline(),
+                '\\' => {
+                    // The escaped character can't end the string or start an interpolation.
+                    self.advance();
+                    match self.peek() {
+                        '\n' => self.newline(),
+                        _ if self.is_at_end() => {}
+                        _ => {
+                            self.advance();
+                        }
+                    }
+                }
+                _ => {
+                    self.advance();
+                }
             }
-            self.advance();
-        }
-        if self.is_at_end() {
-            return self.error_token("Unterminated string.".to_owned());
-        }
+        };
         self.advance();
-        self.make_token(TokenType::TokenString)
+        match unescape(string_contents(&self.source[self.start..self.current])) {
+            Ok(_) => self.make_token(token_type),
+            Err(message) => self.error_token(message),
+        }
     }
 
+    /// Scans the token after the last one returned without consuming it.
+    pub fn peek_token(&mut self) -> Token {
+        let saved = (self.start, self.current, self.line, self.line_start);
+        let (start_line, start_column) = (self.start_line, self.start_column);
+        let interpolations = self.interpolations;
+        let token = self.scan_token();
+        (self.start, self.current, self.line, self.line_start) = saved;
+        (self.start_line, self.start_column) = (start_line, start_column);
+        self.interpolations = interpolations;
+        token
+    }
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.source[self.line_start..self.start].chars().count() + 1;
         if self.is_at_end() {
             return self.make_token(TokenType::TokenEof);
         }
@@ -269,14 +386,21 @@ impl Scanner {
         if c.is_alphabetic() || c == '_' {
             self.identifier()
         } else if c.is_numeric() {
-            return self.number();
+            self.number()
         } else {
             match c {
                 '(' => self.make_token(TokenType::TokenLeftParen),
                 ')' => self.make_token(TokenType::TokenRightParen),
                 '{' => self.make_token(TokenType::TokenLeftBrace),
+                '}' if self.interpolations > 0 => {
+                    self.interpolations -= 1;
+                    self.string()
+                }
                 '}' => self.make_token(TokenType::TokenRightBrace),
+                '[' => self.make_token(TokenType::TokenLeftBracket),
+                ']' => self.make_token(TokenType::TokenRightBracket),
                 ',' => self.make_token(TokenType::TokenComma),
+                ':' => self.make_token(TokenType::TokenColon),
                 '.' => self.make_token(TokenType::TokenDot),
                 '-' => {
                     if self.match_char('=') {
@@ -303,10 +427,23 @@ impl Scanner {
                 '*' => {
                     if self.match_char('=') {
                         self.make_token(TokenType::TokenStarEqual)
+                    } else if self.match_char('*') {
+                        self.make_token(TokenType::TokenStarStar)
                     } else {
                         self.make_token(TokenType::TokenStar)
                     }
                 }
+                '%' => {
+                    if self.match_char('=') {
+                        self.make_token(TokenType::TokenPercentEqual)
+                    } else {
+                        self.make_token(TokenType::TokenPercent)
+                    }
+                }
+                '&' => self.make_token(TokenType::TokenAmpersand),
+                '|' => self.make_token(TokenType::TokenPipe),
+                '^' => self.make_token(TokenType::TokenCaret),
+                '~' => self.make_token(TokenType::TokenTilde),
                 '!' => {
                     if self.match_char('=') {
                         self.make_token(TokenType::TokenBangEqual)
@@ -324,6 +461,8 @@ impl Scanner {
                 '<' => {
                     if self.match_char('=') {
                         self.make_token(TokenType::TokenLessEqual)
+                    } else if self.match_char('<') {
+                        self.make_token(TokenType::TokenLessLess)
                     } else {
                         self.make_token(TokenType::TokenLess)
                     }
@@ -331,6 +470,8 @@ impl Scanner {
                 '>' => {
                     if self.match_char('=') {
                         self.make_token(TokenType::TokenGreaterEqual)
+                    } else if self.match_char('>') {
+                        self.make_token(TokenType::TokenGreaterGreater)
                     } else {
                         self.make_token(TokenType::TokenGreater)
                     }
@@ -343,9 +484,64 @@ impl Scanner {
     }
 }
 
+/// The text of a string token between its delimiters: `"` or `}` before it, and
+/// `"` or `${` after it.
+pub fn string_contents(lexeme: &str) -> &str {
+    let text = &lexeme[1..];
+    text.strip_suffix("${")
+        .or_else(|| text.strip_suffix('"'))
+        .unwrap_or(text)
+}
+
+/// Replaces the escape sequences in the text of a string literal with the
+/// characters they stand for.
+pub fn unescape(text: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('$') => '$',
+            Some('u') => unicode_escape(&mut chars)?,
+            Some(c) => return Err(format!("Unknown escape sequence '\\{c}'.")),
+            None => return Err("Unterminated escape sequence.".to_owned()),
+        };
+        result.push(escaped);
+    }
+    Ok(result)
+}
+
+/// Decodes the `{...}` of a `\u{...}` escape: one to six hex digits naming a character.
+fn unicode_escape(chars: &mut std::str::Chars) -> Result<char, String> {
+    let invalid = || "Invalid unicode escape sequence.".to_owned();
+    if chars.next() != Some('{') {
+        return Err(invalid());
+    }
+    let mut digits = String::new();
+    loop {
+        match chars.next() {
+            Some('}') if !digits.is_empty() => break,
+            Some(c) if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+            _ => return Err(invalid()),
+        }
+    }
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(invalid)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rstest::rstest;
 
     fn scan(source: String) -> Vec<Token> {
         let mut scanner = Scanner::new(source);
@@ -371,15 +567,18 @@ mod tests {
 
     #[test]
     fn test_single_character_tokens() {
-        let source = "{ } ( ) , . - + ; / *".to_owned();
+        let source = "{ } [ ] ( ) , : . - + ; / *".to_owned();
         let tokens = scan(source);
 
         let expected = vec![
             TokenType::TokenLeftBrace,
             TokenType::TokenRightBrace,
+            TokenType::TokenLeftBracket,
+            TokenType::TokenRightBracket,
             TokenType::TokenLeftParen,
             TokenType::TokenRightParen,
             TokenType::TokenComma,
+            TokenType::TokenColon,
             TokenType::TokenDot,
             TokenType::TokenMinus,
             TokenType::TokenPlus,
@@ -394,7 +593,7 @@ mod tests {
 
     #[test]
     fn test_one_or_two_char_tokens() {
-        let source = "! != = == > >= < <=".to_owned();
+        let source = "! != = == > >= >> < <= << * ** % & | ^ ~".to_owned();
         let tokens = scan(source);
 
         let expected = vec![
@@ -404,8 +603,17 @@ mod tests {
             TokenType::TokenEqualEqual,
             TokenType::TokenGreater,
             TokenType::TokenGreaterEqual,
+            TokenType::TokenGreaterGreater,
             TokenType::TokenLess,
             TokenType::TokenLessEqual,
+            TokenType::TokenLessLess,
+            TokenType::TokenStar,
+            TokenType::TokenStarStar,
+            TokenType::TokenPercent,
+            TokenType::TokenAmpersand,
+            TokenType::TokenPipe,
+            TokenType::TokenCaret,
+            TokenType::TokenTilde,
             TokenType::TokenEof,
         ];
 
@@ -415,13 +623,15 @@ mod tests {
     #[test]
     fn test_keywords_and_identifiers() {
         let source =
-            "and class else false for fun if nil or print return super this true var while foo bar"
-                .to_owned();
+        "and break class continue else false for fun if nil or print return super this true var while foo bar c cont"
+            .to_owned();
         let tokens = scan(source);
 
         let expected = vec![
             TokenType::TokenAnd,
+            TokenType::TokenBreak,
             TokenType::TokenClass,
+            TokenType::TokenContinue,
             TokenType::TokenElse,
             TokenType::TokenFalse,
             TokenType::TokenFor,
@@ -438,14 +648,16 @@ mod tests {
             TokenType::TokenWhile,
             TokenType::TokenIdentifier, // foo
             TokenType::TokenIdentifier, // bar
+            TokenType::TokenIdentifier, // c
+            TokenType::TokenIdentifier, // cont
             TokenType::TokenEof,
         ];
 
         assert_eq!(token_types(&tokens), expected);
 
         let lex = lexemes(&tokens);
-        assert_eq!(lex[lex.len() - 3], "foo");
-        assert_eq!(lex[lex.len() - 2], "bar");
+        assert_eq!(lex[lex.len() - 5], "foo");
+        assert_eq!(lex[lex.len() - 4], "bar");
     }
 
     #[test]
@@ -482,6 +694,78 @@ mod tests {
         assert_eq!(tokens[0].lexeme, "\"hello\"");
         assert_eq!(tokens[1].lexeme, "\"\"");
         assert_eq!(tokens[3].lexeme, "Unterminated string.");
+        assert!(tokens[3].at_end);
+        assert!(!tokens[2].at_end);
+    }
+
+    #[test]
+    fn escaped_quotes_do_not_end_a_string() {
+        let tokens = scan(r#""say \"hi\"" "\\""#.to_owned());
+        assert_eq!(
+            token_types(&tokens),
+            vec![
+                TokenType::TokenString,
+                TokenType::TokenString,
+                TokenType::TokenEof
+            ]
+        );
+        assert_eq!(tokens[0].lexeme, r#""say \"hi\"""#);
+        assert_eq!(tokens[1].lexeme, r#""\\""#);
+    }
+
+    #[test]
+    fn interpolations_split_a_string() {
+        let tokens = scan(r#""a${x}b${"c${y}"}d" "\${z}""#.to_owned());
+        let expected = vec![
+            TokenType::TokenInterpolation,
+            TokenType::TokenIdentifier,
+            TokenType::TokenInterpolation,
+            TokenType::TokenInterpolation,
+            TokenType::TokenIdentifier,
+            TokenType::TokenString,
+            TokenType::TokenString,
+            TokenType::TokenString,
+            TokenType::TokenEof,
+        ];
+        assert_eq!(token_types(&tokens), expected);
+        assert_eq!(
+            lexemes(&tokens)[..8],
+            [
+                r#""a${"#,
+                "x",
+                "}b${",
+                r#""c${"#,
+                "y",
+                r#"}""#,
+                r#"}d""#,
+                r#""\${z}""#
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(r#""\q""#, r"Unknown escape sequence '\q'.")]
+    #[case(r#""\u{}""#, "Invalid unicode escape sequence.")]
+    #[case(r#""\u{D800}""#, "Invalid unicode escape sequence.")]
+    #[case(r#""\u{1234567}""#, "Invalid unicode escape sequence.")]
+    #[case(r#""\u41""#, "Invalid unicode escape sequence.")]
+    #[case(r#""${x}\q""#, r"Unknown escape sequence '\q'.")]
+    fn invalid_escapes_are_errors(#[case] source: &str, #[case] message: &str) {
+        let tokens = scan(source.to_owned());
+        let error = tokens
+            .iter()
+            .find(|token| token.token_type == TokenType::TokenError)
+            .expect("an error token");
+        assert_eq!(error.lexeme, message);
+    }
+
+    #[rstest]
+    #[case("plain", "plain")]
+    #[case(r"a\nb\tc", "a\nb\tc")]
+    #[case(r#"\"\\\$"#, "\"\\$")]
+    #[case(r"\u{48}\u{e9}\u{1F600}", "H\u{e9}\u{1F600}")]
+    fn escapes_are_replaced(#[case] text: &str, #[case] expected: &str) {
+        assert_eq!(unescape(text).as_deref(), Ok(expected));
     }
 
     #[test]
@@ -538,6 +822,32 @@ mod tests {
         assert_eq!(lines, expected_lines);
     }
 
+    #[test]
+    fn test_columns_and_spans() {
+        let source = "var a = 1;\n  print \"two\nlines\" a;".to_owned();
+        let tokens = scan(source.clone());
+        let positions: Vec<(usize, usize)> = tokens.iter().map(|t| (t.line, t.column)).collect();
+
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (1, 10),
+                (2, 3),
+                (2, 9),
+                (3, 8),
+                (3, 9),
+                (3, 10)
+            ]
+        );
+        for token in &tokens[..tokens.len() - 1] {
+            assert_eq!(&source[token.span.start..token.span.end], token.lexeme);
+        }
+    }
+
     #[test]
     fn test_unexpected_character() {
         let source = "@ # $".to_owned();
@@ -569,7 +879,7 @@ mod tests {
     }
     #[test]
     fn test_compound_assignment_tokens() {
-        let source = "x += 2; y -= 3; z *= 4; w /= 5;".to_owned();
+        let source = "x += 2; y -= 3; z *= 4; w /= 5; v %= 6;".to_owned();
         let tokens = scan(source);
         let types = token_types(&tokens);
         let lexemes = lexemes(&tokens);
@@ -591,6 +901,10 @@ mod tests {
             TokenType::TokenSlashEqual, // /=
             TokenType::TokenNumber,     // 5
             TokenType::TokenSemicolon,
+            TokenType::TokenIdentifier,   // v
+            TokenType::TokenPercentEqual, // %=
+            TokenType::TokenNumber,       // 6
+            TokenType::TokenSemicolon,
             TokenType::TokenEof,
         ];
 
@@ -599,5 +913,6 @@ mod tests {
         assert_eq!(lexemes[5], "-=");
         assert_eq!(lexemes[9], "*=");
         assert_eq!(lexemes[13], "/=");
+        assert_eq!(lexemes[17], "%=");
     }
 }
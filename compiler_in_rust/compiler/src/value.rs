@@ -1,7 +1,11 @@
 use crate::object::{Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjNative, ObjString};
-use std::{cell::RefCell, fmt, rc::Rc};
+use std::{cell::RefCell, fmt, ptr, rc::Rc};
 
-#[derive(Clone, PartialEq)]
+/// A Lox value: a tagged enum, or with the `nan-boxing` feature a single `u64`.
+/// Code outside this module goes through the `is_*` and `as_*` methods, which both
+/// representations provide.
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone)]
 pub enum Value {
     Bool(bool),
     Nil,
@@ -10,6 +14,7 @@ pub enum Value {
 }
 
 /* ==== Value impl ==== */
+#[cfg(not(feature = "nan-boxing"))]
 impl Value {
     pub fn is_bool(&self) -> bool {
         matches!(self, Value::Bool(_))
@@ -48,11 +53,149 @@ impl Value {
         }
     }
 
+    /// The object this value refers to, without touching its reference count.
+    pub fn obj(&self) -> Option<&RefCell<Obj>> {
+        match self {
+            Value::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+pub use nan_boxing::Value;
+
+/// The book's NaN boxing. Numbers are stored as their own bits. Every other value is a
+/// quiet NaN: `nil`, `false` and `true` set a small tag in the low bits, and objects
+/// set the sign bit and keep the `Rc` pointer in the low 48 bits.
+#[cfg(feature = "nan-boxing")]
+mod nan_boxing {
+    use crate::object::Obj;
+    use std::cell::RefCell;
+    use std::marker::PhantomData;
+    use std::rc::Rc;
+
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+    const NIL_VAL: u64 = QNAN | TAG_NIL;
+    const FALSE_VAL: u64 = QNAN | TAG_FALSE;
+    const TRUE_VAL: u64 = QNAN | TAG_TRUE;
+    const OBJ_TAG: u64 = SIGN_BIT | QNAN;
+
+    /// An object value owns one strong count of its `Rc`, like the enum's `Obj` does.
+    pub struct Value {
+        bits: u64,
+        /// Keeps the value `!Send` and `!Sync`, as the `Rc` it may hold is.
+        _obj: PhantomData<Rc<RefCell<Obj>>>,
+    }
+
+    // The enum's variant names, kept as constructors so both representations build
+    // values the same way.
+    #[allow(non_snake_case, non_upper_case_globals)]
+    impl Value {
+        pub const Nil: Value = Value::from_bits(NIL_VAL);
+
+        pub fn Bool(value: bool) -> Value {
+            Value::from_bits(if value { TRUE_VAL } else { FALSE_VAL })
+        }
+
+        pub fn Number(value: f64) -> Value {
+            // A NaN with payload bits could look like a tagged value; store the plain one.
+            let value = if value.is_nan() { f64::NAN } else { value };
+            Value::from_bits(value.to_bits())
+        }
+
+        pub fn Obj(obj: Rc<RefCell<Obj>>) -> Value {
+            let pointer = Rc::into_raw(obj) as u64;
+            debug_assert_eq!(pointer & OBJ_TAG, 0, "object pointer wider than 48 bits");
+            Value::from_bits(OBJ_TAG | pointer)
+        }
+    }
+
+    impl Value {
+        const fn from_bits(bits: u64) -> Value {
+            Value {
+                bits,
+                _obj: PhantomData,
+            }
+        }
+
+        fn pointer(&self) -> *const RefCell<Obj> {
+            (self.bits & !OBJ_TAG) as *const RefCell<Obj>
+        }
+
+        pub fn is_bool(&self) -> bool {
+            self.bits | 1 == TRUE_VAL
+        }
+
+        pub fn is_nil(&self) -> bool {
+            self.bits == NIL_VAL
+        }
+
+        pub fn is_number(&self) -> bool {
+            self.bits & QNAN != QNAN
+        }
+
+        pub fn is_obj(&self) -> bool {
+            self.bits & OBJ_TAG == OBJ_TAG
+        }
+
+        pub fn as_bool(&self) -> bool {
+            assert!(self.is_bool(), "Expected bool");
+            self.bits == TRUE_VAL
+        }
+
+        pub fn as_number(&self) -> f64 {
+            assert!(self.is_number(), "Expected number");
+            f64::from_bits(self.bits)
+        }
+
+        pub fn as_obj(&self) -> Rc<RefCell<Obj>> {
+            assert!(self.is_obj(), "Expected object");
+            // SAFETY: the pointer came from `Rc::into_raw` and this value still owns a
+            // count, so adding one for the returned `Rc` keeps the object alive.
+            unsafe {
+                Rc::increment_strong_count(self.pointer());
+                Rc::from_raw(self.pointer())
+            }
+        }
+
+        /// The object this value refers to, without touching its reference count.
+        pub fn obj(&self) -> Option<&RefCell<Obj>> {
+            // SAFETY: the count this value owns keeps the object alive while borrowed.
+            self.is_obj().then(|| unsafe { &*self.pointer() })
+        }
+    }
+
+    impl Clone for Value {
+        fn clone(&self) -> Self {
+            if self.is_obj() {
+                // SAFETY: see `as_obj`; the clone owns the new count.
+                unsafe { Rc::increment_strong_count(self.pointer()) };
+            }
+            Value::from_bits(self.bits)
+        }
+    }
+
+    impl Drop for Value {
+        fn drop(&mut self) {
+            if self.is_obj() {
+                // SAFETY: gives back the count this value owns.
+                unsafe { Rc::decrement_strong_count(self.pointer()) };
+            }
+        }
+    }
+}
+
+impl Value {
     pub fn as_string(&self) -> Rc<ObjString> {
         let obj = self.as_obj();
         match &*obj.borrow() {
-            Obj::String(string) => Rc::new(string.clone()),
-            _ => panic!("Value is not a closure"),
+            Obj::String(string) => Rc::clone(string),
+            _ => panic!("Value is not a string"),
         }
     }
 
@@ -60,7 +203,7 @@ impl Value {
         let obj = self.as_obj();
 
         match &*obj.borrow() {
-            Obj::Closure(closure) => Rc::new(closure.clone()),
+            Obj::Closure(closure) => Rc::clone(closure),
             _ => panic!("Value is not a closure"),
         }
     }
@@ -91,20 +234,42 @@ impl Value {
     pub fn as_function(&self) -> Rc<ObjFunction> {
         let obj = self.as_obj();
         match &*obj.borrow() {
-            Obj::Function(function) => Rc::new(function.clone()),
+            Obj::Function(function) => Rc::clone(function),
             _ => panic!("Value is not a function"),
         }
     }
+}
 
-    pub fn print(&self) {
-        match self {
-            Value::Bool(b) => println!("{}", if *b { "true" } else { "false" }),
-            Value::Nil => println!("nil"),
-            Value::Number(n) => println!("{n}"),
-            Value::Obj(obj) => {
-                let obj = obj.borrow();
-                obj.print();
+/* ==== Equality ==== */
+/// Objects are equal only to themselves; strings are interned, so equal text means
+/// the same object.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        if self.is_number() && other.is_number() {
+            return self.as_number() == other.as_number();
+        }
+        match (self.obj(), other.obj()) {
+            (Some(a), Some(b)) => ptr::eq(a, b),
+            (None, None) => {
+                (self.is_nil() && other.is_nil())
+                    || (self.is_bool() && other.is_bool() && self.as_bool() == other.as_bool())
             }
+            _ => false,
+        }
+    }
+}
+
+/* ==== Display ==== */
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(obj) = self.obj() {
+            write!(f, "{}", obj.borrow())
+        } else if self.is_bool() {
+            write!(f, "{}", self.as_bool())
+        } else if self.is_number() {
+            write!(f, "{}", self.as_number())
+        } else {
+            write!(f, "nil")
         }
     }
 }
@@ -112,11 +277,14 @@ impl Value {
 /* ==== Debug ==== */
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Value::Bool(v) => write!(f, "Bool({})", v),
-            Value::Nil => write!(f, "Nil"),
-            Value::Number(n) => write!(f, "Number({})", n),
-            Value::Obj(_) => write!(f, "Obj(...)"),
+        if self.is_obj() {
+            write!(f, "Obj(...)")
+        } else if self.is_bool() {
+            write!(f, "Bool({})", self.as_bool())
+        } else if self.is_number() {
+            write!(f, "Number({})", self.as_number())
+        } else {
+            write!(f, "Nil")
         }
     }
 }
@@ -138,6 +306,28 @@ pub fn obj_val(obj: Obj) -> Value {
     Value::Obj(Rc::new(RefCell::new(obj)))
 }
 
+/* ==== Integers ==== */
+/// `n` as an integer for the bitwise operators, if it is a whole number that fits.
+pub fn to_integer(n: f64) -> Option<i64> {
+    // -2^63 converts exactly; 2^63 is the first value past `i64::MAX`.
+    let limit = -(i64::MIN as f64);
+    (n.fract() == 0.0 && (-limit..limit).contains(&n)).then_some(n as i64)
+}
+
+/// `a << count`. Bits shifted past the top are lost, and a negative count is `None`.
+pub fn shift_left(a: i64, count: i64) -> Option<i64> {
+    match count {
+        ..0 => None,
+        0..64 => Some(a << count),
+        _ => Some(0),
+    }
+}
+
+/// `a >> count`, keeping the sign. A negative count is `None`.
+pub fn shift_right(a: i64, count: i64) -> Option<i64> {
+    (count >= 0).then(|| a >> count.min(63))
+}
+
 /* ==== ValueArray ==== */
 #[derive(Debug, Clone)]
 pub struct ValueArray {
@@ -165,3 +355,62 @@ impl Default for ValueArray {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(Value::Nil, "nil")]
+    #[case(Value::Bool(true), "true")]
+    #[case(Value::Bool(false), "false")]
+    #[case(Value::Number(-0.5), "-0.5")]
+    #[case(Value::Number(f64::INFINITY), "inf")]
+    #[case(Value::Number(f64::NAN), "NaN")]
+    fn immediates_keep_their_kind(#[case] value: Value, #[case] shown: &str) {
+        let kinds = [
+            value.is_nil(),
+            value.is_bool(),
+            value.is_number(),
+            value.is_obj(),
+        ];
+        assert_eq!(kinds.iter().filter(|&&kind| kind).count(), 1);
+        assert_eq!(value.to_string(), shown);
+        assert_eq!(value.clone() == value, shown != "NaN");
+    }
+
+    #[rstest]
+    #[case(3.0, Some(3))]
+    #[case(-0.0, Some(0))]
+    #[case(-9223372036854775808.0, Some(i64::MIN))]
+    #[case(9223372036854775808.0, None)]
+    #[case(0.5, None)]
+    #[case(f64::INFINITY, None)]
+    #[case(f64::NAN, None)]
+    fn only_whole_numbers_are_integers(#[case] n: f64, #[case] integer: Option<i64>) {
+        assert_eq!(to_integer(n), integer);
+    }
+
+    #[test]
+    fn objects_count_their_references() {
+        let obj = Rc::new(RefCell::new(Obj::String(Rc::new(
+            ObjString::copy_from_str("s"),
+        ))));
+        let value = Value::Obj(Rc::clone(&obj));
+        assert!(value.is_obj() && !value.is_number());
+        assert!(ptr::eq(value.obj().unwrap(), &*obj));
+
+        let copy = value.clone();
+        assert_eq!(Rc::strong_count(&obj), 3);
+        assert_eq!(copy, value);
+        drop((copy, value));
+        assert_eq!(Rc::strong_count(&obj), 1);
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn nan_boxed_values_are_eight_bytes() {
+        assert_eq!(size_of::<Value>(), 8);
+    }
+}
@@ -1,41 +1,182 @@
 use crate::chunks::OpCode;
+use crate::compiler::{Compiler, FunctionType};
+use crate::debug::{self, TraceFormat};
+use crate::diagnostics::Diagnostic;
+use crate::globals::GlobalNames;
+use crate::interner::Interner;
+use crate::memory::{GcStats, Heap, Marker};
 use crate::object::{
-    NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjNative, ObjString,
-    ObjUpvalue,
+    BuiltinFn, InlineCache, MapKey, NativeFn, NativeFunction, Obj, ObjBoundMethod, ObjClass,
+    ObjClosure, ObjFunction, ObjInstance, ObjList, ObjMap, ObjNative, ObjString, ObjUpvalue,
+    Property, Table,
 };
-use crate::value::{Value, obj_val};
+use crate::optimizer::OptLevel;
+use crate::value::{Value, obj_val, shift_left, shift_right, to_integer};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
 use std::rc::Rc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Runs a method of a built-in type on the receiver under its arguments.
+type BuiltinMethod = fn(&mut VM, &RefCell<Obj>, &ObjString, usize) -> Result<(), RuntimeError>;
+
+/// Why a script failed: it did not compile, or it raised an error while running.
+#[derive(Debug, PartialEq)]
+pub enum InterpretError {
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// An operand had the wrong type, e.g. `nil + 1` or a property access on a number.
+    TypeError,
+    UndefinedVariable,
+    UndefinedProperty,
+    ArityMismatch,
+    NotCallable,
+    StackOverflow,
+    /// A list was indexed outside its bounds.
+    IndexOutOfRange,
+    /// A map was read at a key it does not hold.
+    UndefinedKey,
+}
+/// One active call at the moment a runtime error was raised.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub function: String,
+    pub line: usize,
+    /// Index of the failing instruction in the function's chunk.
+    pub ip: usize,
+}
+/// A runtime error with the call stack at the point it was raised, innermost frame first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub kind: RuntimeErrorKind,
+    pub frames: Vec<Frame>,
+}
+
+/// How many frames are shown at each end of a long stack trace.
+const TRACE_EDGE: usize = 10;
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        let omitted = self.frames.len().saturating_sub(2 * TRACE_EDGE);
+        for (index, frame) in self.frames.iter().enumerate() {
+            if omitted > 0 && index == TRACE_EDGE {
+                write!(f, "\n... {omitted} more frames ...")?;
+            }
+            if omitted > 0 && (TRACE_EDGE..TRACE_EDGE + omitted).contains(&index) {
+                continue;
+            }
+            write!(f, "\n[line {}] in {}", frame.line, frame.function)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Outcome of feeding one chunk of REPL input to [`VM::interpret_repl`].
 #[derive(Debug, PartialEq)]
-pub enum InterpretResult {
-    InterpretOk,
-    InterpretCompileError,
-    InterpretRuntimeError,
+pub enum ReplResult {
+    Done(Result<(), InterpretError>),
+    /// The source ended in the middle of a declaration; more lines are needed.
+    Incomplete,
 }
+/// An in-memory output sink whose clones share one buffer, so a caller can hand
+/// one clone to the VM and read what the script printed through another.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Limits on how deep a script may go before it fails with "Stack overflow.".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VMConfig {
+    /// The most calls that may be active at once, the script itself included.
+    pub max_frames: usize,
+    /// The most values the stack may hold.
+    pub max_stack: usize,
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        VMConfig {
+            max_frames: 1 << 14,
+            max_stack: 1 << 20,
+        }
+    }
+}
+
 pub struct VM {
+    config: VMConfig,
     call_frames: Vec<CallFrame>,
+    /// Grows as values are pushed; `run_loop` keeps it within `config.max_stack`.
     stack: Vec<Value>,
-    stack_top: usize,
-    globals: HashMap<String, Value>,
-    strings: HashMap<String, Value>,
-    init_string: ObjString,
+    /// Global values by slot; `None` marks a slot that is named but not yet defined.
+    globals: Vec<Option<Value>>,
+    global_names: Rc<RefCell<GlobalNames>>,
+    strings: Rc<RefCell<Interner>>,
+    init_string: Rc<ObjString>,
     open_upvalues: Vec<Rc<RefCell<ObjUpvalue>>>,
-    bytes_allocated: usize,
-    next_gc: usize,
-    objects: Vec<Obj>,
-    gray_count: usize,
-    gray_capacity: usize,
-    gray_stack: Vec<Obj>,
+    heap: Heap,
+    opt_level: OptLevel,
+    trace: Option<TraceFormat>,
+    out: Box<dyn Write>,
+    err: Box<dyn Write>,
+    /// Arguments forwarded to the script, read by `argc()` and `argv(i)`.
+    args: Vec<String>,
+    render_diagnostics: bool,
 }
 pub struct CallFrame {
-    pub closure: Rc<RefCell<ObjClosure>>,
+    pub closure: Rc<ObjClosure>,
     pub ip: usize,
     pub slot_start: usize,
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resolves a property name through `cache`, refilling it from the class on a miss.
+fn resolve_property(
+    cache: &RefCell<InlineCache>,
+    instance: &ObjInstance,
+    name: &Value,
+) -> Option<Property> {
+    if let Some(property) = cache.borrow().lookup(instance) {
+        return Some(property);
+    }
+    cache.borrow_mut().fill(&instance.klass, &name.as_string());
+    cache.borrow().lookup(instance)
+}
+
 pub fn clock_native(_: usize, _: &[Value]) -> Value {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -45,224 +186,541 @@ pub fn clock_native(_: usize, _: &[Value]) -> Value {
     Value::Number(now)
 }
 
+fn argc_native(vm: &mut VM, _: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(vm.args.len() as f64))
+}
+
+fn map_native(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args.is_empty() {
+        return Err(vm.runtime_error(
+            RuntimeErrorKind::ArityMismatch,
+            format!("Expected 0 arguments but got {}.", args.len()),
+        ));
+    }
+    Ok(vm.alloc(Obj::Map(ObjMap::new())))
+}
+
+fn argv_native(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.len() != 1 || !args[0].is_number() {
+        return Ok(Value::Nil);
+    }
+    let index = args[0].as_number();
+    if index < 0.0 || index.fract() != 0.0 || index as usize >= vm.args.len() {
+        return Ok(Value::Nil);
+    }
+    let arg = vm.args[index as usize].clone();
+    Ok(vm.intern(arg))
+}
+
 impl VM {
     pub fn new() -> Self {
+        Self::with_config(VMConfig::default())
+    }
+    pub fn with_config(config: VMConfig) -> Self {
+        let strings = Rc::new(RefCell::new(Interner::new()));
+        let init_string = strings.borrow_mut().intern("init").as_string();
         let mut vm = VM {
+            config,
             call_frames: vec![],
-            stack: vec![Value::Nil; 1024],
-            stack_top: 0,
-            globals: Default::default(),
-            strings: Default::default(),
-            init_string: ObjString::from_string("init".to_owned()),
+            stack: Vec::new(),
+            globals: Vec::new(),
+            global_names: Default::default(),
+            strings,
+            init_string,
             open_upvalues: vec![],
-            bytes_allocated: 0,
-            next_gc: 0,
-            objects: vec![],
-            gray_count: 0,
-            gray_capacity: 0,
-            gray_stack: vec![],
+            heap: Heap::new(),
+            trace: None,
+            opt_level: OptLevel::default(),
+            out: Box::new(BufWriter::new(io::stdout())),
+            err: Box::new(io::stderr()),
+            args: Vec::new(),
+            render_diagnostics: false,
         };
         vm.define_native("clock", clock_native);
+        vm.define_builtin("argc", argc_native);
+        vm.define_builtin("argv", argv_native);
+        vm.define_builtin("Map", map_native);
         vm
     }
-    pub fn push(&mut self, value: Value) {
-        self.stack[self.stack_top] = value;
-        self.stack_top += 1;
+    /// Sends the output of `print` statements to `out` instead of stdout.
+    pub fn set_output(&mut self, out: impl Write + 'static) {
+        self.out = Box::new(out);
     }
-    pub fn pop(&mut self) -> Value {
-        if self.stack_top == 0 {
-            panic!("Stack underflow");
+    /// Sends compile errors, runtime errors and traces to `err` instead of stderr.
+    pub fn set_error_output(&mut self, err: impl Write + 'static) {
+        self.err = Box::new(err);
+    }
+    /// Reports compile errors with their source line and a caret, as [`Diagnostic::render`]
+    /// does, instead of the one-line clox form.
+    pub fn set_render_diagnostics(&mut self, render: bool) {
+        self.render_diagnostics = render;
+    }
+    /// Prints every instruction and the value stack to stderr before it is dispatched.
+    pub fn set_trace(&mut self, trace: Option<TraceFormat>) {
+        self.trace = trace;
+    }
+    /// Optimizes the code of later `interpret` calls at `opt_level`.
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+    /// Collects garbage before every instruction that follows an allocation.
+    pub fn set_stress_gc(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+    /// Frees the objects that cannot be reached from the stack, the call frames,
+    /// the globals or the open upvalues, including ones that only refer to each other.
+    /// Values the embedder still holds are kept intact, along with everything they reach.
+    pub fn collect_garbage(&mut self) {
+        let mut marker = Marker::new();
+        for value in &self.stack {
+            marker.mark_value(value);
+        }
+        for frame in &self.call_frames {
+            marker.mark_closure(&frame.closure);
         }
-        self.stack_top -= 1;
-        self.stack[self.stack_top].clone()
+        for value in self.globals.iter().flatten() {
+            marker.mark_value(value);
+        }
+        for upvalue in &self.open_upvalues {
+            marker.mark_upvalue(upvalue);
+        }
+        self.heap.collect(marker, &mut self.strings.borrow_mut());
     }
-    fn peek(&mut self, distance: usize) -> Value {
-        let index = self.stack_top as isize - 1 - distance as isize;
-        if index < 0 {
-            println!(
-                "DEBUG: peek index out of bounds: distance={} stack_top={} stack_len={}",
-                distance,
-                self.stack_top,
-                self.stack.len()
-            );
-            panic!("Peek index out of bounds");
+    fn alloc(&mut self, obj: Obj) -> Value {
+        let value = obj_val(obj);
+        self.heap.track(&value);
+        value
+    }
+    /// Interns `text`, tracking the string if it was not interned yet.
+    fn intern(&mut self, text: String) -> Value {
+        let mut strings = self.strings.borrow_mut();
+        let count = strings.len();
+        let value = strings.intern_owned(text);
+        if strings.len() > count {
+            self.heap.track(&value);
         }
-        self.stack[index as usize].to_owned()
-    }
-    fn print_stack(&self) {
-        println!("################");
-        println!("Stack (bottom -> top)");
-        for (i, v) in self.stack.iter().enumerate() {
-            if i >= self.stack_top {
-                break;
-            }
-            print!("{i}: ");
-            v.print();
-            println!();
+        value
+    }
+    /// Swaps a string made outside the VM for its interned copy; see [`Interner::canonical`].
+    fn canonical(&mut self, value: Value) -> Value {
+        let mut strings = self.strings.borrow_mut();
+        let count = strings.len();
+        let value = strings.canonical(value);
+        if strings.len() > count {
+            self.heap.track(&value);
         }
-        println!("################");
+        value
+    }
+    /// Arguments forwarded to the script, readable through `argc()` and `argv(i)`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+    pub fn pop(&mut self) -> Value {
+        self.stack.pop().expect("Stack underflow")
+    }
+    fn peek(&mut self, distance: usize) -> Value {
+        let len = self.stack.len();
+        assert!(
+            distance < len,
+            "peek index out of bounds: distance {distance} with {len} values on the stack"
+        );
+        self.stack[len - 1 - distance].to_owned()
     }
     fn reset_stack(&mut self) {
-        self.stack_top = 0;
-        self.stack = vec![Value::Nil; 1024];
+        self.call_frames.clear();
+        self.stack.clear();
         self.open_upvalues = vec![];
     }
-    fn runtime_error(&mut self, msg: String) {
-        eprintln!("{msg}");
-
-        for frame in self.call_frames.iter().rev() {
-            let closure = frame.closure.borrow();
-            let function = &closure.function;
-
-            let instruction_idx = frame.ip.saturating_sub(1);
-            let line = function.chunk.lines[instruction_idx];
-            eprint!("[line {line}] in ");
+    fn runtime_error(&self, kind: RuntimeErrorKind, message: String) -> RuntimeError {
+        let frames = self
+            .call_frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = &frame.closure.function;
+                let ip = function.chunk.instruction_start(frame.ip.saturating_sub(1));
+                Frame {
+                    function: if function.name.is_empty() {
+                        "<script>".to_owned()
+                    } else {
+                        function.name.clone()
+                    },
+                    line: function.chunk.lines[ip],
+                    ip,
+                }
+            })
+            .collect();
 
-            if function.name.is_empty() {
-                eprintln!("<script>");
-            } else {
-                eprintln!("{}", function.name);
-            }
+        RuntimeError {
+            message,
+            kind,
+            frames,
         }
-
-        self.reset_stack();
     }
-    fn runtime_error_fmt(&mut self, fmt: &str, args: std::fmt::Arguments<'_>) {
-        self.runtime_error(format!("{fmt}{args}"));
+    /// Writes to the error sink after flushing program output, so the two stay in order.
+    fn report_error(&mut self, report: &str) {
+        let _ = self.out.flush();
+        let _ = self.err.write_all(report.as_bytes());
+        let _ = self.err.flush();
     }
-    fn define_native(&mut self, name: &str, function: NativeFn) {
-        let name_obj = obj_val(Obj::String(ObjString::copy_from_str(name)));
-        let native_obj = obj_val(Obj::Native(ObjNative::new(function)));
-
-        self.push(name_obj.clone());
-        self.push(native_obj.clone());
-
-        let key = match &*name_obj.as_obj().borrow() {
-            Obj::String(s) => s.data.clone(),
-            _ => panic!("Expected string as key"),
-        };
-
-        self.globals.insert(key, native_obj);
-
-        self.pop();
-        self.pop();
+    /// Binds `function` to the global `name`, replacing any previous definition.
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
+        let native = self.alloc(Obj::Native(ObjNative::new(function)));
+        self.set_global(name, native);
+    }
+    fn define_builtin(&mut self, name: &str, function: BuiltinFn) {
+        let native = self.alloc(Obj::Native(ObjNative::builtin(function)));
+        self.set_global(name, native);
+    }
+    /// The value of the global `name`, or `None` if it has not been defined.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let slot = self.global_names.borrow().get(name)?;
+        self.globals.get(slot).cloned().flatten()
     }
-    fn call(&mut self, closure: Rc<RefCell<ObjClosure>>, arg_count: usize) -> bool {
-        let _closure = closure.borrow();
-        if arg_count != _closure.function.arity {
-            self.runtime_error(format!(
-                "Expected {} arguments but got {}.",
-                _closure.function.arity, arg_count
+    /// Defines or replaces the global `name`.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let slot = self.global_names.borrow_mut().slot(name);
+        let value = self.canonical(value);
+        self.define_global(slot, value);
+    }
+    fn define_global(&mut self, slot: usize, value: Value) {
+        if slot >= self.globals.len() {
+            self.globals.resize(slot + 1, None);
+        }
+        self.globals[slot] = Some(value);
+    }
+    fn undefined_variable(&self, slot: usize) -> RuntimeError {
+        let message = format!(
+            "Undefined variable '{}'.",
+            self.global_names.borrow().name(slot)
+        );
+        self.runtime_error(RuntimeErrorKind::UndefinedVariable, message)
+    }
+    fn call(&mut self, closure: Rc<ObjClosure>, arg_count: usize) -> Result<(), RuntimeError> {
+        if arg_count != closure.function.arity {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::ArityMismatch,
+                format!(
+                    "Expected {} arguments but got {}.",
+                    closure.function.arity, arg_count
+                ),
             ));
-            return false;
         }
 
-        if self.call_frames.len() >= 64 {
-            self.runtime_error("Stack overflow.".to_string());
-            return false;
+        if self.call_frames.len() >= self.config.max_frames
+            || self.stack.len() > self.config.max_stack
+        {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::StackOverflow,
+                "Stack overflow.".to_string(),
+            ));
         }
 
         let frame = CallFrame {
-            closure: closure.clone(),
+            closure,
             ip: 0, // ✅ start at beginning
-            slot_start: self.stack_top - arg_count - 1,
+            slot_start: self.stack.len() - arg_count - 1,
         };
 
         self.call_frames.push(frame);
-        true
+        Ok(())
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: usize) -> bool {
-        let obj_rc = match callee {
-            Value::Obj(ref o) => o.clone(),
-            _ => {
-                self.runtime_error("Can only call functions and classes.".to_owned());
-                return false;
-            }
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        let Some(obj) = callee.obj() else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::NotCallable,
+                "Can only call functions and classes.".to_owned(),
+            ));
         };
 
-        let obj = obj_rc.borrow();
+        let obj = obj.borrow();
+        if let Obj::Native(ObjNative {
+            function: NativeFunction::Builtin(function),
+        }) = &*obj
+        {
+            let function = *function;
+            drop(obj);
+            let args = self.stack.split_off(self.stack.len() - arg_count);
+            let result = function(self, &args)?;
+            self.pop();
+            self.push(result);
+            return Ok(());
+        }
 
         match &*obj {
             Obj::Class(klass) => {
                 let instance = ObjInstance::new(klass.clone());
-                let instance_val = obj_val(Obj::Instance(instance));
+                let instance_val = self.alloc(Obj::Instance(instance));
 
-                let slot = self.stack_top - arg_count - 1;
+                let slot = self.stack.len() - arg_count - 1;
                 self.stack[slot] = instance_val.clone();
 
                 let klass_borrow = klass.clone();
 
-                if let Some(init) = klass_borrow.methods.get("init") {
+                if let Some(init) = klass_borrow.methods.get(&self.init_string) {
                     return self.call_value(init.clone(), arg_count);
                 }
 
                 if arg_count != 0 {
-                    self.runtime_error(format!("Expected 0 arguments, got {}.", arg_count));
-                    return false;
+                    return Err(self.runtime_error(
+                        RuntimeErrorKind::ArityMismatch,
+                        format!("Expected 0 arguments but got {}.", arg_count),
+                    ));
                 }
 
-                true
+                Ok(())
             }
 
-            Obj::Closure(c) => self.call(Rc::new(RefCell::new(c.clone())), arg_count),
+            Obj::Closure(closure) => self.call(Rc::clone(closure), arg_count),
 
-            Obj::Native(native) => {
-                let args_start = self.stack_top - arg_count;
-                let result = (native.function)(arg_count, &self.stack[args_start..self.stack_top]);
+            Obj::Native(ObjNative {
+                function: NativeFunction::Host(function),
+            }) => {
+                let args_start = self.stack.len() - arg_count;
+                let result = function(arg_count, &self.stack[args_start..]);
+                let result = self.canonical(result);
 
-                self.stack_top -= arg_count + 1;
+                self.stack.truncate(args_start - 1);
                 self.push(result);
 
-                true
+                Ok(())
             }
 
             Obj::BoundMethod(bound) => {
-                let slot = self.stack_top - arg_count - 1;
+                let slot = self.stack.len() - arg_count - 1;
                 self.stack[slot] = bound.receiver.clone();
 
-                let method =
-                    Value::Obj(Rc::new(RefCell::new(Obj::Closure((*bound.method).clone()))));
-
-                drop(obj);
-                self.call_value(method, arg_count)
+                self.call(Rc::clone(&bound.method), arg_count)
             }
 
-            _ => {
-                self.runtime_error("Can only call functions and classes.".to_owned());
-                false
-            }
+            _ => Err(self.runtime_error(
+                RuntimeErrorKind::NotCallable,
+                "Can only call functions and classes.".to_owned(),
+            )),
         }
     }
-    fn invoke_from_class(&mut self, klass: Rc<ObjClass>, name: String, arg_count: usize) -> bool {
+    fn invoke_from_class(
+        &mut self,
+        klass: Rc<ObjClass>,
+        name: Rc<ObjString>,
+        arg_count: usize,
+    ) -> Result<(), RuntimeError> {
         let method = match klass.methods.get(&name) {
             Some(method) => method.clone(),
             None => {
-                self.runtime_error(format!("Undefined property {}", name));
-                return false;
+                return Err(self.runtime_error(
+                    RuntimeErrorKind::UndefinedProperty,
+                    format!("Undefined property '{}'.", name.data),
+                ));
             }
         };
         self.call_value(method, arg_count)
     }
-    fn bind_method(&mut self, klass: Rc<RefCell<ObjClass>>, name: String) -> bool {
-        let method = match klass.borrow().methods.get(&name) {
-            Some(method) => method.clone(),
-            None => {
-                self.runtime_error(format!("Undefined property {}", name));
-                return false;
+    /// Runs the built-in list method `name` on the list under its `arg_count` arguments.
+    fn invoke_list(
+        &mut self,
+        list: &RefCell<Obj>,
+        name: &ObjString,
+        arg_count: usize,
+    ) -> Result<(), RuntimeError> {
+        let arity = match name.data.as_str() {
+            "pop" | "len" => Some(0..=0),
+            "push" | "remove" => Some(1..=1),
+            "insert" => Some(2..=2),
+            "slice" => Some(1..=2),
+            _ => None,
+        };
+        let args = self.method_args(name, arity, arg_count)?;
+        let mut obj = list.borrow_mut();
+        let Obj::List(list) = &mut *obj else {
+            unreachable!("list methods are only invoked on lists");
+        };
+        let len = list.items.len();
+        let result = match name.data.as_str() {
+            "push" => {
+                list.items.push(args[0].clone());
+                Value::Nil
+            }
+            "pop" => match list.items.pop() {
+                Some(item) => item,
+                None => {
+                    return Err(self.runtime_error(
+                        RuntimeErrorKind::IndexOutOfRange,
+                        "Can't pop from an empty list.".to_owned(),
+                    ));
+                }
+            },
+            "len" => Value::Number(len as f64),
+            "insert" => {
+                let index = self.list_index(&args[0], len, true)?;
+                list.items.insert(index, args[1].clone());
+                Value::Nil
+            }
+            "remove" => {
+                let index = self.list_index(&args[0], len, false)?;
+                list.items.remove(index)
+            }
+            _ => {
+                let start = self.list_index(&args[0], len, true)?;
+                let end = match args.get(1) {
+                    Some(end) => self.list_index(end, len, true)?,
+                    None => len,
+                };
+                let items = list.items[start..end.max(start)].to_vec();
+                drop(obj);
+                self.alloc(Obj::List(ObjList::new(items)))
             }
         };
 
-        let receiver = self.peek(0);
-        let closure = method.as_closure();
-        let bound = ObjBoundMethod {
-            receiver,
-            method: closure,
-        };
         self.pop();
-        self.push(Value::Obj(Rc::new(RefCell::new(Obj::BoundMethod(bound)))));
+        self.push(result);
+        Ok(())
+    }
+    /// Runs the built-in map method `name` on the map under its `arg_count` arguments.
+    fn invoke_map(
+        &mut self,
+        map: &RefCell<Obj>,
+        name: &ObjString,
+        arg_count: usize,
+    ) -> Result<(), RuntimeError> {
+        let arity = match name.data.as_str() {
+            "keys" | "values" | "len" => Some(0..=0),
+            "has" | "delete" => Some(1..=1),
+            _ => None,
+        };
+        let args = self.method_args(name, arity, arg_count)?;
+        // The key is read before the map is borrowed, as it may be the map itself.
+        let key = args.first().map(|key| self.map_key(key)).transpose()?;
+        let mut obj = map.borrow_mut();
+        let Obj::Map(map) = &mut *obj else {
+            unreachable!("map methods are only invoked on maps");
+        };
+        let result = match (name.data.as_str(), key) {
+            ("has", Some(key)) => Value::Bool(map.contains_key(&key)),
+            ("delete", Some(key)) => Value::Bool(map.remove(&key).is_some()),
+            ("len", _) => Value::Number(map.len() as f64),
+            (method, _) => {
+                let items = map
+                    .entries()
+                    .iter()
+                    .map(|(key, value)| if method == "keys" { key } else { value })
+                    .cloned()
+                    .collect();
+                drop(obj);
+                self.alloc(Obj::List(ObjList::new(items)))
+            }
+        };
 
-        true
+        self.pop();
+        self.push(result);
+        Ok(())
+    }
+    /// Pops the `arg_count` arguments of the built-in method `name`, which takes
+    /// `arity` arguments or does not exist if that is `None`.
+    fn method_args(
+        &mut self,
+        name: &ObjString,
+        arity: Option<RangeInclusive<usize>>,
+        arg_count: usize,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let Some(arity) = arity else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::UndefinedProperty,
+                format!("Undefined property '{}'.", name.data),
+            ));
+        };
+        if !arity.contains(&arg_count) {
+            let expected = if arity.start() == arity.end() {
+                arity.end().to_string()
+            } else {
+                format!("{} or {}", arity.start(), arity.end())
+            };
+            return Err(self.runtime_error(
+                RuntimeErrorKind::ArityMismatch,
+                format!("Expected {expected} arguments but got {arg_count}."),
+            ));
+        }
+        Ok(self.stack.split_off(self.stack.len() - arg_count))
+    }
+    /// The item of the list or map `target` at `index`.
+    fn get_index(&self, target: &Value, index: &Value) -> Result<Value, RuntimeError> {
+        match target.obj().map(|obj| obj.borrow()).as_deref() {
+            Some(Obj::List(list)) => {
+                let index = self.list_index(index, list.items.len(), false)?;
+                Ok(list.items[index].clone())
+            }
+            Some(Obj::Map(map)) => match map.get(&self.map_key(index)?) {
+                Some(value) => Ok(value.clone()),
+                None => Err(self.runtime_error(
+                    RuntimeErrorKind::UndefinedKey,
+                    format!("Undefined key '{index}'."),
+                )),
+            },
+            _ => Err(self.not_indexable()),
+        }
+    }
+    /// Stores `value` in the list or map `target` at `index`.
+    fn set_index(&self, target: &Value, index: &Value, value: Value) -> Result<(), RuntimeError> {
+        // The key is read before the map is borrowed, as it may be the map itself.
+        let key = MapKey::new(index);
+        match target.obj().map(|obj| obj.borrow_mut()).as_deref_mut() {
+            Some(Obj::List(list)) => {
+                let index = self.list_index(index, list.items.len(), false)?;
+                list.items[index] = value;
+            }
+            Some(Obj::Map(map)) => {
+                let key = key.ok_or_else(|| self.invalid_key())?;
+                map.insert(key, index.clone(), value);
+            }
+            _ => return Err(self.not_indexable()),
+        }
+        Ok(())
+    }
+    fn not_indexable(&self) -> RuntimeError {
+        self.runtime_error(
+            RuntimeErrorKind::TypeError,
+            "Only lists and maps can be indexed.".to_owned(),
+        )
+    }
+    fn map_key(&self, key: &Value) -> Result<MapKey, RuntimeError> {
+        MapKey::new(key).ok_or_else(|| self.invalid_key())
+    }
+    fn invalid_key(&self) -> RuntimeError {
+        self.runtime_error(
+            RuntimeErrorKind::TypeError,
+            "Map keys must be strings, numbers, booleans or nil.".to_owned(),
+        )
+    }
+    /// Converts `index` to a position in a list of length `len`. Insertion points may
+    /// also be `len` itself, one past the last item.
+    fn list_index(
+        &self,
+        index: &Value,
+        len: usize,
+        insertion: bool,
+    ) -> Result<usize, RuntimeError> {
+        if !index.is_number() || index.as_number().fract() != 0.0 {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "List index must be an integer.".to_owned(),
+            ));
+        }
+        let index = index.as_number();
+        let bound = if insertion { len + 1 } else { len };
+        if index < 0.0 || index >= bound as f64 {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::IndexOutOfRange,
+                format!("Index {index} is out of range for a list of length {len}."),
+            ));
+        }
+        Ok(index as usize)
     }
     fn capture_upvalue(&mut self, local: usize) -> Rc<RefCell<ObjUpvalue>> {
         for upvalue in &self.open_upvalues {
@@ -272,41 +730,30 @@ impl VM {
         }
 
         let new_upvalue = Rc::new(RefCell::new(ObjUpvalue::new_open(local)));
+        self.heap.track_upvalue(&new_upvalue);
         self.open_upvalues.push(new_upvalue.clone());
         new_upvalue
     }
-    fn close_upvalue(&mut self, stack: &[Value], last_index: usize) {
-        for upvalue in &self.open_upvalues {
-            let mut uv = upvalue.borrow_mut();
-
-            if let Some(loc) = uv.location {
-                if loc >= last_index {
-                    uv.close(stack);
-                }
-            }
-        }
-        self.open_upvalues.retain(|uv| uv.borrow().is_open());
-    }
     fn close_upvalues(&mut self, last: usize) {
         for upvalue in &self.open_upvalues {
             let mut uv = upvalue.borrow_mut();
 
-            if let Some(location) = uv.location {
-                if location >= last {
-                    uv.closed = self.stack[location].clone();
-                    uv.location = None;
-                }
+            if let Some(location) = uv.location
+                && location >= last
+            {
+                uv.closed = self.stack[location].clone();
+                uv.location = None;
             }
         }
 
         self.open_upvalues
             .retain(|uv| uv.borrow().location.is_some());
     }
-    fn define_method(&mut self, name: String) {
+    fn define_method(&mut self, name: Rc<ObjString>) {
         let method = self.peek(0);
         let klass_val = self.peek(1);
-        match klass_val {
-            Value::Obj(obj) => {
+        match klass_val.obj() {
+            Some(obj) => {
                 let mut klass_ref = obj.borrow_mut();
                 match &mut *klass_ref {
                     Obj::Class(c) => {
@@ -324,27 +771,108 @@ impl VM {
 
         self.pop(); // method
     }
-    fn is_falsey(&self, value: Value) -> bool {
-        value.is_nil() || (value.is_bool() && !value.as_bool())
+    /// `a + b` for two numbers or two strings.
+    fn add(&mut self, a: Value, b: Value) -> Result<Value, RuntimeError> {
+        if a.is_number() && b.is_number() {
+            return Ok(Value::Number(a.as_number() + b.as_number()));
+        }
+        if let (Some(a), Some(b)) = (a.obj(), b.obj())
+            && let (Obj::String(a), Obj::String(b)) = (&*a.borrow(), &*b.borrow())
+        {
+            return Ok(self.intern(format!("{}{}", a.data, b.data)));
+        }
+        Err(self.runtime_error(
+            RuntimeErrorKind::TypeError,
+            "Operands must be two numbers or two strings.".to_string(),
+        ))
     }
-    fn concatenate(&mut self) {
-        let b = self.peek(0).as_string().as_str().to_owned();
-        let a = self.peek(1).as_string().as_str().to_owned();
-        let result = format!("{a}{b}");
-        let result_obj = ObjString::from_string(result);
+    /// Pops the operands of an arithmetic instruction, which must both be numbers.
+    fn pop_numbers(&mut self) -> Result<(f64, f64), RuntimeError> {
+        if !self.peek(0).is_number() || !self.peek(1).is_number() {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "Operands must be numbers.".to_string(),
+            ));
+        }
+        let b = self.pop().as_number();
+        let a = self.pop().as_number();
+        Ok((a, b))
+    }
+    /// Pops the operands of a bitwise instruction, which must both be whole numbers.
+    fn pop_integers(&mut self) -> Result<(i64, i64), RuntimeError> {
+        let (a, b) = (self.peek(1), self.peek(0));
+        if !a.is_number() || !b.is_number() {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "Operands must be numbers.".to_string(),
+            ));
+        }
+        let (Some(a), Some(b)) = (to_integer(a.as_number()), to_integer(b.as_number())) else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "Operands must be integers.".to_string(),
+            ));
+        };
         self.pop();
         self.pop();
-        self.push(Value::Obj(Rc::new(RefCell::new(Obj::String(result_obj)))));
+        Ok((a, b))
+    }
+    fn negative_shift(&self) -> RuntimeError {
+        self.runtime_error(
+            RuntimeErrorKind::TypeError,
+            "Shift count must not be negative.".to_string(),
+        )
+    }
+    fn is_falsey(&self, value: Value) -> bool {
+        value.is_nil() || (value.is_bool() && !value.as_bool())
+    }
+    fn trace_instruction(&mut self, frame_index: usize) {
+        let Some(format) = self.trace else {
+            return;
+        };
+        let record = {
+            let frame = &self.call_frames[frame_index];
+            debug::trace_instruction(
+                format,
+                &frame.closure.function,
+                frame.ip,
+                &self.stack,
+                &self.global_names.borrow(),
+            )
+        };
+        let _ = self.err.write_all(record.as_bytes());
     }
-    fn run(&mut self) -> InterpretResult {
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        // Picking the loop once keeps the trace check out of the untraced hot path.
+        if self.trace.is_some() {
+            self.run_loop::<true>()
+        } else {
+            self.run_loop::<false>()
+        }
+    }
+    fn run_loop<const TRACE: bool>(&mut self) -> Result<(), RuntimeError> {
         loop {
+            // Between instructions every live value is reachable from the roots.
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+            // Checked here rather than in `push`; no instruction pushes more than a few values.
+            if self.stack.len() > self.config.max_stack {
+                return Err(self.runtime_error(
+                    RuntimeErrorKind::StackOverflow,
+                    "Stack overflow.".to_string(),
+                ));
+            }
             let frame_index = self.call_frames.len() - 1;
+            if TRACE {
+                self.trace_instruction(frame_index);
+            }
 
             let instruction = {
                 let frame = &mut self.call_frames[frame_index];
 
-                let instruction = frame.closure.borrow().function.chunk.code[frame.ip].clone();
-                frame.ip += 1;
+                let (instruction, next) = frame.closure.function.chunk.read(frame.ip);
+                frame.ip = next;
                 instruction
             };
 
@@ -353,7 +881,7 @@ impl VM {
                     let constant = {
                         let frame = &self.call_frames[frame_index];
 
-                        frame.closure.borrow().function.chunk.constants[index as usize].clone()
+                        frame.closure.function.chunk.constants[index as usize].clone()
                     };
 
                     self.push(constant);
@@ -397,53 +925,25 @@ impl VM {
                     self.stack[index] = value;
                 }
 
-                OpCode::DefineGlobal(index) => {
-                    let name = {
-                        let frame = &self.call_frames[frame_index];
-
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
-                    };
-
-                    let value = self.peek(0).clone();
-                    self.globals.insert(name.data.clone(), value);
+                OpCode::DefineGlobal(slot) => {
+                    let value = self.peek(0);
+                    self.define_global(slot as usize, value);
                     self.pop();
                 }
 
-                OpCode::GetGlobal(index) => {
-                    let name = {
-                        let frame = &self.call_frames[frame_index];
-
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
-                    };
-
-                    match self.globals.get(name.as_str()) {
-                        Some(value) => {
-                            self.push(value.clone());
-                        }
-
-                        None => {
-                            self.runtime_error(format!("Undefined variable '{}'.", name.as_str()));
-
-                            return InterpretResult::InterpretRuntimeError;
-                        }
+                OpCode::GetGlobal(slot) => match self.globals.get(slot as usize) {
+                    Some(Some(value)) => {
+                        let value = value.clone();
+                        self.push(value);
                     }
-                }
-
-                OpCode::SetGlobal(index) => {
-                    let name = {
-                        let frame = &self.call_frames[frame_index];
+                    _ => return Err(self.undefined_variable(slot as usize)),
+                },
 
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
-                    };
-
-                    if self.globals.contains_key(&name.data) {
-                        let value = self.peek(0).clone();
-
-                        self.globals.insert(name.data.clone(), value);
-                    } else {
-                        self.runtime_error(format!("Undefined variable '{}'.", name.data));
-
-                        return InterpretResult::InterpretRuntimeError;
+                OpCode::SetGlobal(slot) => {
+                    let value = self.peek(0);
+                    match self.globals.get_mut(slot as usize) {
+                        Some(Some(global)) => *global = value,
+                        _ => return Err(self.undefined_variable(slot as usize)),
                     }
                 }
 
@@ -451,7 +951,7 @@ impl VM {
                     let upvalue = {
                         let frame = &self.call_frames[frame_index];
 
-                        frame.closure.borrow().upvalues[slot as usize].clone()
+                        frame.closure.upvalues[slot as usize].clone()
                     };
 
                     let value = {
@@ -470,7 +970,7 @@ impl VM {
                     let value = self.peek(0).clone();
                     let upvalue = {
                         let frame = &self.call_frames[frame_index];
-                        frame.closure.borrow().upvalues[slot as usize].clone()
+                        frame.closure.upvalues[slot as usize].clone()
                     };
 
                     let mut uv = upvalue.borrow_mut();
@@ -493,9 +993,10 @@ impl VM {
 
                 OpCode::Greater => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.".to_string());
-
-                        return InterpretResult::InterpretRuntimeError;
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Operands must be numbers.".to_string(),
+                        ));
                     }
 
                     let b = self.pop().as_number();
@@ -506,9 +1007,10 @@ impl VM {
 
                 OpCode::Less => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.".to_string());
-
-                        return InterpretResult::InterpretRuntimeError;
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Operands must be numbers.".to_string(),
+                        ));
                     }
 
                     let b = self.pop().as_number();
@@ -520,50 +1022,16 @@ impl VM {
                 OpCode::Add => {
                     let b = self.pop();
                     let a = self.pop();
-
-                    match (a, b) {
-                        (Value::Number(a), Value::Number(b)) => {
-                            self.push(Value::Number(a + b));
-                        }
-
-                        (Value::Obj(a), Value::Obj(b)) => {
-                            let a = a.borrow();
-                            let b = b.borrow();
-
-                            match (&*a, &*b) {
-                                (Obj::String(a), Obj::String(b)) => {
-                                    let result = format!("{}{}", a.data, b.data);
-
-                                    self.push(Value::Obj(Rc::new(RefCell::new(Obj::String(
-                                        ObjString::from_string(result),
-                                    )))));
-                                }
-
-                                _ => {
-                                    self.runtime_error(
-                                        "Operands must be two numbers or two strings.".to_string(),
-                                    );
-
-                                    return InterpretResult::InterpretRuntimeError;
-                                }
-                            }
-                        }
-
-                        _ => {
-                            self.runtime_error(
-                                "Operands must be two numbers or two strings.".to_string(),
-                            );
-
-                            return InterpretResult::InterpretRuntimeError;
-                        }
-                    }
+                    let result = self.add(a, b)?;
+                    self.push(result);
                 }
 
                 OpCode::Subtract => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.".to_string());
-
-                        return InterpretResult::InterpretRuntimeError;
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Operands must be numbers.".to_string(),
+                        ));
                     }
 
                     let b = self.pop().as_number();
@@ -574,9 +1042,10 @@ impl VM {
 
                 OpCode::Multiply => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.".to_string());
-
-                        return InterpretResult::InterpretRuntimeError;
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Operands must be numbers.".to_string(),
+                        ));
                     }
 
                     let b = self.pop().as_number();
@@ -587,9 +1056,10 @@ impl VM {
 
                 OpCode::Divide => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.".to_string());
-
-                        return InterpretResult::InterpretRuntimeError;
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Operands must be numbers.".to_string(),
+                        ));
                     }
 
                     let b = self.pop().as_number();
@@ -598,6 +1068,60 @@ impl VM {
                     self.push(Value::Number(a / b));
                 }
 
+                OpCode::Modulo => {
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Number(a % b));
+                }
+
+                OpCode::Power => {
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Number(a.powf(b)));
+                }
+
+                OpCode::BitAnd => {
+                    let (a, b) = self.pop_integers()?;
+                    self.push(Value::Number((a & b) as f64));
+                }
+
+                OpCode::BitOr => {
+                    let (a, b) = self.pop_integers()?;
+                    self.push(Value::Number((a | b) as f64));
+                }
+
+                OpCode::BitXor => {
+                    let (a, b) = self.pop_integers()?;
+                    self.push(Value::Number((a ^ b) as f64));
+                }
+
+                OpCode::ShiftLeft => {
+                    let (a, b) = self.pop_integers()?;
+                    let result = shift_left(a, b).ok_or_else(|| self.negative_shift())?;
+                    self.push(Value::Number(result as f64));
+                }
+
+                OpCode::ShiftRight => {
+                    let (a, b) = self.pop_integers()?;
+                    let result = shift_right(a, b).ok_or_else(|| self.negative_shift())?;
+                    self.push(Value::Number(result as f64));
+                }
+
+                OpCode::BitNot => {
+                    if !self.peek(0).is_number() {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Operand must be a number.".to_string(),
+                        ));
+                    }
+                    let Some(value) = to_integer(self.peek(0).as_number()) else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Operand must be an integer.".to_string(),
+                        ));
+                    };
+                    self.pop();
+                    self.push(Value::Number(!value as f64));
+                }
+
                 OpCode::Not => {
                     let value = self.pop();
 
@@ -606,9 +1130,10 @@ impl VM {
 
                 OpCode::Negate => {
                     if !self.peek(0).is_number() {
-                        self.runtime_error("Operand must be a number.".to_string());
-
-                        return InterpretResult::InterpretRuntimeError;
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Operand must be a number.".to_string(),
+                        ));
                     }
 
                     let value = self.pop().as_number();
@@ -616,7 +1141,10 @@ impl VM {
                     self.push(Value::Number(-value));
                 }
 
-                OpCode::Print => self.pop().print(),
+                OpCode::Print => {
+                    let value = self.pop();
+                    let _ = writeln!(self.out, "{value}");
+                }
 
                 OpCode::Jump(offset) => {
                     let frame = &mut self.call_frames[frame_index];
@@ -642,17 +1170,14 @@ impl VM {
                 OpCode::Call(arg_count) => {
                     let callee = self.peek(arg_count as usize).clone();
 
-                    if !self.call_value(callee, arg_count as usize) {
-                        return InterpretResult::InterpretRuntimeError;
-                    }
+                    self.call_value(callee, arg_count as usize)?;
                 }
 
                 OpCode::Closure(index) => {
                     let function = {
                         let frame = &self.call_frames[frame_index];
 
-                        frame.closure.borrow().function.chunk.constants[index as usize]
-                            .as_function()
+                        frame.closure.function.chunk.constants[index as usize].as_function()
                     };
 
                     let mut closure = ObjClosure::new(function);
@@ -662,17 +1187,10 @@ impl VM {
                         let upvalue_index;
                         {
                             let frame = &mut self.call_frames[frame_index];
-                            is_local = match frame.closure.borrow().function.chunk.code[frame.ip] {
-                                OpCode::Data(d) => d == 1,
-                                _ => panic!("Expected Data opcode for upvalue is_local"),
-                            };
-                            frame.ip += 1;
-                            upvalue_index =
-                                match frame.closure.borrow().function.chunk.code[frame.ip] {
-                                    OpCode::Data(d) => d,
-                                    _ => panic!("Expected Data opcode for upvalue index"),
-                                };
-                            frame.ip += 1;
+                            let code = &frame.closure.function.chunk.code;
+                            is_local = code[frame.ip] == 1;
+                            upvalue_index = code[frame.ip + 1];
+                            frame.ip += 2;
                         }
 
                         if is_local {
@@ -682,17 +1200,18 @@ impl VM {
                                 .push(self.capture_upvalue(slot_start + upvalue_index as usize));
                         } else {
                             closure.upvalues.push(Rc::clone(
-                                &self.call_frames[frame_index].closure.borrow().upvalues
+                                &self.call_frames[frame_index].closure.upvalues
                                     [upvalue_index as usize],
                             ));
                         }
                     }
 
-                    self.push(Value::Obj(Rc::new(RefCell::new(Obj::Closure(closure)))));
+                    let closure = self.alloc(Obj::Closure(Rc::new(closure)));
+                    self.push(closure);
                 }
 
                 OpCode::CloseUpvalue => {
-                    let last = self.stack_top - 1;
+                    let last = self.stack.len() - 1;
 
                     self.close_upvalues(last);
 
@@ -706,9 +1225,9 @@ impl VM {
                     self.close_upvalues(frame.slot_start);
 
                     if self.call_frames.is_empty() {
-                        return InterpretResult::InterpretOk;
+                        return Ok(());
                     }
-                    self.stack_top = frame.slot_start;
+                    self.stack.truncate(frame.slot_start);
                     self.push(result);
                 }
 
@@ -716,97 +1235,94 @@ impl VM {
                     let name = {
                         let frame = &self.call_frames[frame_index];
 
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
-                    };
-                    let class = ObjClass {
-                        name: name.data.clone(),
-                        methods: HashMap::new(),
+                        frame.closure.function.chunk.constants[index as usize].as_string()
                     };
+                    let class = ObjClass::new(name.data.clone(), Table::default());
 
-                    self.push(Value::Obj(Rc::new(RefCell::new(Obj::Class(Rc::new(
-                        class,
-                    ))))));
+                    let class = self.alloc(Obj::Class(Rc::new(class)));
+                    self.push(class);
                 }
                 OpCode::Method(index) => {
                     let name = {
                         let frame = &self.call_frames[frame_index];
 
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
+                        frame.closure.function.chunk.constants[index as usize].as_string()
                     };
-                    self.define_method(name.data.to_owned());
+                    self.define_method(name);
                 }
                 OpCode::SetProperty(index) => {
-                    let name = {
-                        let frame = &self.call_frames[frame_index];
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
-                    };
-
-                    let receiver = self.peek(1).clone();
-                    let value = self.peek(0).clone();
-
-                    match receiver {
-                        Value::Obj(obj) => {
-                            let mut obj_ref = obj.borrow_mut();
+                    let frame = &self.call_frames[frame_index];
+                    let function = Rc::clone(&frame.closure.function);
+                    let cache = function.chunk.cache(frame.ip);
 
-                            match &mut *obj_ref {
-                                Obj::Instance(instance) => {
-                                    instance.fields.insert(name.data.clone(), value.clone());
-
-                                    let value = self.pop(); // value
-                                    self.pop(); // receiver
-                                    self.push(value);
-                                }
-
-                                _ => {
-                                    self.runtime_error("Only instances have fields.".to_string());
-                                    return InterpretResult::InterpretRuntimeError;
+                    let receiver = self.peek(1);
+                    let value = self.peek(0);
+                    let Some(obj) = receiver.obj() else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Only instances have fields.".to_string(),
+                        ));
+                    };
+                    match &mut *obj.borrow_mut() {
+                        Obj::Instance(instance) => {
+                            let cached = cache.borrow().slot(instance);
+                            let slot = match cached {
+                                Some(slot) => slot,
+                                None => {
+                                    let name = function.chunk.constants[index as usize].as_string();
+                                    cache.borrow_mut().fill(&instance.klass, &name)
                                 }
-                            }
+                            };
+                            instance.set_field_at(slot, value);
                         }
-
                         _ => {
-                            self.runtime_error("Only instances have fields.".to_string());
-                            return InterpretResult::InterpretRuntimeError;
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::TypeError,
+                                "Only instances have fields.".to_string(),
+                            ));
                         }
-                    }
-                }
-                OpCode::GetProperty(index) => {
-                    let name = {
-                        let frame = &self.call_frames[frame_index];
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
-                    };
-
-                    let receiver = self.peek(0).clone();
+                    }
 
-                    let result = match &receiver {
-                        Value::Obj(obj) => match &*obj.borrow() {
+                    let value = self.pop(); // value
+                    self.pop(); // receiver
+                    self.push(value);
+                }
+                OpCode::GetProperty(index) => {
+                    let frame = &self.call_frames[frame_index];
+                    let function = Rc::clone(&frame.closure.function);
+                    let cache = function.chunk.cache(frame.ip);
+                    let name = &function.chunk.constants[index as usize];
+
+                    let receiver = self.peek(0);
+                    let property = match receiver.obj() {
+                        Some(obj) => match &*obj.borrow() {
                             Obj::Instance(instance) => {
-                                if let Some(value) = instance.fields.get(&name.data) {
-                                    value.clone()
-                                } else if let Some(method) = instance.klass.methods.get(&name.data)
-                                {
-                                    let bound = ObjBoundMethod {
-                                        receiver: receiver.clone(),
-                                        method: method.as_closure(),
-                                    };
-
-                                    Value::Obj(Rc::new(RefCell::new(Obj::BoundMethod(bound))))
-                                } else {
-                                    self.runtime_error(format!(
-                                        "Undefined property '{}'.",
-                                        name.data
-                                    ));
-                                    return InterpretResult::InterpretRuntimeError;
-                                }
-                            }
-                            _ => {
-                                self.runtime_error("Only instances have properties.".to_string());
-                                return InterpretResult::InterpretRuntimeError;
+                                Some(resolve_property(cache, instance, name))
                             }
+                            _ => None,
                         },
-                        _ => {
-                            self.runtime_error("Only instances have properties.".to_string());
-                            return InterpretResult::InterpretRuntimeError;
+                        _ => None,
+                    };
+                    let result = match property {
+                        Some(Some(Property::Field(value))) => value,
+                        Some(Some(Property::Method(method))) => {
+                            let bound = ObjBoundMethod {
+                                receiver: receiver.clone(),
+                                method: method.as_closure(),
+                            };
+                            self.alloc(Obj::BoundMethod(bound))
+                        }
+                        Some(None) => {
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::UndefinedProperty,
+                                format!("Undefined property '{}'.", name.as_string().data),
+                            ));
+                        }
+                        None => {
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::TypeError,
+                                "Only instances have properties.".to_string(),
+                            ));
                         }
                     };
 
@@ -814,68 +1330,56 @@ impl VM {
                     self.push(result);
                 }
                 OpCode::Invoke(index, arg_count) => {
-                    let name = {
-                        let frame = &self.call_frames[frame_index];
-
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
+                    let arg_count = arg_count as usize;
+                    let frame = &self.call_frames[frame_index];
+                    let function = Rc::clone(&frame.closure.function);
+                    let cache = function.chunk.cache(frame.ip);
+                    let name = &function.chunk.constants[index as usize];
+
+                    let receiver = self.peek(arg_count);
+                    let Some(obj) = receiver.obj() else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Only instances have methods.".to_string(),
+                        ));
                     };
-                    let receiver = self.peek(arg_count as usize).clone();
-
-                    match receiver {
-                        Value::Obj(obj) => {
-                            let obj_ref = obj.borrow();
-
-                            match &*obj_ref {
-                                Obj::Instance(instance) => {
-                                    if let Some(value) = instance.fields.get(&name.data) {
-                                        let value = value.clone();
-
-                                        let slot = self.stack_top - 1 - arg_count as usize;
-                                        self.stack[slot] = value.clone();
-
-                                        if !self.call_value(value, arg_count as usize) {
-                                            return InterpretResult::InterpretRuntimeError;
-                                        }
-
-                                        continue;
-                                    }
-
-                                    if !self.invoke_from_class(
-                                        instance.klass.clone(),
-                                        name.data.clone(),
-                                        arg_count as usize,
-                                    ) {
-                                        return InterpretResult::InterpretRuntimeError;
-                                    }
-                                }
-                                Obj::Class(klass) => {
-                                    if let Some(value) = klass.methods.get(&name.data) {
-                                        let value = value.clone();
-
-                                        let slot = self.stack_top - 1 - arg_count as usize;
-                                        self.stack[slot] = value.clone();
-
-                                        if !self.call_value(value, arg_count as usize) {
-                                            return InterpretResult::InterpretRuntimeError;
-                                        }
-
-                                        continue;
-                                    }
-                                }
-                                _ => {
-                                    self.runtime_error(
-                                        "Only instances and Classes have methods.".to_string(),
-                                    );
-
-                                    return InterpretResult::InterpretRuntimeError;
-                                }
-                            }
-                        }
-
+                    let builtin: Option<BuiltinMethod> = match &*obj.borrow() {
+                        Obj::List(_) => Some(Self::invoke_list),
+                        Obj::Map(_) => Some(Self::invoke_map),
+                        _ => None,
+                    };
+                    if let Some(invoke) = builtin {
+                        invoke(self, obj, &name.as_string(), arg_count)?;
+                        continue;
+                    }
+                    let property = match &*obj.borrow() {
+                        Obj::Instance(instance) => resolve_property(cache, instance, name),
+                        Obj::Class(klass) => klass
+                            .methods
+                            .get(&*name.as_string())
+                            .cloned()
+                            .map(Property::Field),
                         _ => {
-                            self.runtime_error("Only instances have methods.".to_string());
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::TypeError,
+                                "Only instances and Classes have methods.".to_string(),
+                            ));
+                        }
+                    };
 
-                            return InterpretResult::InterpretRuntimeError;
+                    match property {
+                        Some(Property::Field(value)) => {
+                            // A callable field replaces the receiver, like a plain call.
+                            let slot = self.stack.len() - 1 - arg_count;
+                            self.stack[slot] = value.clone();
+                            self.call_value(value, arg_count)?;
+                        }
+                        Some(Property::Method(method)) => self.call_value(method, arg_count)?,
+                        None => {
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::UndefinedProperty,
+                                format!("Undefined property '{}'.", name.as_string().data),
+                            ));
                         }
                     }
                 }
@@ -883,37 +1387,41 @@ impl VM {
                     let superclass_val = self.peek(1).clone();
                     let subclass_val = self.peek(0).clone();
 
-                    let super_class = match superclass_val {
-                        Value::Obj(ref obj) => match &*obj.borrow() {
+                    let super_class = match superclass_val.obj() {
+                        Some(obj) => match &*obj.borrow() {
                             Obj::Class(c) => c.clone(),
                             _ => {
-                                self.runtime_error(
+                                return Err(self.runtime_error(
+                                    RuntimeErrorKind::TypeError,
                                     "Inherit: Superclass must be a class.".to_string(),
-                                );
-                                return InterpretResult::InterpretRuntimeError;
+                                ));
                             }
                         },
                         _ => {
-                            self.runtime_error("Inherit: Superclass must be a class.".to_string());
-                            return InterpretResult::InterpretRuntimeError;
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::TypeError,
+                                "Inherit: Superclass must be a class.".to_string(),
+                            ));
                         }
                     };
 
-                    let sub_obj = match subclass_val {
-                        Value::Obj(ref obj) => Rc::clone(obj),
-                        _ => {
-                            self.runtime_error("Subclass must be a class.".to_string());
-                            return InterpretResult::InterpretRuntimeError;
-                        }
-                    };
+                    if !subclass_val.is_obj() {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Subclass must be a class.".to_string(),
+                        ));
+                    }
+                    let sub_obj = subclass_val.as_obj();
 
                     {
                         let mut tmp_obj = sub_obj.borrow_mut();
                         let sub_class = match &*tmp_obj {
                             Obj::Class(c) => Rc::clone(c),
                             _ => {
-                                self.runtime_error("Subclass must be a class.".to_string());
-                                return InterpretResult::InterpretRuntimeError;
+                                return Err(self.runtime_error(
+                                    RuntimeErrorKind::TypeError,
+                                    "Subclass must be a class.".to_string(),
+                                ));
                             }
                         };
 
@@ -926,6 +1434,7 @@ impl VM {
                         let new_sub_class = Rc::new(ObjClass {
                             name: sub_class.name.clone(),
                             methods: new_methods,
+                            field_slots: Rc::clone(&sub_class.field_slots),
                         });
 
                         *tmp_obj = Obj::Class(new_sub_class);
@@ -937,36 +1446,39 @@ impl VM {
                     let name = {
                         let frame = &self.call_frames[frame_index];
 
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
+                        frame.closure.function.chunk.constants[index as usize].as_string()
                     };
 
                     // stack:
                     // ... receiver superclass
                     let superclass = self.pop();
-                    let superclass = match superclass {
-                        Value::Obj(obj) => match &*obj.borrow() {
+                    let superclass = match superclass.obj() {
+                        Some(obj) => match &*obj.borrow() {
                             Obj::Class(class) => class.clone(),
                             _ => {
-                                self.runtime_error(
+                                return Err(self.runtime_error(
+                                    RuntimeErrorKind::TypeError,
                                     "GetSuper: Superclass must be a class.".to_string(),
-                                );
-                                return InterpretResult::InterpretRuntimeError;
+                                ));
                             }
                         },
 
                         _ => {
-                            self.runtime_error("GetSuper: Superclass must be a class.".to_string());
-                            return InterpretResult::InterpretRuntimeError;
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::TypeError,
+                                "GetSuper: Superclass must be a class.".to_string(),
+                            ));
                         }
                     };
 
                     let receiver = self.peek(0).clone();
-                    let method = match superclass.methods.get(&name.data) {
+                    let method = match superclass.methods.get(&name) {
                         Some(method) => method.clone(),
                         None => {
-                            self.runtime_error(format!("Undefined property '{}'.", name.data));
-
-                            return InterpretResult::InterpretRuntimeError;
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::UndefinedProperty,
+                                format!("Undefined property '{}'.", name.data),
+                            ));
                         }
                     };
 
@@ -975,76 +1487,182 @@ impl VM {
                         method: method.as_closure(),
                     };
 
+                    let bound_method = self.alloc(Obj::BoundMethod(bound_method));
                     self.pop();
-                    self.push(Value::Obj(Rc::new(RefCell::new(Obj::BoundMethod(
-                        bound_method,
-                    )))));
+                    self.push(bound_method);
                 }
                 OpCode::SuperInvoke(index, arg_count) => {
                     let name = {
                         let frame = &self.call_frames[frame_index];
 
-                        frame.closure.borrow().function.chunk.constants[index as usize].as_string()
+                        frame.closure.function.chunk.constants[index as usize].as_string()
                     };
                     let superclass = self.pop();
-                    let superclass = match superclass {
-                        Value::Obj(obj) => match &*obj.borrow() {
+                    let superclass = match superclass.obj() {
+                        Some(obj) => match &*obj.borrow() {
                             Obj::Class(class) => class.clone(),
                             _ => {
-                                self.runtime_error(
+                                return Err(self.runtime_error(
+                                    RuntimeErrorKind::TypeError,
                                     "SuperInvoke: Superclass must be a class.".to_string(),
-                                );
-                                return InterpretResult::InterpretRuntimeError;
+                                ));
                             }
                         },
 
                         _ => {
-                            self.runtime_error(
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::TypeError,
                                 "SuperInvoke: Superclass must be a class.".to_string(),
-                            );
-                            return InterpretResult::InterpretRuntimeError;
+                            ));
                         }
                     };
 
-                    if !self.invoke_from_class(superclass, name.data.clone(), arg_count as usize) {
-                        return InterpretResult::InterpretRuntimeError;
-                    }
+                    self.invoke_from_class(superclass, name, arg_count as usize)?;
+                }
+
+                OpCode::BuildList(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count as usize);
+                    let list = self.alloc(Obj::List(ObjList::new(items)));
+                    self.push(list);
+                }
+                OpCode::GetIndex => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    let item = self.get_index(&target, &index)?;
+                    self.push(item);
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let target = self.pop();
+                    self.set_index(&target, &index, value.clone())?;
+                    self.push(value);
+                }
+                OpCode::Dup2 => {
+                    let second = self.peek(1);
+                    let top = self.peek(0);
+                    self.push(second);
+                    self.push(top);
+                }
+
+                OpCode::Stringify => {
+                    let value = self.pop();
+                    let is_string = value
+                        .obj()
+                        .is_some_and(|obj| matches!(&*obj.borrow(), Obj::String(_)));
+                    let string = if is_string {
+                        value
+                    } else {
+                        self.intern(value.to_string())
+                    };
+                    self.push(string);
                 }
 
                 OpCode::Data(_) => {
-                    // Data opcodes are consumed by other opcodes like Closure
-                    // If we hit one here, it means something is wrong with the bytecode stream
+                    // Raw operand bytes are consumed by other opcodes like Closure and
+                    // never decode to an instruction of their own.
                     panic!("Unexpected Data opcode in main loop");
                 }
                 OpCode::Nop => {}
 
-                _ => {
-                    println!("Unhandled opcode: {:?}", instruction);
-                    todo!("Opcode not implemented");
+                OpCode::AddLocals(a, b) => {
+                    let slot_start = self.call_frames[frame_index].slot_start;
+                    let a = self.stack[slot_start + a as usize].clone();
+                    let b = self.stack[slot_start + b as usize].clone();
+                    let result = self.add(a, b)?;
+                    self.push(result);
+                }
+
+                OpCode::AddConstant(index) => {
+                    let b = {
+                        let frame = &self.call_frames[frame_index];
+
+                        frame.closure.function.chunk.constants[index as usize].clone()
+                    };
+                    let a = self.pop();
+                    let result = self.add(a, b)?;
+                    self.push(result);
+                }
+
+                OpCode::NotEqual => {
+                    let b = self.pop();
+                    let a = self.pop();
+
+                    self.push(Value::Bool(a != b));
+                }
+
+                OpCode::SetLocalPop(slot) => {
+                    let value = self.pop();
+                    let slot_start = self.call_frames[frame_index].slot_start;
+
+                    self.stack[slot_start + slot as usize] = value;
                 }
             }
         }
     }
-    pub fn interpret(&mut self, source: String) -> InterpretResult {
-        let mut compiler =
-            crate::compiler::Compiler::new(None, crate::compiler::FunctionType::TypeScript);
-        let function = compiler.compile(source);
+    /// Compiles and runs `source`. Errors are returned and also written to the error sink.
+    pub fn interpret(&mut self, source: String) -> Result<(), InterpretError> {
+        let mut compiler = self.compiler();
+        let function = compiler.compile(source.clone());
         if compiler.had_error {
-            return InterpretResult::InterpretCompileError;
+            self.report_compile_errors(&compiler.diagnostics, &source);
+            return Err(InterpretError::Compile(compiler.diagnostics));
         }
-
-        let function_rc = Rc::new(function);
-        let closure = Rc::new(RefCell::new(ObjClosure::new(function_rc.clone())));
+        self.run_script(function)
+    }
+    /// Interprets one REPL entry on the same VM, so globals survive between entries.
+    /// Bare expression statements are echoed, and input that stops in the middle of
+    /// a declaration reports [`ReplResult::Incomplete`] instead of a compile error.
+    pub fn interpret_repl(&mut self, source: String) -> ReplResult {
+        let mut compiler = self.compiler();
+        compiler.repl = true;
+        let function = compiler.compile(source.clone());
+        if compiler.had_error {
+            // An earlier error means the entry is wrong already, however it ends.
+            if compiler.diagnostics.iter().all(|d| d.at_end) {
+                return ReplResult::Incomplete;
+            }
+            self.report_compile_errors(&compiler.diagnostics, &source);
+            return ReplResult::Done(Err(InterpretError::Compile(compiler.diagnostics)));
+        }
+        ReplResult::Done(self.run_script(function))
+    }
+    /// A compiler that shares this VM's globals and strings.
+    fn compiler(&self) -> Compiler {
+        let mut compiler = Compiler::new(None, FunctionType::TypeScript);
+        compiler.globals = Rc::clone(&self.global_names);
+        compiler.strings = Rc::clone(&self.strings);
+        compiler.opt_level = self.opt_level;
+        compiler
+    }
+    fn report_compile_errors(&mut self, diagnostics: &[Diagnostic], source: &str) {
+        let report: String = diagnostics
+            .iter()
+            .map(|d| {
+                if self.render_diagnostics {
+                    d.render(source)
+                } else {
+                    format!("{d}\n")
+                }
+            })
+            .collect();
+        self.report_error(&report);
+    }
+    fn run_script(&mut self, function: ObjFunction) -> Result<(), InterpretError> {
+        let closure = Rc::new(ObjClosure::new(Rc::new(function)));
         // Standard Lox: push closure first, then call.
         // The script closure stays at stack[0] during the entire execution.
-        self.push(Value::Obj(Rc::new(RefCell::new(Obj::Closure(
-            (*closure.borrow()).clone(),
-        )))));
+        let script = self.alloc(Obj::Closure(Rc::clone(&closure)));
+        self.push(script);
 
-        self.call(closure, 0);
-        let result = self.run();
+        let result = self.call(closure, 0).and_then(|()| self.run());
         self.reset_stack();
-        result
+        if let Err(error) = &result {
+            self.report_error(&format!("{error}\n"));
+        }
+        let _ = self.out.flush();
+        let _ = self.err.flush();
+        result.map_err(InterpretError::Runtime)
     }
 }
 
@@ -1398,9 +2016,942 @@ mod tests {
     #[case(SOURCE_CONCATENATE)]
     fn test(#[case] source: &str) {
         let mut vm = VM::new();
+        assert_eq!(vm.interpret(source.to_string()), Ok(()));
+    }
+
+    #[test]
+    fn repl_keeps_globals_between_entries() {
+        let mut vm = VM::new();
+        let ok = ReplResult::Done(Ok(()));
+        assert_eq!(vm.interpret_repl("var a = 1;".to_owned()), ok);
+        assert_eq!(
+            vm.interpret_repl("fun f(x) { return x + a; }".to_owned()),
+            ok
+        );
+        assert_eq!(vm.interpret_repl("class C {}".to_owned()), ok);
+        assert_eq!(vm.interpret_repl("var c = C(); c.v = f(2);".to_owned()), ok);
+        assert_eq!(vm.interpret_repl("c.v".to_owned()), ok);
+    }
+
+    #[test]
+    fn repl_reports_incomplete_input() {
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.interpret_repl("fun f() {\n".to_owned()),
+            ReplResult::Incomplete
+        );
+        assert_eq!(
+            vm.interpret_repl("print (1 +\n".to_owned()),
+            ReplResult::Incomplete
+        );
+        assert_eq!(
+            vm.interpret_repl("fun f() {\n return 1;\n}\n".to_owned()),
+            ReplResult::Done(Ok(()))
+        );
+        vm.set_error_output(io::sink());
+        assert!(matches!(
+            vm.interpret_repl("print ); print (".to_owned()),
+            ReplResult::Done(Err(InterpretError::Compile(_)))
+        ));
+        assert!(matches!(
+            vm.interpret_repl("print ); print \"b\n".to_owned()),
+            ReplResult::Done(Err(InterpretError::Compile(_)))
+        ));
+    }
+
+    #[test]
+    fn repl_continues_an_unterminated_string() {
+        let mut vm = VM::new();
+        let out = SharedBuffer::default();
+        vm.set_output(out.clone());
+        for incomplete in ["print \"one\n", "print \"one\ntwo ${1 + 1}\n"] {
+            assert_eq!(
+                vm.interpret_repl(incomplete.to_owned()),
+                ReplResult::Incomplete
+            );
+        }
+        assert_eq!(
+            vm.interpret_repl("print \"one\ntwo ${1 + 1}\nthree\";\n".to_owned()),
+            ReplResult::Done(Ok(()))
+        );
+        assert_eq!(out.contents(), "one\ntwo 2\nthree\n");
+    }
+
+    #[test]
+    fn repl_recovers_after_runtime_error() {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret_repl("fun f() { return nil + 1; } f();".to_owned()),
+            ReplResult::Done(Err(InterpretError::Runtime(_)))
+        ));
+        assert_eq!(
+            vm.interpret_repl("var b = 2; b;".to_owned()),
+            ReplResult::Done(Ok(()))
+        );
+    }
+
+    #[test]
+    fn errors_inside_function_bodies_fail_compilation() {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret("fun f() { print 1 } print 2;".to_owned()),
+            Err(InterpretError::Compile(_))
+        ));
+    }
+
+    #[rstest]
+    #[case(TraceFormat::Text)]
+    #[case(TraceFormat::Json)]
+    fn traced_run_matches_untraced_result(#[case] format: TraceFormat) {
+        let mut vm = VM::new();
+        vm.set_trace(Some(format));
+        assert_eq!(vm.interpret(SOURCE_CLOSURES.to_string()), Ok(()));
+        assert!(matches!(
+            vm.interpret("print nil + 1;".to_string()),
+            Err(InterpretError::Runtime(_))
+        ));
+    }
+
+    fn run_captured(source: &str) -> (Result<(), InterpretError>, String, String) {
+        let out = SharedBuffer::new();
+        let err = SharedBuffer::new();
+        let mut vm = VM::new();
+        vm.set_output(out.clone());
+        vm.set_error_output(err.clone());
+        let result = vm.interpret(source.to_string());
+        (result, out.contents(), err.contents())
+    }
+
+    #[test]
+    fn print_writes_to_output_sink() {
+        let (result, out, err) = run_captured(SOURCE_CLASS3);
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            out,
+            "<class TestClass>\n<instance>\ntest\ndoing staff\ndoing staff\nDone\n"
+        );
+        assert_eq!(err, "");
+
+        let (_, out, _) = run_captured(SOURCE_FIB);
+        assert!(out.starts_with("0\n1\n1\n2\n3\n5\n8\n"));
+        assert!(out.ends_with("46368\n75025\n"));
+    }
+
+    #[test]
+    fn runtime_errors_write_to_error_sink() {
+        let source = "print 1;\nfun f() {\n  return nil + 1;\n}\nf();";
+        let (result, out, err) = run_captured(source);
+        assert!(matches!(result, Err(InterpretError::Runtime(_))));
+        assert_eq!(out, "1\n");
+        assert_eq!(
+            err,
+            "Operands must be two numbers or two strings.\n[line 3] in f\n[line 5] in <script>\n"
+        );
+    }
+
+    fn runtime_error_of(source: &str) -> RuntimeError {
+        let mut vm = VM::new();
+        vm.set_output(io::sink());
+        vm.set_error_output(io::sink());
+        match vm.interpret(source.to_owned()) {
+            Err(InterpretError::Runtime(error)) => error,
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+    }
+
+    #[rstest]
+    #[case("print -\"a\" < 1;", RuntimeErrorKind::TypeError)]
+    #[case("print nil + 1;", RuntimeErrorKind::TypeError)]
+    #[case("print 1.field;", RuntimeErrorKind::TypeError)]
+    #[case("print missing;", RuntimeErrorKind::UndefinedVariable)]
+    #[case("missing = 1;", RuntimeErrorKind::UndefinedVariable)]
+    #[case("class C {} print C().nope;", RuntimeErrorKind::UndefinedProperty)]
+    #[case("fun f(a) {} f();", RuntimeErrorKind::ArityMismatch)]
+    #[case("class C {} C(1);", RuntimeErrorKind::ArityMismatch)]
+    #[case("var x = 1; x();", RuntimeErrorKind::NotCallable)]
+    #[case("fun f() { f(); } f();", RuntimeErrorKind::StackOverflow)]
+    fn runtime_errors_carry_their_kind(#[case] source: &str, #[case] kind: RuntimeErrorKind) {
+        assert_eq!(runtime_error_of(source).kind, kind);
+    }
+
+    /// Whether the frame that called this builtin runs the very function object held in
+    /// the script's constant table, rather than a copy of it.
+    fn caller_shares_its_function(vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+        let caller = &vm
+            .call_frames
+            .last()
+            .expect("a calling frame")
+            .closure
+            .function;
+        let script = &vm.call_frames[0].closure.function;
+        let shared = script.chunk.constants.iter().filter(|c| c.is_obj()).any(
+            |constant| matches!(&*constant.as_obj().borrow(), Obj::Function(f) if Rc::ptr_eq(f, caller)),
+        );
+        Ok(Value::Bool(shared))
+    }
+
+    #[test]
+    fn calls_share_the_compiled_function() {
+        let out = SharedBuffer::new();
+        let mut vm = VM::new();
+        vm.set_output(out.clone());
+        vm.define_builtin("shared", caller_shares_its_function);
+        let source = "fun f() { print shared(); }
+                      class C { m() { print shared(); } }
+                      f();
+                      C().m();
+                      var bound = C().m;
+                      bound();
+                      print shared();";
+        assert_eq!(vm.interpret(source.to_owned()), Ok(()));
+        // The script itself is not one of its own constants.
+        assert_eq!(out.contents(), "true\ntrue\ntrue\nfalse\n");
+    }
+
+    #[rstest]
+    #[case::function("fun f(a) {} f();", "Expected 1 arguments but got 0.")]
+    #[case::initializer("class C { init(a) {} } C();", "Expected 1 arguments but got 0.")]
+    #[case::no_initializer("class C {} C(1);", "Expected 0 arguments but got 1.")]
+    #[case::builtin("Map(1);", "Expected 0 arguments but got 1.")]
+    fn arity_errors_share_one_message(#[case] source: &str, #[case] message: &str) {
+        assert_eq!(runtime_error_of(source).message, message);
+    }
+
+    #[test]
+    fn deep_recursion_and_large_frames_fit() {
+        let locals: String = (0..200).map(|i| format!("var a{i} = {i};")).collect();
+        let source = format!(
+            "fun sum(n) {{ if (n == 0) return 0; return n + sum(n - 1); }}
+             fun wide(n) {{ {locals} if (n > 0) wide(n - 1); }}
+             wide(100); print sum(5000);"
+        );
+        let (result, out, _) = run_captured(&source);
+        assert_eq!(result, Ok(()));
+        assert_eq!(out, "12502500\n");
+    }
+
+    #[rstest]
+    #[case::frames(VMConfig { max_frames: 10, ..Default::default() }, "fun f() { f(); } f();")]
+    #[case::stack(
+        VMConfig { max_stack: 50, ..Default::default() },
+        "fun f(a, b, c, d) { var e; f(a, b, c, d); } f(1, 2, 3, 4);"
+    )]
+    fn exceeding_a_limit_is_a_stack_overflow(#[case] config: VMConfig, #[case] source: &str) {
+        let mut vm = VM::with_config(config);
+        let err = SharedBuffer::new();
+        vm.set_error_output(err.clone());
+        let error = match vm.interpret(source.to_owned()) {
+            Err(InterpretError::Runtime(error)) => error,
+            other => panic!("expected a runtime error, got {other:?}"),
+        };
+        assert_eq!(error.kind, RuntimeErrorKind::StackOverflow);
+        assert_eq!(error.message, "Stack overflow.");
+        assert!(error.frames.len() <= config.max_frames);
+        assert!(error.frames.len() > 1);
+        assert!(
+            err.contents()
+                .starts_with("Stack overflow.\n[line 1] in f\n")
+        );
+
+        // The VM is usable again once the overflowing script is unwound.
+        assert_eq!(vm.interpret("f;".to_owned()), Ok(()));
+    }
+
+    #[test]
+    fn deep_expressions_are_a_stack_overflow() {
+        let mut vm = VM::with_config(VMConfig {
+            max_stack: 10,
+            ..Default::default()
+        });
+        vm.set_error_output(io::sink());
+        let source = "var a = 1; print 1+(2+(3+(4+(5+(6+(7+(8+(9+(10+(11+a))))))))));";
+        let error = match vm.interpret(source.to_owned()) {
+            Err(InterpretError::Runtime(error)) => error,
+            other => panic!("expected a runtime error, got {other:?}"),
+        };
+        assert_eq!(error.kind, RuntimeErrorKind::StackOverflow);
+    }
+
+    #[test]
+    fn long_traces_keep_only_their_ends() {
+        let error = runtime_error_of("fun f(n) { if (n == 0) return nil + 1; f(n - 1); } f(100);");
+        assert_eq!(error.frames.len(), 102);
+        let report = error.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 1 + 2 * TRACE_EDGE + 1);
+        assert_eq!(lines[TRACE_EDGE + 1], "... 82 more frames ...");
+        assert_eq!(lines.last(), Some(&"[line 1] in <script>"));
+    }
+
+    #[test]
+    fn runtime_error_lists_frames_innermost_first() {
+        let source = "fun inner() {\n  return nil + 1;\n}\nfun outer() {\n  inner();\n}\nouter();";
+        let error = runtime_error_of(source);
+        assert_eq!(
+            error.message,
+            "Operands must be two numbers or two strings."
+        );
+        let frames: Vec<(&str, usize)> = error
+            .frames
+            .iter()
+            .map(|frame| (frame.function.as_str(), frame.line))
+            .collect();
+        assert_eq!(frames, vec![("inner", 2), ("outer", 5), ("<script>", 7)]);
+        assert_eq!(
+            error.to_string(),
+            "Operands must be two numbers or two strings.\n[line 2] in inner\n[line 5] in outer\n[line 7] in <script>"
+        );
+    }
+
+    #[test]
+    fn runtime_error_frames_point_at_the_failing_instruction() {
+        let error = runtime_error_of("print nil + 1;");
+        let function = crate::compiler::compile("print nil + 1;".to_owned()).unwrap();
+        assert_eq!(function.chunk.read(error.frames[0].ip).0, OpCode::Add);
+
+        let source = "fun f() { return nil + 1; }\nf();";
+        let error = runtime_error_of(source);
+        let function = crate::compiler::compile(source.to_owned()).unwrap();
+        assert_eq!(function.chunk.read(error.frames[1].ip).0, OpCode::Call(0));
+    }
+
+    #[test]
+    fn wide_constant_indexes_run() {
+        let mut source: String = (0..300).map(|i| format!("var v{i} = {i};\n")).collect();
+        source.push_str(
+            "class Pair { init(a) { this.first = a; } sum(b) { return this.first + b; } }\n\
+             fun add(a, b) { return a + b; }\n\
+             print Pair(v299).sum(add(v0, v256));\n",
+        );
+        let (result, out, err) = run_captured(&source);
+        assert_eq!(result, Ok(()), "{err}");
+        assert_eq!(out, "555\n");
+    }
+
+    #[test]
+    fn globals_bind_late_and_name_undefined_ones() {
+        let (result, out, _) =
+            run_captured("fun f() { return later; }\nvar later = 7;\nprint f();");
+        assert_eq!(result, Ok(()));
+        assert_eq!(out, "7\n");
+
+        let error = runtime_error_of("fun f() { return later; }\nf();\nvar later = 7;");
+        assert_eq!(error.message, "Undefined variable 'later'.");
+    }
+
+    #[test]
+    fn globals_are_reachable_by_name() {
+        let mut vm = VM::new();
+        vm.set_output(io::sink());
+        vm.set_global("answer", Value::Number(41f64));
+        assert_eq!(
+            vm.interpret("answer = answer + 1; var other = 1;".to_owned()),
+            Ok(())
+        );
+        assert_eq!(vm.get_global("answer"), Some(Value::Number(42f64)));
+        assert!(vm.get_global("missing").is_none());
+        assert_eq!(
+            vm.interpret_repl("print answer + other;".to_owned()),
+            ReplResult::Done(Ok(()))
+        );
+    }
+
+    #[test]
+    fn equal_strings_are_one_object() {
+        let source = r#"
+            var a = "ab";
+            var b = "a" + "b";
+            class C {}
+            print a == b;
+            print C() == C();
+            print argv(0) == "arg";
+        "#;
+        let out = SharedBuffer::new();
+        let mut vm = VM::new();
+        vm.set_output(out.clone());
+        vm.set_args(vec!["arg".to_owned()]);
+        assert_eq!(vm.interpret(source.to_owned()), Ok(()));
+        assert_eq!(out.contents(), "true\nfalse\ntrue\n");
+        assert_eq!(vm.get_global("a"), vm.get_global("b"));
+    }
+
+    #[test]
+    fn each_vm_keeps_its_own_arguments() {
+        let (first_out, second_out) = (SharedBuffer::new(), SharedBuffer::new());
+        let mut first = VM::new();
+        first.set_output(first_out.clone());
+        first.set_args(vec!["a".to_owned(), "b".to_owned()]);
+        let mut second = VM::new();
+        second.set_output(second_out.clone());
+        second.set_args(vec!["c".to_owned()]);
+        let source = "print argc(); print argv(0);";
+        assert_eq!(first.interpret(source.to_owned()), Ok(()));
+        assert_eq!(second.interpret(source.to_owned()), Ok(()));
+        assert_eq!(first_out.contents(), "2\na\n");
+        assert_eq!(second_out.contents(), "1\nc\n");
+    }
+
+    #[test]
+    fn repeated_concatenation_reuses_the_string() {
+        let mut vm = VM::new();
+        vm.set_output(io::sink());
+        let source = r#"for (var i = 0; i < 100; i = i + 1) { var s = "x" + "y"; }"#;
+        assert_eq!(vm.interpret(source.to_owned()), Ok(()));
+        let interned = vm.strings.borrow().len();
+        assert_eq!(vm.interpret(source.to_owned()), Ok(()));
+        assert_eq!(vm.strings.borrow().len(), interned);
+        vm.strings.borrow_mut().intern("xy");
+        assert_eq!(vm.strings.borrow().len(), interned);
+    }
+
+    #[rstest]
+    #[case::class_changes(
+        "class A { m() { return \"A\"; } }
+         class B < A { m() { return \"B\"; } }
+         fun call(o) { return o.m(); }
+         print call(A()); print call(B()); print call(A());",
+        "A\nB\nA\n"
+    )]
+    #[case::field_shadows_cached_method(
+        "class A { m() { return \"method\"; } }
+         fun get(o) { return o.m(); }
+         fun field() { return \"field\"; }
+         var a = A();
+         print get(a); a.m = field; print get(a);",
+        "method\nfield\n"
+    )]
+    #[case::slots_differ_between_classes(
+        "class A { init() { this.x = 1; this.y = 2; } }
+         class B { init() { this.y = 3; } }
+         fun y(o) { return o.y; }
+         print y(A()); print y(B()); print y(A());",
+        "2\n3\n2\n"
+    )]
+    #[case::subclass_shares_field_slots(
+        "class A { init() { this.x = \"x\"; } }
+         class B < A { init() { super.init(); this.y = \"y\"; } }
+         var b = B();
+         print b.x + b.y;",
+        "xy\n"
+    )]
+    fn inline_caches_follow_the_receiver(#[case] source: &str, #[case] expected: &str) {
+        let (result, out, err) = run_captured(source);
+        assert_eq!(result, Ok(()), "{err}");
+        assert_eq!(out, expected);
+    }
+
+    #[rstest]
+    #[case(
+        "class A { init() { this.x = 1; } } class B {}
+         fun x(o) { return o.x; }
+         print x(A()); print x(B());",
+        "Undefined property 'x'."
+    )]
+    #[case(
+        "class A { m() { return 1; } } class B {}
+         fun m(o) { return o.m(); }
+         print m(A()); print m(B());",
+        "Undefined property 'm'."
+    )]
+    #[case(
+        "class A {} class B < A { m() { return super.m(); } }
+         print 1; print B().m();",
+        "Undefined property 'm'."
+    )]
+    fn cached_sites_still_report_missing_properties(#[case] source: &str, #[case] message: &str) {
+        let (result, out, err) = run_captured(source);
+        assert!(matches!(result, Err(InterpretError::Runtime(_))));
+        assert_eq!(out, "1\n");
+        assert!(err.starts_with(message), "{err}");
+    }
+
+    #[rstest]
+    #[case("var o = C(); o.me = o;")]
+    #[case("var o = C(); o.m = o.method;")]
+    #[case("fun make() { var f; fun g() { return f; } f = g; return g; } var o = make();")]
+    #[case("var o = [1]; o.push(o);")]
+    #[case("var o = Map(); o[\"me\"] = o;")]
+    fn cycles_are_reclaimed(#[case] source: &str) {
+        let mut vm = VM::new();
+        let source = format!("class C {{ method() {{}} }}\n{source}");
+        assert_eq!(vm.interpret(source), Ok(()));
+        let object = match vm.get_global("o") {
+            Some(object) if object.is_obj() => Rc::downgrade(&object.as_obj()),
+            other => panic!("expected an object, got {other:?}"),
+        };
+        assert_eq!(vm.interpret("o = nil;".to_owned()), Ok(()));
+        assert!(object.upgrade().is_some(), "the cycle keeps itself alive");
+
+        vm.collect_garbage();
+        assert!(object.upgrade().is_none());
+        assert!(vm.gc_stats().objects_freed > 0);
+    }
+
+    #[test]
+    fn collection_keeps_reachable_objects() {
+        let source = r#"
+            class C { method() { return this.value; } }
+            fun counter() { var i = 0; fun next() { i = i + 1; return i; } return next; }
+            var c = C();
+            c.value = "kept";
+            c.self = c;
+            var next = counter();
+            next();
+        "#;
+        let (mut vm, out) = (VM::new(), SharedBuffer::new());
+        vm.set_output(out.clone());
+        assert_eq!(vm.interpret(source.to_owned()), Ok(()));
+        vm.collect_garbage();
+        assert_eq!(
+            vm.interpret("print c.self.method(); print next();".to_owned()),
+            Ok(())
+        );
+        assert_eq!(out.contents(), "kept\n2\n");
+    }
+
+    #[test]
+    fn collection_keeps_what_the_embedder_holds() {
+        let source = r#"
+            class C {}
+            var c = C();
+            c.me = c;
+            c.items = [1, 2];
+            c.items.push(c.items);
+        "#;
+        let (mut vm, out) = (VM::new(), SharedBuffer::new());
+        vm.set_output(out.clone());
+        assert_eq!(vm.interpret(source.to_owned()), Ok(()));
+        let held = vm.get_global("c").expect("c is defined");
+        assert_eq!(vm.interpret("c = nil;".to_owned()), Ok(()));
+
+        vm.collect_garbage();
+        vm.set_global("c", held);
         assert_eq!(
-            vm.interpret(source.to_string()),
-            InterpretResult::InterpretOk
+            vm.interpret("print c.me == c; print c.items[1];".to_owned()),
+            Ok(())
+        );
+        assert_eq!(out.contents(), "true\n2\n");
+    }
+
+    #[test]
+    fn stress_gc_matches_a_normal_run() {
+        for source in [
+            SOURCE_CLOSURES,
+            SOURCE_CLASS6,
+            SOURCE_INHERITANCE3,
+            SOURCE_FIB,
+        ] {
+            let (result, out, _) = run_captured(source);
+            let stressed = SharedBuffer::new();
+            let mut vm = VM::new();
+            vm.set_output(stressed.clone());
+            vm.set_stress_gc(true);
+            assert_eq!(vm.interpret(source.to_owned()), result);
+            assert_eq!(stressed.contents(), out);
+            assert!(vm.gc_stats().collections > 0);
+        }
+    }
+
+    #[rstest]
+    #[case(SOURCE_CLOSURES)]
+    #[case(SOURCE_CLASS6)]
+    #[case(SOURCE_INHERITANCE3)]
+    #[case(SOURCE_FIB)]
+    #[case("fun f(a) { var b = \"x\"; return a + b; }\nprint f(\"y\");\nf(1);")]
+    #[case(
+        "a: for (var i = 0; i < 9; i = i + 1) { while (true) { if (i < 5) continue a; break a; } print i; }"
+    )]
+    fn optimized_code_matches_a_normal_run(#[case] source: &str) {
+        let (result, out, err) = run_captured(source);
+        let (optimized_out, optimized_err) = (SharedBuffer::new(), SharedBuffer::new());
+        let mut vm = VM::new();
+        vm.set_output(optimized_out.clone());
+        vm.set_error_output(optimized_err.clone());
+        vm.set_opt_level(OptLevel::O1);
+        // Offsets in the frames move with the code; the reported lines must not.
+        assert_eq!(vm.interpret(source.to_owned()).is_ok(), result.is_ok());
+        assert_eq!(optimized_out.contents(), out);
+        assert_eq!(optimized_err.contents(), err);
+    }
+
+    #[rstest]
+    #[case::modulo(
+        "var a = 7; var b = -7; print a % 3; print b % 3; print 5.5 % 2;",
+        "1\n-1\n1.5\n"
+    )]
+    #[case::power(
+        "var a = 2; print a ** 10; print a ** 3 ** 2; print -a ** 2; print a ** -1;",
+        "1024\n512\n-4\n0.5\n"
+    )]
+    #[case::bitwise(
+        "var a = 6; print a & 3; print a | 3; print a ^ 3; print ~a;",
+        "2\n7\n5\n-7\n"
+    )]
+    #[case::shifts(
+        "var a = 1; print a << 4; print -16 >> a; print a << 64; print -a >> 64;",
+        "16\n-8\n0\n-1\n"
+    )]
+    #[case::precedence(
+        "var a = 1; print a | 2 == 3; print a + 1 << 2; print 2 * a ** 2;",
+        "true\n8\n2\n"
+    )]
+    #[case::compound_modulo(
+        "var a = 10; a %= 4; var xs = [9]; xs[0] %= 5; print a; print xs[0];",
+        "2\n4\n"
+    )]
+    fn numeric_operators_compute(#[case] source: &str, #[case] expected: &str) {
+        let (result, out, err) = run_captured(source);
+        assert_eq!(result, Ok(()), "{err}");
+        assert_eq!(out, expected);
+    }
+
+    #[rstest]
+    #[case("var a = \"s\"; print a % 2;", "Operands must be numbers.")]
+    #[case("var a = nil; print 2 ** a;", "Operands must be numbers.")]
+    #[case("var a = \"s\"; print a & 1;", "Operands must be numbers.")]
+    #[case("var a = 1.5; print a | 1;", "Operands must be integers.")]
+    #[case("var a = 1; print a ^ (0 / 0);", "Operands must be integers.")]
+    #[case("var a = 1; print a << 2 ** 70;", "Operands must be integers.")]
+    #[case("var a = -1; print 1 >> a;", "Shift count must not be negative.")]
+    #[case("var a = 0.5; print ~a;", "Operand must be an integer.")]
+    #[case("var a = nil; print ~a;", "Operand must be a number.")]
+    fn numeric_operators_check_their_operands(#[case] source: &str, #[case] message: &str) {
+        let error = runtime_error_of(source);
+        assert_eq!(error.kind, RuntimeErrorKind::TypeError);
+        assert_eq!(error.message, message);
+    }
+
+    #[test]
+    fn folded_expressions_print_like_computed_ones() {
+        let (result, out, _) = run_captured(
+            "var two = 2; var t = true;
+             print -1; print !true; print 1 + 2 * 3; print \"a\" + \"b\" == \"ab\";
+             print -two + 1; print !t; print 1 + two * 3; print \"a\" + \"b\" == \"a\" + \"b\";",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(out, "-1\nfalse\n7\ntrue\n-1\nfalse\n7\ntrue\n");
+    }
+
+    #[rstest]
+    #[case::break_while(
+        "var i = 0; while (true) { i = i + 1; if (i == 3) break; print i; } print \"end\";",
+        "1\n2\nend\n"
+    )]
+    #[case::continue_runs_the_increment(
+        "for (var i = 0; i < 5; i = i + 1) { if (i == 1 or i == 3) continue; print i; }",
+        "0\n2\n4\n"
+    )]
+    #[case::locals_are_popped(
+        "var a = \"a\";
+         for (var i = 0; i < 3; i = i + 1) { var x = i; var y = x * 2; if (x == 1) continue; print a + \"\"; }
+         print a;",
+        "a\na\na\n"
+    )]
+    #[case::labels_leave_outer_loops(
+        "outer: for (var i = 0; i < 3; i = i + 1) {
+           for (var j = 0; j < 3; j = j + 1) {
+             if (j == 1) continue outer;
+             if (i == 2) break outer;
+             print i * 10 + j;
+           }
+         }
+         print \"end\";",
+        "0\n10\nend\n"
+    )]
+    #[case::captured_locals_are_closed(
+        "var fs = nil; var gs = nil;
+         outer: for (var i = 0; i < 2; i = i + 1) {
+           var a = i;
+           while (true) {
+             var b = a * 10;
+             fun f() { return b; }
+             if (a == 0) { fs = f; continue outer; }
+             gs = f;
+             break outer;
+           }
+         }
+         var c = \"filler\";
+         print fs(); print gs();",
+        "0\n10\n"
+    )]
+    #[case::locals_captured_after_the_exit_are_closed(
+        "var saved;
+         outer: while (true) {
+           var x = \"x\"; var i = 0;
+           while (true) {
+             if (i == 1) break outer;
+             fun f() { print x; }
+             saved = f;
+             i = i + 1;
+           }
+         }
+         { var y = \"clobber\"; saved(); }",
+        "x\n"
+    )]
+    #[case::loops_in_functions(
+        "fun first(n) { var i = 0; while (true) { if (i * i > n) return i; i = i + 1; } }
+         fun f() { var k = 0; for (;;) { k = k + 1; if (k > 4) break; } return k; }
+         print first(10); print f();",
+        "4\n5\n"
+    )]
+    fn loops_can_be_left_early(#[case] source: &str, #[case] expected: &str) {
+        let (result, out, err) = run_captured(source);
+        assert_eq!(result, Ok(()), "{err}");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn closures_keep_block_locals_they_capture() {
+        let (result, out, _) = run_captured(
+            "var f; var g;
+             { var a = \"a\"; fun get() { return a; } f = get; }
+             { var b = \"b\"; fun outer() { fun inner() { return b; } return inner; } g = outer(); }
+             var c = \"c\";
+             print f(); print g();",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(out, "a\nb\n");
+    }
+
+    #[rstest]
+    #[case::literals(
+        "print [1, \"a\", nil, [true]]; print [];",
+        "[1, a, nil, [true]]\n[]\n"
+    )]
+    #[case::get_and_set(
+        "var xs = [1, 2, 3]; xs[1] = xs[0] + xs[2]; print xs[1]; print xs[2] = 7; print xs;",
+        "4\n7\n[1, 4, 7]\n"
+    )]
+    #[case::compound_assignment(
+        "var xs = [1, [2]]; var i = 0; xs[i] += 10; xs[1][i] *= 3; xs[0] -= 1; print xs;",
+        "[10, [6]]\n"
+    )]
+    #[case::push_pop_and_len(
+        "var xs = []; print xs.push(1); xs.push(2); print xs.len(); print xs.pop(); print xs;",
+        "nil\n2\n2\n[1]\n"
+    )]
+    #[case::insert_and_remove(
+        "var xs = [1, 3]; xs.insert(1, 2); xs.insert(3, 4); print xs; print xs.remove(0); print xs;",
+        "[1, 2, 3, 4]\n1\n[2, 3, 4]\n"
+    )]
+    #[case::slice(
+        "var xs = [1, 2, 3]; var ys = xs.slice(1); ys[0] = 0; print xs; print ys; print xs.slice(0, 2); print xs.slice(3);",
+        "[1, 2, 3]\n[0, 3]\n[1, 2]\n[]\n"
+    )]
+    #[case::self_containing(
+        "var xs = [1]; xs.push(xs); print xs; print [xs];",
+        "[1, [...]]\n[[1, [...]]]\n"
+    )]
+    #[case::lists_are_compared_by_identity(
+        "var xs = [1]; var ys = xs; print xs == ys; print xs == [1];",
+        "true\nfalse\n"
+    )]
+    #[case::map_get_and_set(
+        "var m = Map(); m[\"a\"] = 1; m[2] = \"two\"; m[true] = false; m[nil] = nil; print m[\"a\"]; print m[2]; print m[true]; print m[nil];",
+        "1\ntwo\nfalse\nnil\n"
+    )]
+    #[case::map_insertion_order(
+        "var m = Map(); m[\"b\"] = 1; m[\"a\"] = 2; m[\"c\"] = 3; m[\"b\"] = 4; print m; print m.keys(); print m.values();",
+        "{b: 4, a: 2, c: 3}\n[b, a, c]\n[4, 2, 3]\n"
+    )]
+    #[case::map_delete_keeps_order(
+        "var m = Map(); m[1] = 1; m[2] = 2; m[3] = 3; print m.delete(2); print m.delete(2); m[2] = 4; print m; print m[3];",
+        "true\nfalse\n{1: 1, 3: 3, 2: 4}\n3\n"
+    )]
+    #[case::map_has_and_len(
+        "var m = Map(); print m.len(); m[\"x\"] = nil; print m.has(\"x\"); print m.has(\"y\"); print m.len();",
+        "0\ntrue\nfalse\n1\n"
+    )]
+    #[case::map_compound_assignment(
+        "var m = Map(); m[\"n\"] = 1; m[\"n\"] += 2; m[\"n\"] *= 5; print m[\"n\"];",
+        "15\n"
+    )]
+    #[case::map_numbers_compare_by_value(
+        "var m = Map(); m[0] = \"zero\"; print m[-0]; print m[0.0 + 0];",
+        "zero\nzero\n"
+    )]
+    #[case::map_strings_compare_by_text(
+        "var m = Map(); m[\"ab\"] = 1; var k = \"a\" + \"b\"; print m[k];",
+        "1\n"
+    )]
+    #[case::map_self_containing(
+        "var m = Map(); m[\"me\"] = m; print m; print m[\"me\"] == m;",
+        "{me: {...}}\ntrue\n"
+    )]
+    fn collections_hold_values(#[case] source: &str, #[case] expected: &str) {
+        let (result, out, err) = run_captured(source);
+        assert_eq!(result, Ok(()), "{err}");
+        assert_eq!(out, expected);
+    }
+
+    #[rstest]
+    #[case(
+        "print [1, 2][2];",
+        RuntimeErrorKind::IndexOutOfRange,
+        "Index 2 is out of range for a list of length 2."
+    )]
+    #[case(
+        "var xs = []; xs[0] = 1;",
+        RuntimeErrorKind::IndexOutOfRange,
+        "Index 0 is out of range for a list of length 0."
+    )]
+    #[case(
+        "print [1][-1];",
+        RuntimeErrorKind::IndexOutOfRange,
+        "Index -1 is out of range for a list of length 1."
+    )]
+    #[case(
+        "[1].insert(2, 0);",
+        RuntimeErrorKind::IndexOutOfRange,
+        "Index 2 is out of range for a list of length 1."
+    )]
+    #[case(
+        "[1].remove(1);",
+        RuntimeErrorKind::IndexOutOfRange,
+        "Index 1 is out of range for a list of length 1."
+    )]
+    #[case(
+        "[1, 2].slice(0, 3);",
+        RuntimeErrorKind::IndexOutOfRange,
+        "Index 3 is out of range for a list of length 2."
+    )]
+    #[case(
+        "[].pop();",
+        RuntimeErrorKind::IndexOutOfRange,
+        "Can't pop from an empty list."
+    )]
+    #[case(
+        "print [1][0.5];",
+        RuntimeErrorKind::TypeError,
+        "List index must be an integer."
+    )]
+    #[case(
+        "print [1][\"0\"];",
+        RuntimeErrorKind::TypeError,
+        "List index must be an integer."
+    )]
+    #[case(
+        "var x = 1; print x[0];",
+        RuntimeErrorKind::TypeError,
+        "Only lists and maps can be indexed."
+    )]
+    #[case(
+        "[].push();",
+        RuntimeErrorKind::ArityMismatch,
+        "Expected 1 arguments but got 0."
+    )]
+    #[case(
+        "[1].slice();",
+        RuntimeErrorKind::ArityMismatch,
+        "Expected 1 or 2 arguments but got 0."
+    )]
+    #[case(
+        "[].sort();",
+        RuntimeErrorKind::UndefinedProperty,
+        "Undefined property 'sort'."
+    )]
+    #[case(
+        "print Map()[\"a\"];",
+        RuntimeErrorKind::UndefinedKey,
+        "Undefined key 'a'."
+    )]
+    #[case(
+        "var m = Map(); m[1] = 1; m.delete(1); print m[1];",
+        RuntimeErrorKind::UndefinedKey,
+        "Undefined key '1'."
+    )]
+    #[case(
+        "var m = Map(); m[[]] = 1;",
+        RuntimeErrorKind::TypeError,
+        "Map keys must be strings, numbers, booleans or nil."
+    )]
+    #[case(
+        "var m = Map(); print m.has(m);",
+        RuntimeErrorKind::TypeError,
+        "Map keys must be strings, numbers, booleans or nil."
+    )]
+    #[case(
+        "Map(1);",
+        RuntimeErrorKind::ArityMismatch,
+        "Expected 0 arguments but got 1."
+    )]
+    #[case(
+        "Map().has();",
+        RuntimeErrorKind::ArityMismatch,
+        "Expected 1 arguments but got 0."
+    )]
+    #[case(
+        "Map().push(1);",
+        RuntimeErrorKind::UndefinedProperty,
+        "Undefined property 'push'."
+    )]
+    fn collection_misuse_is_a_runtime_error(
+        #[case] source: &str,
+        #[case] kind: RuntimeErrorKind,
+        #[case] message: &str,
+    ) {
+        let error = runtime_error_of(source);
+        assert_eq!(error.kind, kind);
+        assert_eq!(error.message, message);
+    }
+
+    #[rstest]
+    #[case::escapes(
+        r#"print "a\tb\\c \"q\" \u{e9}\u{1F600}\nnext";"#,
+        "a\tb\\c \"q\" \u{e9}\u{1F600}\nnext\n"
+    )]
+    #[case::interpolation(r#"var name = "Lox"; print "Hello ${name}!";"#, "Hello Lox!\n")]
+    #[case::values_are_printed(
+        r#"var xs = [1, "a"]; print "${1 + 2.5} ${true} ${nil} ${xs} ${clock}";"#,
+        "3.5 true nil [1, a] <native fn>\n"
+    )]
+    #[case::nested(r#"var a = "x"; print "<${"[${a}]" + a}>";"#, "<[x]x>\n")]
+    #[case::escaped_dollar(r#"print "\${not} $ {x}";"#, "${not} $ {x}\n")]
+    #[case::result_is_interned(r#"var n = 1; print "n${n}" == "n1";"#, "true\n")]
+    #[case::calls_and_methods(
+        r#"fun f(x) { return x * 2; } var xs = [1]; print "${f(2)}/${xs.len()}";"#,
+        "4/1\n"
+    )]
+    fn strings_support_escapes_and_interpolation(#[case] source: &str, #[case] expected: &str) {
+        let (result, out, err) = run_captured(source);
+        assert_eq!(result, Ok(()), "{err}");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn allocation_volume_triggers_collection() {
+        let mut vm = VM::new();
+        let source = "class C {} for (var i = 0; i < 100000; i = i + 1) { var c = C(); c.me = c; }";
+        assert_eq!(vm.interpret(source.to_owned()), Ok(()));
+        assert!(vm.gc_stats().collections > 0);
+        // Whatever the objects weigh, none of the cycles outlives a full collection.
+        vm.collect_garbage();
+        let stats = vm.gc_stats();
+        assert!(
+            stats.objects_allocated - stats.objects_freed < 100,
+            "{stats}"
         );
     }
+
+    #[test]
+    fn compile_errors_write_to_error_sink() {
+        let (result, out, err) = run_captured("print 1");
+        assert!(matches!(result, Err(InterpretError::Compile(_))));
+        assert_eq!(out, "");
+        assert_eq!(err, "[line 1] Error at end: Expect ';' after value.\n");
+    }
+
+    #[test]
+    fn compile_errors_can_be_rendered() {
+        let err = SharedBuffer::new();
+        let mut vm = VM::new();
+        vm.set_error_output(err.clone());
+        vm.set_render_diagnostics(true);
+        let source = "var a = 1;\nprint a +;";
+        let Err(InterpretError::Compile(diagnostics)) = vm.interpret(source.to_owned()) else {
+            panic!("expected a compile error");
+        };
+        assert_eq!(err.contents(), diagnostics[0].render(source));
+        assert!(err.contents().contains("2 | print a +;\n"));
+    }
 }
@@ -0,0 +1,61 @@
+//! Guards against functions and closures being copied when they are called, bound or
+//! closed over. The same calls are timed with a tiny function body and with a large one
+//! that never runs; sharing makes the two cost the same, copying makes the large one
+//! slower in proportion to its size.
+//!
+//! Wall-clock timings are too noisy for every test run, so this only runs when asked for
+//! with `cargo test --release --test call_overhead -- --ignored`. The check that always
+//! runs is `calls_share_the_compiled_function` in the VM's unit tests.
+
+mod common;
+
+use common::{Outcome, run_rust};
+
+const ITERATIONS: usize = 20_000;
+const LARGE_BODY: usize = 5_000;
+/// Best-of-N keeps a busy machine from failing the comparison.
+const ATTEMPTS: usize = 3;
+const MAX_SLOWDOWN: f64 = 3.0;
+
+/// A script that makes, calls, invokes and binds functions whose unreachable body
+/// holds `statements` statements, and prints the seconds that took.
+fn script(statements: usize) -> String {
+    let body = "print n + 1;".repeat(statements);
+    format!(
+        "class C {{ m(n) {{ if (n < 0) {{ {body} }} return n; }} }}\n\
+         fun make() {{ fun f(n) {{ if (n < 0) {{ {body} }} return n; }} return f; }}\n\
+         var c = C();\n\
+         var start = clock();\n\
+         for (var i = 0; i < {ITERATIONS}; i = i + 1) {{\n\
+           make()(i);\n\
+           c.m(i);\n\
+           var bound = c.m;\n\
+           bound(i);\n\
+         }}\n\
+         print clock() - start;\n"
+    )
+}
+
+fn seconds(statements: usize) -> f64 {
+    let source = script(statements);
+    (0..ATTEMPTS)
+        .map(|_| {
+            let run = run_rust(&source);
+            assert_eq!(run.outcome, Outcome::Ok, "{}", run.stderr);
+            run.stdout.trim().parse::<f64>().expect("elapsed seconds")
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+#[test]
+#[ignore = "timing-sensitive"]
+fn calls_do_not_copy_function_bodies() {
+    let small = seconds(0);
+    let large = seconds(LARGE_BODY);
+    // Guard against a zero baseline on a coarse clock.
+    let slowdown = large / small.max(0.001);
+    assert!(
+        slowdown < MAX_SLOWDOWN,
+        "a {LARGE_BODY}-statement body made calls {slowdown:.1}x slower ({large:.3}s vs {small:.3}s)"
+    );
+}
@@ -0,0 +1,138 @@
+//! Helpers shared by the integration tests that run the `lox_scripts` corpus.
+// Each test binary compiles this module on its own and uses a different subset of it.
+#![allow(dead_code)]
+
+use compiler::{InterpretError, SharedBuffer, VM};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    CompileError,
+    RuntimeError,
+    /// An external implementation exited with a status outside the clox convention.
+    Crashed(Option<i32>),
+    TimedOut,
+}
+
+impl Outcome {
+    /// Reads an exit status using the clox convention of 65 and 70 for compile and runtime errors.
+    pub fn from_exit_code(code: Option<i32>) -> Self {
+        match code {
+            Some(0) => Outcome::Ok,
+            Some(65) => Outcome::CompileError,
+            Some(70) => Outcome::RuntimeError,
+            other => Outcome::Crashed(other),
+        }
+    }
+}
+
+pub struct Run {
+    pub outcome: Outcome,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+pub fn scripts_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../lox_scripts")
+}
+
+pub fn lox_scripts() -> Vec<PathBuf> {
+    let mut scripts: Vec<PathBuf> = fs::read_dir(scripts_dir())
+        .expect("lox_scripts directory should exist")
+        .map(|entry| entry.expect("readable directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    scripts.sort();
+    assert!(
+        !scripts.is_empty(),
+        "no scripts found in {:?}",
+        scripts_dir()
+    );
+    scripts
+}
+
+pub fn script_name(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    format!("lox_scripts/{name}")
+}
+
+/// Runs `source` on a fresh VM with its output and errors captured.
+pub fn run_rust(source: &str) -> Run {
+    run_rust_with(source, |_| {})
+}
+
+/// Like [`run_rust`], letting `setup` configure the VM first.
+pub fn run_rust_with(source: &str, setup: impl FnOnce(&mut VM)) -> Run {
+    let out = SharedBuffer::new();
+    let err = SharedBuffer::new();
+    let mut vm = VM::new();
+    setup(&mut vm);
+    vm.set_output(out.clone());
+    vm.set_error_output(err.clone());
+    let outcome = match vm.interpret(source.to_owned()) {
+        Ok(()) => Outcome::Ok,
+        Err(InterpretError::Compile(_)) => Outcome::CompileError,
+        Err(InterpretError::Runtime(_)) => Outcome::RuntimeError,
+    };
+    Run {
+        outcome,
+        stdout: out.contents(),
+        stderr: err.contents(),
+    }
+}
+
+/// What an `// expect any` annotation stands for: one printed line whose text changes
+/// from run to run, such as a timing.
+pub const ANY_LINE: &str = "<any>";
+
+/// Replaces each printed line that `source` annotates with `// expect any` by
+/// [`ANY_LINE`], counting printed lines by their `// expect` annotations.
+pub fn mask_varying_lines(source: &str, output: &mut [String]) {
+    let annotations = source
+        .lines()
+        .filter_map(|line| line.split_once("// ").map(|(_, comment)| comment))
+        .filter(|comment| comment.starts_with("expect: ") || *comment == "expect any");
+    for (line, comment) in output.iter_mut().zip(annotations) {
+        if comment == "expect any" {
+            *line = ANY_LINE.to_owned();
+        }
+    }
+}
+
+pub fn lines(text: &str) -> Vec<String> {
+    text.lines().map(str::to_owned).collect()
+}
+
+/// A line diff of `expected` against `actual`, with `-` for missing and `+` for extra lines.
+pub fn diff(expected: &[String], actual: &[String]) -> String {
+    let (n, m) = (expected.len(), actual.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            out.push_str(&format!("   {}\n", expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push_str(&format!(" - {}\n", expected[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!(" + {}\n", actual[j]));
+            j += 1;
+        }
+    }
+    out
+}
@@ -0,0 +1,279 @@
+//! Runs every script in `lox_scripts` through this VM and through whichever sibling
+//! implementations are available locally, and reports the scripts where their stdout
+//! or error class disagree.
+//!
+//! - The C VM is used when `CLOX` names its binary, or when `compiler_in_c/vm` has
+//!   been built and is executable (`gcc *.c -o vm`).
+//! - The Python tree-walker is used when `LOX_PYTHON`, `interpreter_in_python/.venv`
+//!   or `python3` can import it together with its dependencies.
+//!
+//! The comparison needs at least one of them, so it only runs when asked for with
+//! `cargo test --test differential -- --ignored`, and fails if none can be found.
+
+mod common;
+
+use common::{Outcome, Run, diff, lines, lox_scripts, mask_varying_lines, run_rust, script_name};
+use std::env;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Reads the script from `argv[1]` and exits with the clox status codes.
+const PYTHON_DRIVER: &str = "\
+import sys
+from src.lox import Lox
+lox = Lox()
+with open(sys.argv[1]) as f:
+    lox.run(source=f.read(), repl=False)
+sys.exit(70 if lox.interpreter.had_error else 65 if lox.had_error else 0)
+";
+
+enum Implementation {
+    C(PathBuf),
+    Python(PathBuf),
+}
+
+impl Implementation {
+    fn name(&self) -> &'static str {
+        match self {
+            Implementation::C(_) => "compiler_in_c",
+            Implementation::Python(_) => "interpreter_in_python",
+        }
+    }
+
+    fn command(&self, script: &Path) -> Command {
+        match self {
+            Implementation::C(binary) => {
+                let mut command = Command::new(binary);
+                command.arg(script);
+                command
+            }
+            Implementation::Python(python) => {
+                let mut command = Command::new(python);
+                command
+                    .current_dir(repo_root().join("interpreter_in_python"))
+                    .args(["-c", PYTHON_DRIVER])
+                    .arg(script);
+                command
+            }
+        }
+    }
+
+    fn run(&self, script: &Path) -> Run {
+        let child = self
+            .command(script)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("implementation should start");
+        let mut run = wait_with_timeout(child);
+        if let Implementation::Python(_) = self {
+            normalize_python(&mut run);
+        }
+        run
+    }
+}
+
+fn repo_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../..")
+}
+
+fn wait_with_timeout(mut child: Child) -> Run {
+    // Drain the pipes on their own threads so a chatty child cannot block on a full pipe.
+    let mut stdout = child.stdout.take().expect("piped stdout");
+    let mut stderr = child.stderr.take().expect("piped stderr");
+    let stdout = thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stdout.read_to_string(&mut text);
+        text
+    });
+    let stderr = thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stderr.read_to_string(&mut text);
+        text
+    });
+
+    let deadline = Instant::now() + TIMEOUT;
+    let outcome = loop {
+        if let Some(status) = child.try_wait().expect("child status") {
+            break Outcome::from_exit_code(status.code());
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break Outcome::TimedOut;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    Run {
+        outcome,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }
+}
+
+/// The Python interpreter prints its errors to stdout; keep only what the script printed.
+fn normalize_python(run: &mut Run) {
+    let mut stdout = lines(&run.stdout);
+    match run.outcome {
+        Outcome::CompileError => stdout.clear(),
+        // A runtime error ends with the message and a `[line N ]` line.
+        Outcome::RuntimeError => stdout.truncate(stdout.len().saturating_sub(2)),
+        _ => return,
+    }
+    run.stdout = stdout.iter().map(|line| format!("{line}\n")).collect();
+}
+
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+fn find_c() -> Option<Implementation> {
+    let binary = match env::var_os("CLOX") {
+        Some(path) => PathBuf::from(path),
+        None => repo_root().join("compiler_in_c/vm"),
+    };
+    is_executable(&binary).then_some(Implementation::C(binary))
+}
+
+fn find_python() -> Option<Implementation> {
+    let mut candidates: Vec<PathBuf> = env::var_os("LOX_PYTHON")
+        .map(PathBuf::from)
+        .into_iter()
+        .collect();
+    candidates.push(repo_root().join("interpreter_in_python/.venv/bin/python"));
+    candidates.push(PathBuf::from("python3"));
+
+    candidates.into_iter().find_map(|python| {
+        let imports = Command::new(&python)
+            .current_dir(repo_root().join("interpreter_in_python"))
+            .args(["-c", "import src.lox"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        imports.then_some(Implementation::Python(python))
+    })
+}
+
+/// Describes how `other` disagrees with the Rust VM on `source`, if it does.
+fn compare(source: &str, rust: &Run, other: &Run, name: &str) -> Option<String> {
+    let mut report = String::new();
+    if rust.outcome != other.outcome {
+        report.push_str(&format!(
+            "  error class: rust {:?}, {name} {:?}\n",
+            rust.outcome, other.outcome
+        ));
+    }
+    let (mut expected, mut actual) = (lines(&rust.stdout), lines(&other.stdout));
+    mask_varying_lines(source, &mut expected);
+    mask_varying_lines(source, &mut actual);
+    if expected != actual {
+        report.push_str(&format!(
+            "  stdout (- rust, + {name}):\n{}",
+            diff(&expected, &actual)
+        ));
+    }
+    if other.outcome != rust.outcome && !other.stderr.is_empty() {
+        report.push_str(&format!("  {name} stderr:\n{}", other.stderr));
+    }
+    (!report.is_empty()).then_some(report)
+}
+
+#[test]
+#[ignore = "needs the C VM or the Python interpreter"]
+fn implementations_agree_on_lox_scripts() {
+    let implementations: Vec<Implementation> = find_c().into_iter().chain(find_python()).collect();
+    assert!(
+        !implementations.is_empty(),
+        "neither the C VM nor the Python interpreter is available to compare against"
+    );
+
+    let mut report = String::new();
+    for path in &lox_scripts() {
+        let source = std::fs::read_to_string(path).expect("script should be readable");
+        let rust = run_rust(&source);
+        for implementation in &implementations {
+            let other = implementation.run(path);
+            if let Some(disagreement) = compare(&source, &rust, &other, implementation.name()) {
+                report.push_str(&format!(
+                    "{} disagrees with {}:\n{disagreement}",
+                    script_name(path),
+                    implementation.name()
+                ));
+            }
+        }
+    }
+    assert!(report.is_empty(), "implementations disagree:\n{report}");
+}
+
+#[test]
+fn python_errors_are_stripped_from_stdout() {
+    let mut run = Run {
+        outcome: Outcome::RuntimeError,
+        stdout: "1\n2\nOperands must be numbers.\n[line 3 ]\n".to_owned(),
+        stderr: String::new(),
+    };
+    normalize_python(&mut run);
+    assert_eq!(run.stdout, "1\n2\n");
+
+    let mut run = Run {
+        outcome: Outcome::CompileError,
+        stdout: "Expect ';' after value.\n".to_owned(),
+        stderr: String::new(),
+    };
+    normalize_python(&mut run);
+    assert_eq!(run.stdout, "");
+}
+
+#[test]
+fn compare_skips_lines_annotated_as_varying() {
+    let source = "print clock(); // expect any\nprint 1; // expect: 1\n";
+    let run = |stdout: &str| Run {
+        outcome: Outcome::Ok,
+        stdout: stdout.to_owned(),
+        stderr: String::new(),
+    };
+    assert_eq!(
+        compare(source, &run("0.5\n1\n"), &run("0.7\n1\n"), "other"),
+        None
+    );
+    assert!(compare(source, &run("0.5\n1\n"), &run("0.7\n2\n"), "other").is_some());
+}
+
+#[test]
+fn compare_reports_class_and_output_differences() {
+    let rust = Run {
+        outcome: Outcome::Ok,
+        stdout: "4\n".to_owned(),
+        stderr: String::new(),
+    };
+    let source = "print 2 + 2;";
+    assert_eq!(compare(source, &rust, &run_rust(source), "same"), None);
+
+    let other = Run {
+        outcome: Outcome::RuntimeError,
+        stdout: "4.0\n".to_owned(),
+        stderr: "boom\n".to_owned(),
+    };
+    assert_eq!(
+        compare(source, &rust, &other, "other").as_deref(),
+        Some(
+            "  error class: rust Ok, other RuntimeError\n  stdout (- rust, + other):\n - 4\n + 4.0\n  other stderr:\nboom\n"
+        )
+    );
+}
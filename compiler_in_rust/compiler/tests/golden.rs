@@ -0,0 +1,181 @@
+//! Runs the scripts in `lox_scripts` against the annotations used by the Crafting
+//! Interpreters test suite:
+//!
+//! - `// expect: text` for each line the script prints, in order, or `// expect any`
+//!   for a line whose text changes from run to run;
+//! - `// Error at 'x': message` or `// [line N] Error ...` for each compile error;
+//! - `// expect runtime error: message` on the line that raises a runtime error.
+
+mod common;
+
+use common::{
+    ANY_LINE, Outcome, diff, lines, lox_scripts, mask_varying_lines, run_rust_with, script_name,
+};
+use compiler::{OptLevel, VM};
+use std::fs;
+use std::path::Path;
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    errors: Vec<String>,
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expectations = Expectations::default();
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let Some((_, comment)) = line.split_once("// ") else {
+                continue;
+            };
+            if let Some(text) = comment.strip_prefix("expect: ") {
+                expectations.output.push(text.to_owned());
+            } else if comment == "expect any" {
+                expectations.output.push(ANY_LINE.to_owned());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((message.to_owned(), line_number));
+            } else if comment.starts_with("Error") {
+                expectations
+                    .errors
+                    .push(format!("[line {line_number}] {comment}"));
+            } else if let Some(rest) = comment.strip_prefix("[") {
+                // `[line N] Error ...`, or `[c line N] Error ...` for clox-specific errors.
+                let rest = rest.strip_prefix("c ").unwrap_or(rest);
+                if rest.starts_with("line ") && rest.contains("] Error") {
+                    expectations.errors.push(format!("[{rest}"));
+                }
+            }
+        }
+        expectations
+    }
+
+    fn outcome(&self) -> Outcome {
+        if !self.errors.is_empty() {
+            Outcome::CompileError
+        } else if self.runtime_error.is_some() {
+            Outcome::RuntimeError
+        } else {
+            Outcome::Ok
+        }
+    }
+
+    fn error_lines(&self) -> Vec<String> {
+        match &self.runtime_error {
+            Some((message, line)) => vec![message.clone(), format!("[line {line}]")],
+            None => self.errors.clone(),
+        }
+    }
+}
+
+/// Runs one script on a VM prepared by `setup` and describes every way it differs
+/// from its annotations.
+fn check(path: &Path, setup: fn(&mut VM)) -> Vec<String> {
+    let source = fs::read_to_string(path).expect("script should be readable");
+    let expected = Expectations::parse(&source);
+
+    let run = run_rust_with(&source, setup);
+    let outcome = run.outcome;
+
+    let mut failures = Vec::new();
+    if outcome != expected.outcome() {
+        failures.push(format!(
+            "expected {:?} but got {outcome:?}",
+            expected.outcome()
+        ));
+    }
+
+    let mut output = lines(&run.stdout);
+    mask_varying_lines(&source, &mut output);
+    if output != expected.output {
+        failures.push(format!(
+            "output differs:\n{}",
+            diff(&expected.output, &output)
+        ));
+    }
+
+    let mut errors = lines(&run.stderr);
+    if outcome == Outcome::RuntimeError {
+        // Only the message and the line of the innermost frame are annotated.
+        errors.truncate(2);
+        if let Some(frame) = errors.get_mut(1)
+            && let Some(end) = frame.find(']')
+        {
+            frame.truncate(end + 1);
+        }
+    }
+    let expected_errors = expected.error_lines();
+    if errors != expected_errors {
+        failures.push(format!(
+            "errors differ:\n{}",
+            diff(&expected_errors, &errors)
+        ));
+    }
+
+    failures
+}
+
+fn check_all(setup: fn(&mut VM)) {
+    let mut report = String::new();
+    for path in &lox_scripts() {
+        for failure in check(path, setup) {
+            report.push_str(&format!("{}: {failure}\n", script_name(path)));
+        }
+    }
+    assert!(report.is_empty(), "golden tests failed:\n{report}");
+}
+
+#[test]
+fn lox_scripts_match_their_annotations() {
+    check_all(|_| {});
+}
+
+#[test]
+fn lox_scripts_match_their_annotations_under_stress_gc() {
+    check_all(|vm| vm.set_stress_gc(true));
+}
+
+#[test]
+fn lox_scripts_match_their_annotations_when_optimized() {
+    check_all(|vm| vm.set_opt_level(OptLevel::O1));
+}
+
+#[test]
+fn parses_upstream_annotation_forms() {
+    let source = "print 1; // expect: 1\n\
+                  print clock(); // expect any\n\
+                  var a = ; // Error at ';': Expect expression.\n\
+                  // [line 7] Error at end: Expect '}' after block.\n\
+                  // [c line 8] Error: Unterminated string.\n\
+                  nil + 1; // expect runtime error: Operands must be two numbers or two strings.\n";
+    let expectations = Expectations::parse(source);
+    assert_eq!(expectations.output, vec!["1", ANY_LINE]);
+    assert_eq!(
+        expectations.errors,
+        vec![
+            "[line 3] Error at ';': Expect expression.",
+            "[line 7] Error at end: Expect '}' after block.",
+            "[line 8] Error: Unterminated string.",
+        ]
+    );
+    assert_eq!(
+        expectations.runtime_error,
+        Some(("Operands must be two numbers or two strings.".to_owned(), 6))
+    );
+}
+
+#[test]
+fn varying_lines_are_masked_by_position() {
+    let source = "print 1; // expect: 1\nprint clock(); // expect any\nprint 3; // expect: 3\n";
+    let mut output = lines("1\n0.25\n3");
+    mask_varying_lines(source, &mut output);
+    assert_eq!(output, vec!["1", ANY_LINE, "3"]);
+}
+
+#[test]
+fn diff_marks_missing_and_extra_lines() {
+    let expected = lines("1\n2\n3");
+    let actual = lines("1\n3\n4");
+    assert_eq!(diff(&expected, &actual), "   1\n - 2\n   3\n + 4\n");
+}